@@ -0,0 +1,60 @@
+// Renders a MIDI file to a WAV file without opening the app, which also works on machines with no sound card.
+// Usage: render <input.mid> <output.wav> [int16|int24|float32] [sample rate] [soundfont.sf2]
+
+use std::path::Path;
+use std::process;
+
+use ui_synth::render::{render_midi_to_wav, SampleFormat};
+use ui_synth::soundfont::load_soundfont;
+use ui_synth::synth::Synth;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 6 {
+        eprintln!(
+            "Usage: {} <input.mid> <output.wav> [int16|int24|float32] [sample rate] [soundfont.sf2]",
+            args[0]
+        );
+        process::exit(1);
+    }
+
+    let format = match args.get(3) {
+        Some(name) => SampleFormat::from_name(name).unwrap_or_else(|| {
+            eprintln!("Unknown sample format: {}", name);
+            process::exit(1);
+        }),
+        None => SampleFormat::Int16,
+    };
+
+    let sample_rate = match args.get(4) {
+        Some(sample_rate) => sample_rate.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid sample rate: {}", sample_rate);
+            process::exit(1);
+        }),
+        None => 48000,
+    };
+
+    let data = std::fs::read(&args[1]).unwrap_or_else(|e| {
+        eprintln!("Error reading {}: {}", args[1], e);
+        process::exit(1);
+    });
+    let smf = midly::Smf::parse(&data).unwrap_or_else(|e| {
+        eprintln!("Error parsing {}: {}", args[1], e);
+        process::exit(1);
+    });
+
+    let mut synth = Synth::new(sample_rate);
+    // Without a SoundFont the MIDI file plays with the default program map
+    if let Some(path) = args.get(5) {
+        let soundfont = load_soundfont(Path::new(path)).unwrap_or_else(|e| {
+            eprintln!("Error loading {}: {}", path, e);
+            process::exit(1);
+        });
+        synth.set_soundfont(path, soundfont);
+    }
+
+    if let Err(e) = render_midi_to_wav(&smf, Path::new(&args[2]), format, synth) {
+        eprintln!("Error rendering {}: {}", args[2], e);
+        process::exit(1);
+    }
+}
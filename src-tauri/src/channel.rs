@@ -0,0 +1,60 @@
+// This file is for the 16 MIDI channels of the synth.
+// Each channel has its own patch, volume and pan, so that every part of a MIDI file can have its own sound.
+
+use std::f32::consts::FRAC_PI_4;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lfo::Lfo;
+use crate::patch::Patch;
+
+pub const NUM_CHANNELS: usize = 16;
+pub const DRUM_CHANNEL: u8 = 9; // Channel 10, counting from 1 like most MIDI software does
+
+// The settings of a channel that the user can edit
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelSettings {
+    pub patch: Patch,
+    pub volume: f32,  // From 0 to 1
+    pub pan: f32,     // From -1 (left) to 1 (right)
+    pub priority: u8, // When voices have to be stolen, channels with a lower priority can be stolen from first
+}
+
+impl Default for ChannelSettings {
+    fn default() -> ChannelSettings {
+        ChannelSettings {
+            patch: Patch::default(),
+            volume: 1.0,
+            pan: 0.0,
+            priority: 64,
+        }
+    }
+}
+
+impl ChannelSettings {
+    // The volume of the left and right speakers.
+    // Constant power panning keeps a note the same loudness as it moves across.
+    pub fn gains(&self) -> (f32, f32) {
+        pan_gains(self.volume, self.pan)
+    }
+}
+
+// The volume of the left and right speakers, for a volume and a pan from -1 (left) to 1 (right)
+pub fn pan_gains(volume: f32, pan: f32) -> (f32, f32) {
+    let angle = (pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+    (angle.cos() * volume, angle.sin() * volume)
+}
+
+// A channel's settings, along with the state of its controllers
+#[derive(Clone, Debug, Default)]
+pub struct Channel {
+    pub settings: ChannelSettings,
+    pub pitch_bend: f32,          // From -1 to 1
+    pub mod_wheel: f32,           // From 0 to 1
+    pub aftertouch: f32,          // The channel pressure, from 0 to 1
+    pub sustain: bool,            // Whether the sustain pedal is down
+    pub held_keys: Vec<(u8, u8)>, // The keys held down in the mono modes, as (key, velocity) in the order they were pressed
+    pub last_key: Option<u8>, // The last key played in the mono modes, which portamento glides from
+    pub global_lfo: Option<Lfo>, // Only runs while the patch's global LFO is turned on
+}
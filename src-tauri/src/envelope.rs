@@ -0,0 +1,274 @@
+// This file is for the Envelope struct, and the Enveloped source which applies an envelope to audio sample by sample.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::source::Source;
+use serde::{Deserialize, Serialize};
+
+// The envelope struct, all times are in seconds and sustain is a volume from 0 to 1
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+impl Envelope {
+    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Envelope {
+        Envelope {
+            attack,
+            decay,
+            sustain,
+            release,
+        }
+    }
+
+    // Wraps an audio source so that its volume follows the envelope.
+    // The returned handle is used to let the source know when the note has been released.
+    pub fn apply<S>(self, source: S) -> (Enveloped<S>, EnvelopeHandle)
+    where
+        S: Source<Item = f32>,
+    {
+        let handle = EnvelopeHandle::default();
+        let generator = EnvelopeGenerator::new(self, source.sample_rate());
+        let enveloped = Enveloped {
+            source,
+            generator,
+            handle: handle.clone(),
+            level: 0.0,
+            channel: 0,
+        };
+        (enveloped, handle)
+    }
+}
+
+// A handle shared with an Enveloped source, so that a note can be released from another thread
+#[derive(Clone, Debug, Default)]
+pub struct EnvelopeHandle {
+    released: Arc<AtomicBool>,
+}
+
+impl EnvelopeHandle {
+    pub fn release(&self) {
+        self.released.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.released.load(Ordering::Relaxed)
+    }
+}
+
+// Steps through an envelope one sample at a time.
+// Time is counted in samples rather than read from the clock, so the output is the same every time it is run.
+#[derive(Clone, Debug)]
+pub struct EnvelopeGenerator {
+    envelope: Envelope,
+    sample_rate: f32,
+    num_sample: u64, // The number of samples since the note started
+    released_at: Option<(u64, f32)>, // The sample the note was released on, and the level at that point
+    level: f32,
+}
+
+impl EnvelopeGenerator {
+    pub fn new(envelope: Envelope, sample_rate: u32) -> EnvelopeGenerator {
+        EnvelopeGenerator {
+            envelope,
+            sample_rate: sample_rate as f32,
+            num_sample: 0,
+            released_at: None,
+            level: 0.0,
+        }
+    }
+
+    pub fn release(&mut self) {
+        if self.released_at.is_none() {
+            self.released_at = Some((self.num_sample, self.level));
+        }
+    }
+
+    // Returns the level for the next sample, or None once the envelope has finished
+    pub fn next_level(&mut self) -> Option<f32> {
+        let envelope = &self.envelope;
+        let elapsed = self.num_sample as f32 / self.sample_rate;
+
+        let level = if let Some((release_sample, release_level)) = self.released_at {
+            // Release
+            let time_since_release = (self.num_sample - release_sample) as f32 / self.sample_rate;
+            if time_since_release >= envelope.release {
+                return None;
+            }
+            // Use the ease out quint function instead of linear interpolation
+            release_level - ease_out_quint(time_since_release / envelope.release) * release_level
+        } else if elapsed < envelope.attack {
+            // Attack
+            elapsed / envelope.attack
+        } else if elapsed < envelope.attack + envelope.decay {
+            // Decay
+            1.0 - (elapsed - envelope.attack) / envelope.decay * (1.0 - envelope.sustain)
+        } else if envelope.sustain <= 0.0 {
+            // The note has decayed to silence, so there is nothing left to play
+            return None;
+        } else {
+            // Sustain
+            envelope.sustain
+        };
+
+        self.num_sample += 1;
+        self.level = level;
+        Some(level)
+    }
+}
+
+// An audio source with an envelope applied to it.
+// The source ends once the release has finished, which lets whatever is playing it clean up the note.
+pub struct Enveloped<S> {
+    source: S,
+    generator: EnvelopeGenerator,
+    handle: EnvelopeHandle,
+    level: f32,
+    channel: u16, // The channel of the next sample, so that every channel in a frame gets the same level
+}
+
+impl<S> Enveloped<S> {
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    // The level of the envelope at the last sample, from 0 to 1
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+}
+
+impl<S> Iterator for Enveloped<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.channel == 0 {
+            if self.handle.is_released() {
+                self.generator.release();
+            }
+            self.level = self.generator.next_level()?;
+        }
+        self.channel = (self.channel + 1) % self.source.channels().max(1);
+
+        self.source.next().map(|sample| sample * self.level)
+    }
+}
+
+impl<S> Source for Enveloped<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None // Depends on when the note is released
+    }
+}
+
+fn ease_out_quint(x: f32) -> f32 {
+    1.0 - (1.0 - x).powf(5.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    // A low sample rate keeps the times in whole numbers of samples
+    const SAMPLE_RATE: u32 = 1000;
+
+    fn levels(generator: &mut EnvelopeGenerator, num_samples: usize) -> Vec<f32> {
+        (0..num_samples)
+            .map(|_| generator.next_level().unwrap())
+            .collect()
+    }
+
+    fn assert_near(level: f32, expected: f32) {
+        assert!(
+            (level - expected).abs() < 1e-4,
+            "{} is not {}",
+            level,
+            expected
+        );
+    }
+
+    #[test]
+    fn attack_decay_and_sustain_take_their_times() {
+        let mut generator = EnvelopeGenerator::new(Envelope::new(0.1, 0.2, 0.5, 0.1), SAMPLE_RATE);
+        let levels = levels(&mut generator, 1000);
+        assert_near(levels[0], 0.0);
+        assert_near(levels[50], 0.5); // Half way through the attack
+        assert_near(levels[100], 1.0); // The peak, at the start of the decay
+        assert_near(levels[200], 0.75); // Half way through the decay
+        assert_near(levels[300], 0.5);
+        assert!(levels[300..].iter().all(|&level| level == 0.5));
+    }
+
+    #[test]
+    fn release_takes_its_time_and_ends_the_envelope() {
+        let mut generator = EnvelopeGenerator::new(Envelope::new(0.0, 0.0, 0.5, 0.1), SAMPLE_RATE);
+        levels(&mut generator, 10);
+        generator.release();
+        let released = levels(&mut generator, 100);
+        assert_near(released[0], 0.5);
+        assert!(released.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert!(released[99] < 0.001);
+        assert_eq!(generator.next_level(), None);
+    }
+
+    #[test]
+    fn envelopes_without_sustain_end_after_the_decay() {
+        let mut generator = EnvelopeGenerator::new(Envelope::new(0.0, 0.1, 0.0, 0.5), SAMPLE_RATE);
+        levels(&mut generator, 100);
+        assert_eq!(generator.next_level(), None);
+    }
+
+    #[test]
+    fn release_during_the_attack_starts_from_the_level_reached() {
+        let mut generator = EnvelopeGenerator::new(Envelope::new(0.1, 0.0, 1.0, 0.1), SAMPLE_RATE);
+        levels(&mut generator, 26);
+        generator.release();
+        assert_near(generator.next_level().unwrap(), 0.25);
+        // Releasing again doesn't start the release over
+        levels(&mut generator, 49);
+        generator.release();
+        levels(&mut generator, 50);
+        assert_eq!(generator.next_level(), None);
+    }
+
+    #[test]
+    fn retriggered_notes_start_from_silence() {
+        let envelope = Envelope::new(0.1, 0.0, 1.0, 0.1);
+        let source = || SamplesBuffer::new(1, SAMPLE_RATE, vec![1.0_f32; 1000]);
+        let (mut first, first_handle) = envelope.apply(source());
+        first.by_ref().take(200).for_each(drop);
+        assert_near(first.level(), 1.0);
+
+        // The same key played again gets its own envelope, while the first note releases underneath it
+        first_handle.release();
+        let (mut second, _) = envelope.apply(source());
+        assert_near(second.next().unwrap(), 0.0);
+        assert_near(first.next().unwrap(), 1.0);
+        assert_near(second.by_ref().take(50).last().unwrap(), 0.5);
+        assert!(first.by_ref().take(50).last().unwrap() < 1.0);
+        assert!(!second.handle.is_released());
+    }
+}
@@ -0,0 +1,288 @@
+// This file is for the resonant filter that every voice is played through.
+// It is a state variable filter, which gives low-pass, high-pass, band-pass and notch outputs from the same two integrators,
+// and stays stable while its cutoff is being swept by the filter envelope.
+
+use std::f32::consts::{PI, SQRT_2};
+use std::time::Duration;
+
+use rodio::source::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::envelope::{Envelope, EnvelopeGenerator};
+
+const COEFFICIENT_INTERVAL: u32 = 16; // How many frames the filter coefficients are kept for, working them out is slow
+const MIN_CUTOFF: f32 = 20.0;
+const MIN_DAMPING: f32 = 0.05; // The damping at full resonance, the filter rings a lot but doesn't oscillate
+const KEY_TRACKING_CENTRE: f32 = 261.63; // The filter is at its set cutoff for middle C
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterType {
+    LowPass,
+    HighPass,
+    BandPass,
+    Notch,
+}
+
+// How steeply the filter cuts, a 24 dB filter is two 12 dB filters one after the other
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterSlope {
+    Db12,
+    Db24,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FilterSettings {
+    pub filter_type: FilterType,
+    pub slope: FilterSlope,
+    pub cutoff: f32,          // In Hz
+    pub resonance: f32,       // From 0 to 1
+    pub key_tracking: f32,    // From 0 to 1, at 1 the cutoff follows the note being played exactly
+    pub envelope: Envelope,   // Moves the cutoff over the course of a note
+    pub envelope_amount: f32, // How far the envelope moves the cutoff at its peak, in octaves
+}
+
+impl Default for FilterSettings {
+    fn default() -> FilterSettings {
+        FilterSettings {
+            filter_type: FilterType::LowPass,
+            slope: FilterSlope::Db12,
+            cutoff: 20000.0,
+            resonance: 0.0,
+            key_tracking: 0.0,
+            envelope: Envelope::new(0.0, 0.0, 1.0, 0.0),
+            envelope_amount: 0.0,
+        }
+    }
+}
+
+impl FilterSettings {
+    // A plain low-pass filter with no resonance or envelope
+    pub fn low_pass(cutoff: f32) -> FilterSettings {
+        FilterSettings {
+            cutoff,
+            ..FilterSettings::default()
+        }
+    }
+
+    // Wraps a source so that it is played through the filter. The note's frequency is used for key tracking.
+    pub fn apply<S>(&self, source: S, note_hz: f32) -> Filtered<S>
+    where
+        S: Source<Item = f32>,
+    {
+        let sample_rate = source.sample_rate();
+        let channels = source.channels().max(1) as usize;
+        let key_tracking = (note_hz / KEY_TRACKING_CENTRE).powf(self.key_tracking);
+        Filtered {
+            source,
+            filter_type: self.filter_type,
+            slope: self.slope,
+            cutoff: self.cutoff * key_tracking,
+            resonance: self.resonance,
+            envelope: EnvelopeGenerator::new(self.envelope, sample_rate),
+            envelope_amount: self.envelope_amount,
+            envelope_level: 0.0,
+            cutoff_modulation: 0.0,
+            resonance_modulation: 0.0,
+            sample_rate: sample_rate as f32,
+            dampings: [SQRT_2; 2],
+            coefficients: [Coefficients::default(); 2],
+            frames_until_update: 0,
+            states: vec![[StageState::default(); 2]; channels],
+            channel: 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Coefficients {
+    a1: f32,
+    a2: f32,
+    a3: f32,
+}
+
+// The two integrators of one filter stage
+#[derive(Clone, Copy, Debug, Default)]
+struct StageState {
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+// An audio source played through a filter
+pub struct Filtered<S> {
+    source: S,
+    filter_type: FilterType,
+    slope: FilterSlope,
+    cutoff: f32, // In Hz, with key tracking applied
+    resonance: f32,
+    envelope: EnvelopeGenerator,
+    envelope_amount: f32,
+    envelope_level: f32,
+    cutoff_modulation: f32, // Moves the cutoff by a number of octaves, on top of the envelope
+    resonance_modulation: f32, // Added to the resonance
+    sample_rate: f32,
+    dampings: [f32; 2], // For each stage, the square root of 2 for no resonance and getting closer to 0 as the resonance goes up
+    coefficients: [Coefficients; 2], // For each stage
+    frames_until_update: u32,
+    states: Vec<[StageState; 2]>, // The integrators of each stage, for each channel
+    channel: usize,               // The channel of the next sample
+}
+
+impl<S> Filtered<S> {
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    // Lets the filter envelope know that the note has been released
+    pub fn release(&mut self) {
+        self.envelope.release();
+    }
+
+    pub fn set_cutoff_modulation(&mut self, octaves: f32) {
+        self.cutoff_modulation = octaves;
+    }
+
+    pub fn set_resonance_modulation(&mut self, amount: f32) {
+        self.resonance_modulation = amount;
+    }
+
+    // The level of the filter envelope, from 0 to 1
+    pub fn envelope_level(&self) -> f32 {
+        self.envelope_level
+    }
+
+    fn num_stages(&self) -> usize {
+        match self.slope {
+            FilterSlope::Db12 => 1,
+            FilterSlope::Db24 => 2,
+        }
+    }
+
+    fn update_coefficients(&mut self) {
+        let octaves = self.envelope_level * self.envelope_amount + self.cutoff_modulation;
+        let cutoff =
+            (self.cutoff * 2.0_f32.powf(octaves)).clamp(MIN_CUTOFF, self.sample_rate * 0.45);
+
+        let resonance = (self.resonance + self.resonance_modulation).clamp(0.0, 1.0);
+        let damping = SQRT_2 - (SQRT_2 - MIN_DAMPING) * resonance;
+        // In the 24 dB filter only the last stage resonates, otherwise the peak would be far too loud
+        self.dampings = match self.slope {
+            FilterSlope::Db12 => [damping, SQRT_2],
+            FilterSlope::Db24 => [SQRT_2, damping],
+        };
+
+        let g = (PI * cutoff / self.sample_rate).tan();
+        for (coefficients, damping) in self.coefficients.iter_mut().zip(self.dampings.iter()) {
+            let a1 = 1.0 / (1.0 + g * (g + damping));
+            let a2 = g * a1;
+            *coefficients = Coefficients { a1, a2, a3: g * a2 };
+        }
+    }
+}
+
+impl<S> Iterator for Filtered<S>
+where
+    S: Source<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut sample = self.source.next()?;
+
+        if self.channel == 0 {
+            // The envelope moves every frame so that its times are right, even though the coefficients only follow it every few frames.
+            // It is finished once it has released, which leaves the cutoff where it started.
+            self.envelope_level = self.envelope.next_level().unwrap_or(0.0);
+            if self.frames_until_update == 0 {
+                self.update_coefficients();
+                self.frames_until_update = COEFFICIENT_INTERVAL;
+            }
+            self.frames_until_update -= 1;
+        }
+
+        let num_stages = self.num_stages();
+        let stages = self.dampings.iter().zip(self.coefficients.iter());
+        let states = self.states[self.channel].iter_mut().take(num_stages);
+        for (state, (damping, coefficients)) in states.zip(stages) {
+            let Coefficients { a1, a2, a3 } = *coefficients;
+            let v3 = sample - state.ic2eq;
+            let v1 = a1 * state.ic1eq + a2 * v3;
+            let v2 = state.ic2eq + a2 * state.ic1eq + a3 * v3;
+            state.ic1eq = 2.0 * v1 - state.ic1eq;
+            state.ic2eq = 2.0 * v2 - state.ic2eq;
+
+            let low = v2;
+            let band = v1;
+            let high = sample - damping * band - low;
+            sample = match self.filter_type {
+                FilterType::LowPass => low,
+                FilterType::HighPass => high,
+                FilterType::BandPass => band,
+                FilterType::Notch => low + high,
+            };
+        }
+
+        self.channel = (self.channel + 1) % self.states.len();
+        Some(sample)
+    }
+}
+
+impl<S> Source for Filtered<S>
+where
+    S: Source<Item = f32>,
+{
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::source::Zero;
+
+    // A low sample rate keeps the times in whole numbers of samples
+    const SAMPLE_RATE: u32 = 1000;
+
+    // The cutoff that the filter's coefficients were last worked out for
+    fn cutoff<S>(filter: &Filtered<S>) -> f32 {
+        let Coefficients { a1, a2, .. } = filter.coefficients[0];
+        (a2 / a1).atan() * filter.sample_rate / PI
+    }
+
+    #[test]
+    fn the_envelope_takes_its_times() {
+        let settings = FilterSettings {
+            cutoff: 100.0,
+            envelope: Envelope::new(0.1, 0.2, 0.5, 0.1),
+            envelope_amount: 2.0,
+            ..FilterSettings::default()
+        };
+        let mut filter = settings.apply(Zero::<f32>::new(1, SAMPLE_RATE), KEY_TRACKING_CENTRE);
+
+        filter.nth(100);
+        assert!((filter.envelope_level() - 1.0).abs() < 1e-4);
+        filter.nth(199);
+        assert!((filter.envelope_level() - 0.5).abs() < 1e-4);
+        // The cutoff catches up with the envelope within one coefficient interval
+        filter.nth(COEFFICIENT_INTERVAL as usize);
+        assert!((cutoff(&filter) - 200.0).abs() < 0.1, "{}", cutoff(&filter));
+
+        filter.release();
+        filter.nth(100 + COEFFICIENT_INTERVAL as usize);
+        assert_eq!(filter.envelope_level(), 0.0);
+        assert!((cutoff(&filter) - 100.0).abs() < 0.1, "{}", cutoff(&filter));
+    }
+}
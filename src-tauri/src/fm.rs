@@ -0,0 +1,339 @@
+// This file is for FM synthesis, where sine wave operators modulate each other's phase to make complex sounds.
+// Each operator has its own frequency ratio, level and envelope. The algorithm decides which operators modulate
+// which, and the carriers are the operators that are heard. The last operator can also modulate itself, which is
+// known as feedback.
+
+use std::time::Duration;
+
+use rodio::source::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::envelope::{Envelope, EnvelopeGenerator};
+use crate::oscillator::{Oscillator, WaveType};
+
+pub const NUM_OPERATORS: usize = 4;
+
+// How far a modulator at full level moves its target's phase, in cycles
+const MODULATION_DEPTH: f32 = 2.0;
+// How far the last operator moves its own phase at full feedback, in cycles
+const FEEDBACK_DEPTH: f32 = 0.5;
+
+// The ways the operators can be connected, drawn with the operators numbered from 1.
+// Operators only modulate lower numbered operators, apart from operator 4's feedback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FmAlgorithm {
+    Stack,         // 4 > 3 > 2 > 1
+    TwoIntoStack,  // 3 and 4 > 2 > 1
+    StackAndOne,   // 3 > 2 > 1, and 4 > 1
+    ThreeIntoOne,  // 2, 3 and 4 > 1
+    TwoStacks,     // 2 > 1 and 4 > 3
+    OneIntoThree,  // 4 > 1, 2 and 3
+    StackAndSines, // 2 > 1, with 3 and 4 heard on their own
+    Additive,      // Every operator is heard on its own
+}
+
+impl FmAlgorithm {
+    // For each operator, the operators that modulate it as bits, and then the carriers as bits
+    fn routing(self) -> ([u8; NUM_OPERATORS], u8) {
+        match self {
+            FmAlgorithm::Stack => ([0b0010, 0b0100, 0b1000, 0], 0b0001),
+            FmAlgorithm::TwoIntoStack => ([0b0010, 0b1100, 0, 0], 0b0001),
+            FmAlgorithm::StackAndOne => ([0b1010, 0b0100, 0, 0], 0b0001),
+            FmAlgorithm::ThreeIntoOne => ([0b1110, 0, 0, 0], 0b0001),
+            FmAlgorithm::TwoStacks => ([0b0010, 0, 0b1000, 0], 0b0101),
+            FmAlgorithm::OneIntoThree => ([0b1000, 0b1000, 0b1000, 0], 0b0111),
+            FmAlgorithm::StackAndSines => ([0b0010, 0, 0, 0], 0b1101),
+            FmAlgorithm::Additive => ([0, 0, 0, 0], 0b1111),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OperatorSettings {
+    pub ratio: f32, // Multiplies the note's frequency
+    pub level: f32, // From 0 to 1, for modulators this sets how bright the sound is
+    pub envelope: Envelope,
+}
+
+impl Default for OperatorSettings {
+    fn default() -> OperatorSettings {
+        OperatorSettings {
+            ratio: 1.0,
+            level: 0.0,
+            envelope: Envelope::new(0.0, 1.0, 0.5, 0.3),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FmSettings {
+    pub algorithm: FmAlgorithm,
+    pub feedback: f32, // From 0 to 1
+    pub operators: [OperatorSettings; NUM_OPERATORS],
+}
+
+impl Default for FmSettings {
+    // A simple electric piano, one carrier with a modulator that fades out
+    fn default() -> FmSettings {
+        FmSettings {
+            algorithm: FmAlgorithm::Stack,
+            feedback: 0.0,
+            operators: [
+                OperatorSettings {
+                    ratio: 1.0,
+                    level: 1.0,
+                    envelope: Envelope::new(0.0, 2.0, 0.3, 0.5),
+                },
+                OperatorSettings {
+                    ratio: 1.0,
+                    level: 0.4,
+                    envelope: Envelope::new(0.0, 0.8, 0.1, 0.5),
+                },
+                OperatorSettings::default(),
+                OperatorSettings::default(),
+            ],
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Operator {
+    oscillator: Oscillator,
+    ratio: f32,
+    level: f32,
+    envelope: EnvelopeGenerator,
+}
+
+// An audio source which plays the operators of an FM patch
+#[derive(Clone, Debug)]
+pub struct FmOperators {
+    operators: Vec<Operator>,
+    modulators: [u8; NUM_OPERATORS],
+    carriers: u8,
+    carrier_gain: f32, // Keeps algorithms with several carriers from being louder than the others
+    feedback: f32,
+    feedback_history: [f32; 2], // The last two outputs of operator 4, averaged to keep feedback from getting harsh
+    outputs: [f32; NUM_OPERATORS],
+    freq: f32,
+    sample_rate: u32,
+}
+
+impl FmOperators {
+    pub fn new(freq: f32, settings: &FmSettings, sample_rate: u32) -> FmOperators {
+        let operators = settings
+            .operators
+            .iter()
+            .map(|operator| Operator {
+                oscillator: Oscillator::new(freq * operator.ratio, WaveType::Sine, sample_rate),
+                ratio: operator.ratio,
+                level: operator.level,
+                envelope: EnvelopeGenerator::new(operator.envelope, sample_rate),
+            })
+            .collect();
+        let (modulators, carriers) = settings.algorithm.routing();
+        FmOperators {
+            operators,
+            modulators,
+            carriers,
+            carrier_gain: 1.0 / carriers.count_ones().max(1) as f32,
+            feedback: settings.feedback.clamp(0.0, 1.0),
+            feedback_history: [0.0; 2],
+            outputs: [0.0; NUM_OPERATORS],
+            freq,
+            sample_rate,
+        }
+    }
+
+    // Lets every operator's envelope know that the note has been released
+    pub fn release(&mut self) {
+        for operator in self.operators.iter_mut() {
+            operator.envelope.release();
+        }
+    }
+
+    // The frequency of the note, before each operator's ratio
+    pub fn frequency(&self) -> f32 {
+        self.freq
+    }
+
+    pub fn set_frequency(&mut self, freq: f32) {
+        self.freq = freq;
+        for operator in self.operators.iter_mut() {
+            operator.oscillator.set_frequency(freq * operator.ratio);
+        }
+    }
+
+    // Slides every operator to a new note, keeping their ratios
+    pub fn glide_to(&mut self, freq: f32, seconds: f32) {
+        self.freq = freq;
+        for operator in self.operators.iter_mut() {
+            operator.oscillator.glide_to(freq * operator.ratio, seconds);
+        }
+    }
+
+    // Bends the pitch up or down by a number of semitones
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        for operator in self.operators.iter_mut() {
+            operator.oscillator.set_pitch_bend(semitones);
+        }
+    }
+}
+
+impl Iterator for FmOperators {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let mut sample = 0.0;
+        // Higher numbered operators go first, so their outputs are ready for the operators they modulate
+        for i in (0..NUM_OPERATORS).rev() {
+            let mut offset = 0.0;
+            for (j, output) in self.outputs.iter().enumerate() {
+                if self.modulators[i] & (1 << j) != 0 {
+                    offset += output * MODULATION_DEPTH;
+                }
+            }
+            if i == NUM_OPERATORS - 1 {
+                offset += (self.feedback_history[0] + self.feedback_history[1]) / 2.0
+                    * self.feedback
+                    * FEEDBACK_DEPTH;
+            }
+
+            let operator = &mut self.operators[i];
+            // The envelope is finished once it has released, which silences the operator
+            let level = operator.envelope.next_level().unwrap_or(0.0) * operator.level;
+            let output = operator.oscillator.next_with_phase_offset(offset) * level;
+            self.outputs[i] = output;
+            if self.carriers & (1 << i) != 0 {
+                sample += output;
+            }
+        }
+        self.feedback_history = [self.outputs[NUM_OPERATORS - 1], self.feedback_history[0]];
+        Some(sample * self.carrier_gain)
+    }
+}
+
+impl Source for FmOperators {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1 // Mono, not stereo
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None // Will continue indefinitely until stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const SAMPLE_RATE: u32 = 8000;
+    const FREQ: f32 = 100.0;
+    const ALGORITHMS: [FmAlgorithm; 8] = [
+        FmAlgorithm::Stack,
+        FmAlgorithm::TwoIntoStack,
+        FmAlgorithm::StackAndOne,
+        FmAlgorithm::ThreeIntoOne,
+        FmAlgorithm::TwoStacks,
+        FmAlgorithm::OneIntoThree,
+        FmAlgorithm::StackAndSines,
+        FmAlgorithm::Additive,
+    ];
+
+    // Plays the operators at their levels, with envelopes that hold them there
+    fn play(algorithm: FmAlgorithm, levels: [f32; NUM_OPERATORS], feedback: f32) -> Vec<f32> {
+        let mut settings = FmSettings {
+            algorithm,
+            feedback,
+            ..FmSettings::default()
+        };
+        for (operator, level) in settings.operators.iter_mut().zip(levels.iter()) {
+            *operator = OperatorSettings {
+                ratio: 1.0,
+                level: *level,
+                envelope: Envelope::new(0.0, 0.0, 1.0, 0.0),
+            };
+        }
+        FmOperators::new(FREQ, &settings, SAMPLE_RATE)
+            .take(SAMPLE_RATE as usize / 10)
+            .collect()
+    }
+
+    // How far the samples are from an unmodulated sine wave of the given amplitude
+    fn distance_from_sine(samples: &[f32], amplitude: f32) -> f32 {
+        samples
+            .iter()
+            .enumerate()
+            .map(|(n, sample)| {
+                let sine = (2.0 * PI * FREQ * n as f32 / SAMPLE_RATE as f32).sin() * amplitude;
+                (sample - sine).abs()
+            })
+            .fold(0.0, f32::max)
+    }
+
+    fn is_silent(samples: &[f32]) -> bool {
+        samples.iter().all(|sample| *sample == 0.0)
+    }
+
+    #[test]
+    fn operators_only_modulate_lower_numbered_operators() {
+        for algorithm in ALGORITHMS {
+            let (modulators, carriers) = algorithm.routing();
+            assert_ne!(carriers, 0, "{:?}", algorithm);
+            for (i, modulators) in modulators.iter().enumerate() {
+                assert_eq!(modulators & ((2 << i) - 1), 0, "{:?}", algorithm);
+                // Modulators are never heard on their own
+                assert_eq!(modulators & carriers, 0, "{:?}", algorithm);
+            }
+        }
+    }
+
+    #[test]
+    fn only_carriers_are_heard() {
+        for algorithm in ALGORITHMS {
+            let (_, carriers) = algorithm.routing();
+            let gain = 1.0 / carriers.count_ones() as f32;
+            for i in 0..NUM_OPERATORS {
+                let mut levels = [0.0; NUM_OPERATORS];
+                levels[i] = 1.0;
+                let samples = play(algorithm, levels, 0.0);
+                if carriers & (1 << i) != 0 {
+                    assert!(distance_from_sine(&samples, gain) < 1e-3, "{:?}", algorithm);
+                } else {
+                    assert!(is_silent(&samples), "{:?} operator {}", algorithm, i + 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn modulators_change_the_operators_they_are_routed_to() {
+        // In the two stacks, 2 modulates 1 and 4 modulates 3, but 4 doesn't reach 1
+        let one_alone = play(FmAlgorithm::TwoStacks, [1.0, 0.0, 0.0, 0.0], 0.0);
+        let one_and_two = play(FmAlgorithm::TwoStacks, [1.0, 1.0, 0.0, 0.0], 0.0);
+        let one_and_four = play(FmAlgorithm::TwoStacks, [1.0, 0.0, 0.0, 1.0], 0.0);
+        assert!(distance_from_sine(&one_and_two, 0.5) > 0.1);
+        assert_eq!(one_alone, one_and_four);
+    }
+
+    #[test]
+    fn feedback_only_changes_the_last_operator() {
+        let plain = play(FmAlgorithm::Additive, [0.0, 0.0, 0.0, 1.0], 0.0);
+        let fed_back = play(FmAlgorithm::Additive, [0.0, 0.0, 0.0, 1.0], 1.0);
+        assert!(distance_from_sine(&plain, 0.25) < 1e-3);
+        assert!(distance_from_sine(&fed_back, 0.25) > 0.01);
+
+        let first = play(FmAlgorithm::Additive, [1.0, 0.0, 0.0, 0.0], 1.0);
+        assert!(distance_from_sine(&first, 0.25) < 1e-3);
+    }
+}
@@ -0,0 +1,200 @@
+// This file is for LFOs, the slow oscillators that modulate a voice's pitch, filter cutoff, volume or pan.
+// A patch has a voice LFO, which starts again with every note, and a global LFO, which runs freely and is shared
+// by every note on the channel. Either one can be synced to the tempo of the MIDI file.
+
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::oscillator::{Oscillator, Random, WaveType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LfoShape {
+    Sine,
+    Square,
+    Sawtooth,
+    Triangle,
+    SampleAndHold, // Jumps to a new random value at the start of every cycle
+}
+
+impl LfoShape {
+    // The oscillator shape to use, sample and hold isn't a wave so it has none
+    fn wave_type(self) -> Option<WaveType> {
+        match self {
+            LfoShape::Sine => Some(WaveType::Sine),
+            LfoShape::Square => Some(WaveType::Square),
+            LfoShape::Sawtooth => Some(WaveType::Sawtooth),
+            LfoShape::Triangle => Some(WaveType::Triangle),
+            LfoShape::SampleAndHold => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LfoDestination {
+    Pitch,             // The amount is in semitones
+    Cutoff,            // The amount is in octaves
+    Amplitude, // The amount is from 0 to 1, at 1 the volume goes all the way down at the bottom of each cycle
+    Pan,       // The amount is from 0 to 1, at 1 the note moves all the way from left to right
+    PulseWidth, // The amount is added to the width of the pulse waves, which are from 0 to 1
+    WavetablePosition, // The amount is added to the position of the wavetables, which are from 0 to 1
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub rate: f32,  // In Hz, when the LFO isn't synced
+    pub sync: bool, // Whether the rate follows the tempo
+    pub beats: f32, // How many beats one cycle lasts for when the LFO is synced
+    pub destination: LfoDestination,
+    pub amount: f32, // 0 turns the LFO off
+}
+
+impl Default for LfoSettings {
+    fn default() -> LfoSettings {
+        LfoSettings {
+            shape: LfoShape::Sine,
+            rate: 5.0,
+            sync: false,
+            beats: 1.0,
+            destination: LfoDestination::Pitch,
+            amount: 0.0,
+        }
+    }
+}
+
+impl LfoSettings {
+    // The rate in Hz, the tempo is in microseconds per beat like in MIDI files
+    pub fn frequency(&self, tempo: u32) -> f32 {
+        if self.sync && self.beats > 0.0 && tempo > 0 {
+            1_000_000.0 / tempo as f32 / self.beats
+        } else {
+            self.rate
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Lfo {
+    settings: LfoSettings,
+    oscillator: Option<Oscillator>, // Used for every shape apart from sample and hold
+    scale: f32,                     // Brings the oscillator's wave to between -1 and 1
+    phase: f32,     // How far through the current cycle sample and hold is, from 0 to 1
+    increment: f32, // How far the sample and hold phase moves each sample
+    held: f32,      // The value sample and hold is holding
+    value: f32,     // The last value returned
+    random: Random,
+    sample_rate: u32,
+}
+
+impl Lfo {
+    // The seed picks the sample and hold values, so that LFOs started with different seeds don't move together
+    pub fn new(settings: LfoSettings, tempo: u32, seed: u32, sample_rate: u32) -> Lfo {
+        let freq = settings.frequency(tempo);
+        let oscillator = settings
+            .shape
+            .wave_type()
+            .map(|wave_type| Oscillator::new(freq, wave_type, sample_rate).naive());
+        // The naive triangle is the arcsine of a sine wave, which goes from -π/2 to π/2
+        let scale = if settings.shape == LfoShape::Triangle {
+            2.0 / PI
+        } else {
+            1.0
+        };
+        let mut lfo = Lfo {
+            settings,
+            oscillator,
+            scale,
+            phase: 0.0,
+            increment: freq / sample_rate as f32,
+            held: 0.0,
+            value: 0.0,
+            random: Random::new(seed),
+            sample_rate,
+        };
+        lfo.held = lfo.random.next_value();
+        lfo
+    }
+
+    pub fn settings(&self) -> &LfoSettings {
+        &self.settings
+    }
+
+    // Changes the rate of a synced LFO to match a new tempo
+    pub fn set_tempo(&mut self, tempo: u32) {
+        let freq = self.settings.frequency(tempo);
+        self.increment = freq / self.sample_rate as f32;
+        if let Some(oscillator) = self.oscillator.as_mut() {
+            oscillator.set_frequency(freq);
+        }
+    }
+
+    // Returns the next value, from -1 to 1
+    pub fn next_value(&mut self) -> f32 {
+        self.value = match self.oscillator.as_mut() {
+            Some(oscillator) => oscillator.next().unwrap_or(0.0) * self.scale,
+            None => {
+                self.phase += self.increment;
+                if self.phase >= 1.0 {
+                    self.phase = self.phase.fract();
+                    self.held = self.random.next_value();
+                }
+                self.held
+            }
+        };
+        self.value
+    }
+
+    // The last value returned by next_value
+    pub fn value(&self) -> f32 {
+        self.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+
+    fn lfo(shape: LfoShape, seed: u32) -> Lfo {
+        let settings = LfoSettings {
+            shape,
+            rate: 10.0,
+            amount: 1.0,
+            ..LfoSettings::default()
+        };
+        Lfo::new(settings, 500_000, seed, SAMPLE_RATE)
+    }
+
+    #[test]
+    fn every_shape_goes_from_minus_1_to_1() {
+        for shape in [
+            LfoShape::Sine,
+            LfoShape::Square,
+            LfoShape::Sawtooth,
+            LfoShape::Triangle,
+        ] {
+            let mut lfo = lfo(shape, 0);
+            let values: Vec<f32> = (0..SAMPLE_RATE).map(|_| lfo.next_value()).collect();
+            let max = values.iter().cloned().fold(f32::MIN, f32::max);
+            let min = values.iter().cloned().fold(f32::MAX, f32::min);
+            assert!((max - 1.0).abs() < 0.05, "{:?} peaks at {}", shape, max);
+            assert!((min + 1.0).abs() < 0.05, "{:?} dips to {}", shape, min);
+        }
+    }
+
+    #[test]
+    fn sample_and_hold_follows_its_seed() {
+        let values = |seed| {
+            let mut lfo = lfo(LfoShape::SampleAndHold, seed);
+            (0..SAMPLE_RATE)
+                .map(|_| lfo.next_value())
+                .collect::<Vec<f32>>()
+        };
+        assert_eq!(values(1), values(1));
+        assert_ne!(values(1), values(2));
+        assert!(values(0).iter().all(|value| (-1.0..=1.0).contains(value)));
+    }
+}
@@ -0,0 +1,24 @@
+// The synth engine, which doesn't depend on tauri so that it can be used without opening the app
+pub mod channel;
+pub mod envelope;
+pub mod filter;
+pub mod fm;
+pub mod lfo;
+pub mod midi;
+pub mod midi_file;
+pub mod midi_input;
+pub mod mod_matrix;
+pub mod mono;
+pub mod oscillator;
+pub mod oscillator_bank;
+pub mod patch;
+pub mod patch_files;
+pub mod polyphony;
+pub mod presets;
+pub mod programs;
+pub mod render;
+pub mod sampler;
+pub mod soundfont;
+pub mod synth;
+pub mod voice;
+pub mod wavetable;
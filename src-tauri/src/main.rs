@@ -4,32 +4,49 @@
     windows_subsystem = "windows"
 )]
 
-use midir::{MidiInput, MidiInputConnection};
+use midly::live::LiveEvent;
 use midly::Track;
-use rodio::source::Source;
-use rodio::OutputStream;
+use rodio::cpal::traits::HostTrait;
+use rodio::{DeviceTrait, OutputStream};
 
 // Import synth module
-mod oscillator;
-mod synth;
-
-use oscillator::Oscillator;
-use synth::{Envelope, Synth};
+use ui_synth::channel::{ChannelSettings, NUM_CHANNELS};
+use ui_synth::midi::parse_message;
+use ui_synth::midi_file::{get_midi_data, timed_events, TempoMap};
+use ui_synth::midi_input::{MidiInputs, MidiPort};
+use ui_synth::mod_matrix::ModSlot;
+use ui_synth::oscillator::WaveType;
+use ui_synth::oscillator_bank::NUM_OSCILLATORS;
+use ui_synth::patch::{Patch, PatchType};
+use ui_synth::polyphony::{PolyphonySettings, VoiceStats};
+use ui_synth::presets;
+use ui_synth::programs::{self, ProgramMap, NUM_PROGRAMS};
+use ui_synth::render::{render_midi_to_wav, SampleFormat};
+use ui_synth::sampler::{self, SampleZone};
+use ui_synth::soundfont;
+use ui_synth::synth::{Synth, SynthSource};
+use ui_synth::wavetable;
 
 use serde::{Deserialize, Serialize};
 // use core::time;
 // use tauri::http::header;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use tauri::{Manager, Window, Wry};
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Window, Wry};
 use tauri::api::dialog;
 
+const MIDI_RESCAN_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Default)]
 struct MidiState {
-    pub input: Mutex<Option<MidiInputConnection<()>>>,
+    inputs: Mutex<MidiInputs>,
+    rescanning: AtomicBool, // Set once the thread that looks for new ports has been started
 }
 
 struct SynthState {
-    synth: Mutex<Synth>,
+    synth: Arc<Mutex<Synth>>, // Shared with the SynthSource that plays it
 }
 
 #[derive(Default)]
@@ -38,6 +55,7 @@ struct MidiPlayerState<'a> {
     tempo: Mutex<u32>,
     track_time: Mutex<u32>,
     length_in_ticks: Mutex<u32>,
+    file_data: Mutex<Vec<u8>>, // The MIDI file that was uploaded, for rendering
 }
 
 struct TrackPlus<'a> {
@@ -50,12 +68,6 @@ struct MidiMessage {
     message: Vec<u8>,
 }
 
-struct MidiData {
-    tempo: u32,
-    length_in_ticks: u32,
-    meta_track_index: Option<usize>,
-}
-
 #[derive(Clone, Serialize, Deserialize)]
 struct FrontEndNote {
     note: u8,
@@ -64,124 +76,417 @@ struct FrontEndNote {
     end_time: u32,
 }
 
-enum SimpleNote {
-    On(u8, u8),
-    Off(u8),
-}
-
+// Starts listening to every MIDI input port, and keeps checking for ports that are plugged in later
 #[tauri::command]
-fn open_midi_connection(midi_state: tauri::State<'_, MidiState>, window: Window<Wry>) {
-    let handle = Arc::new(window).clone();
-    let midi_in = MidiInput::new("ui_synth");
-    match midi_in {
-        Ok(midi_in) => {
-            let midi_in_ports = midi_in.ports();
-            let port = midi_in_ports.get(0);
-            match port {
-                Some(port) => {
-                    // Print the name of the port
-                    // println!("Port: {}", midi_in.port_name(port).unwrap());
-                    let midi_in_conn = midi_in.connect(
-                        port,
-                        "midir",
-                        move |_, message, _| {
-                            // println!("Message: {:?}", message);
-                            handle
-                                .emit_and_trigger(
-                                    "midi_message",
-                                    MidiMessage {
-                                        message: message.to_vec(),
-                                    },
-                                )
-                                .map_err(|e| {
-                                    println!("Error sending midi message: {}", e);
-                                })
-                                .ok();
-                        },
-                        (),
-                    );
-                    match midi_in_conn {
-                        Ok(midi_in_conn) => {
-                            midi_state.input.lock().unwrap().replace(midi_in_conn);
-                        }
-                        Err(e) => {
-                            println!("Error: {}", e);
-                        }
-                    }
-                }
-                None => {
-                    println!("No port found at index {}", 0);
+fn open_midi_connection(
+    midi_state: tauri::State<'_, MidiState>,
+    window: Window<Wry>,
+) -> Result<Vec<MidiPort>, String> {
+    let handle = Arc::new(window.clone());
+    let mut inputs = midi_state.inputs.lock().unwrap();
+    inputs.set_callback(Arc::new(move |message| {
+        // println!("Message: {:?}", message);
+        handle
+            .emit_and_trigger(
+                "midi_message",
+                MidiMessage {
+                    message: message.to_vec(),
+                },
+            )
+            .map_err(|e| {
+                println!("Error sending midi message: {}", e);
+            })
+            .ok();
+    }));
+    inputs.rescan()?;
+
+    if !midi_state.rescanning.swap(true, Ordering::Relaxed) {
+        let handle = window.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(MIDI_RESCAN_INTERVAL);
+            let midi_state = handle.state::<MidiState>();
+            let mut inputs = midi_state.inputs.lock().unwrap();
+            match inputs.rescan() {
+                Ok(true) => {
+                    handle
+                        .emit("midi_ports_changed", inputs.ports())
+                        .map_err(|e| {
+                            println!("Error sending midi ports: {}", e);
+                        })
+                        .ok();
                 }
+                Ok(false) => {}
+                Err(e) => println!("Error scanning midi ports: {}", e),
             }
-        }
-        Err(e) => println!("Error: {}", e),
+        });
     }
+
+    Ok(inputs.ports())
 }
 
-#[tauri::command(async)]
-fn update_synth(synth_state: tauri::State<'_, SynthState>) {
-    loop {
-        let synth_state = &synth_state.synth;
-        let mut synth = synth_state.lock().unwrap();
-        synth.update();
-    }
+#[tauri::command]
+fn list_midi_ports(midi_state: tauri::State<'_, MidiState>) -> Vec<MidiPort> {
+    midi_state.inputs.lock().unwrap().ports()
+}
+
+#[tauri::command]
+fn connect_midi_port(
+    midi_state: tauri::State<'_, MidiState>,
+    name: String,
+) -> Result<Vec<MidiPort>, String> {
+    let mut inputs = midi_state.inputs.lock().unwrap();
+    inputs.connect(&name)?;
+    Ok(inputs.ports())
 }
 
-static mut FILL_DATA: Vec<u8> = Vec::new();
+#[tauri::command]
+fn disconnect_midi_port(midi_state: tauri::State<'_, MidiState>, name: String) -> Vec<MidiPort> {
+    let mut inputs = midi_state.inputs.lock().unwrap();
+    inputs.disconnect(&name);
+    inputs.ports()
+}
 
 #[tauri::command]
 async fn file_upload(window: Window<Wry>) {
     let path_buf = dialog::blocking::FileDialogBuilder::default()
         .add_filter("Midi", &["midi", "mid"])
         .pick_file();
-    unsafe {
-        if let Some(p) = path_buf {
-            FILL_DATA = std::fs::read(p).unwrap();
-        }
-        let mut smf = midly::Smf::parse(&FILL_DATA).unwrap();
-
-        let track_count = smf.tracks.len();
-        println!("Track count: {}", track_count);
-        let header = smf.header;
-        let timing = header.timing;
-        // let track_tempo = get_tempo(&smf);
-        let data = get_midi_data(&smf);
-        println!("Length in ticks: {}", data.length_in_ticks);
-        println!("Tempo: {}", data.tempo);
-        println!("Meta track index: {:?}", data.meta_track_index);
-
-        // smf.tracks.remove(data.meta_track_index.unwrap());
+    let path_buf = match path_buf {
+        Some(path_buf) => path_buf,
+        None => return, // The dialog was cancelled
+    };
+    let file_data = std::fs::read(path_buf).unwrap();
+    // The tracks are kept after the file's bytes are moved into the state, so they can't borrow from them
+    let smf = midly::Smf::parse(&file_data).unwrap().make_static();
+
+    let track_count = smf.tracks.len();
+    println!("Track count: {}", track_count);
+    let header = smf.header;
+    let timing = header.timing;
+    // let track_tempo = get_tempo(&smf);
+    let data = get_midi_data(&smf);
+    println!("Length in ticks: {}", data.length_in_ticks);
+    println!("Tempo: {}", data.tempo);
+    println!("Meta track index: {:?}", data.meta_track_index);
+
+    // smf.tracks.remove(data.meta_track_index.unwrap());
 
-        let handle = Arc::new(window).clone();
-        handle
+    let handle = Arc::new(window).clone();
+    handle
         .emit("call_the_rust_function", ()) //p.to_str().unwrap().to_string())
         .map_err(|e| {
             println!("Error sending midi message: {}", e);
         })
         .ok();
-        // handle.trigger("play_arrangement", None);
-
-        // Use the handle to get the midi player state
-        let midi_player_state = handle.state::<MidiPlayerState>();
-
-        // Add the data to the midi player state
-        let mut arangements = midi_player_state.arangements.lock().unwrap();
-        arangements.clear();
-        for track in smf.tracks {
-            let track_plus = TrackPlus {
-                track: track.clone(),
-                timing: timing,
-            };
-            arangements.push(track_plus);
-        }
-        // handle.emit_and_trigger("play_arrangement", ()).unwrap();
-        // Add the tempo to the midi player state
-        let mut tempo = midi_player_state.tempo.lock().unwrap();
-        *tempo = data.tempo;
-        // Add the length in ticks to the midi player state
-        let mut length_in_ticks = midi_player_state.length_in_ticks.lock().unwrap();
-        *length_in_ticks = data.length_in_ticks;
+    // handle.trigger("play_arrangement", None);
+
+    // Use the handle to get the midi player state
+    let midi_player_state = handle.state::<MidiPlayerState>();
+
+    // Add the data to the midi player state
+    let mut arangements = midi_player_state.arangements.lock().unwrap();
+    arangements.clear();
+    for track in smf.tracks {
+        let track_plus = TrackPlus {
+            track: track.clone(),
+            timing: timing,
+        };
+        arangements.push(track_plus);
     }
+    // Keep the file so that it can be rendered
+    *midi_player_state.file_data.lock().unwrap() = file_data;
+    // handle.emit_and_trigger("play_arrangement", ()).unwrap();
+    // Add the tempo to the midi player state
+    let mut tempo = midi_player_state.tempo.lock().unwrap();
+    *tempo = data.tempo;
+    // Add the length in ticks to the midi player state
+    let mut length_in_ticks = midi_player_state.length_in_ticks.lock().unwrap();
+    *length_in_ticks = data.length_in_ticks;
+}
+
+// Renders the loaded MIDI file to a WAV file chosen by the user
+#[tauri::command]
+async fn render_arrangement(
+    format: String,
+    sample_rate: u32,
+    synth_state: tauri::State<'_, SynthState>,
+    midi_player_state: tauri::State<'_, MidiPlayerState>,
+) -> Result<(), String> {
+    let format = SampleFormat::from_name(&format)
+        .ok_or_else(|| format!("Unknown sample format: {}", format))?;
+    let path_buf = dialog::blocking::FileDialogBuilder::default()
+        .add_filter("Wave", &["wav"])
+        .save_file();
+    let path_buf = match path_buf {
+        Some(path_buf) => path_buf,
+        None => return Ok(()), // The dialog was cancelled
+    };
+    // Render with a separate synth with the same channel settings, so that the live synth can keep playing.
+    // The settings are copied out first, so that the live synth isn't locked while the files they use are loaded.
+    let (channels, program_map, polyphony) = {
+        let live_synth = synth_state.synth.lock().unwrap();
+        let channels: Vec<ChannelSettings> = (0..NUM_CHANNELS as u8)
+            .map(|channel| live_synth.channel_settings(channel).clone())
+            .collect();
+        (channels, live_synth.program_map().clone(), live_synth.polyphony().clone())
+    };
+    let mut synth = Synth::new(sample_rate);
+    synth.load_files(channels.iter().map(|settings| &settings.patch).chain(program_map.patches()));
+    for (channel, settings) in channels.into_iter().enumerate() {
+        synth.set_channel_settings(channel as u8, settings);
+    }
+    synth.set_program_map(program_map);
+    synth.set_polyphony(polyphony);
+    let file_data = midi_player_state.file_data.lock().unwrap().clone();
+    let smf = midly::Smf::parse(&file_data).map_err(|e| e.to_string())?;
+    render_midi_to_wav(&smf, &path_buf, format, synth).map_err(|e| e.to_string())
+}
+
+// Loads the files that patches use, before the patches are given to the synth.
+// The synth is only locked to find the files it is missing and to add them, not while they are read.
+fn load_patch_files<'a>(synth: &Mutex<Synth>, patches: impl IntoIterator<Item = &'a Patch>) {
+    let missing = synth.lock().unwrap().missing_files(patches);
+    if !missing.is_empty() {
+        let files = missing.load();
+        synth.lock().unwrap().add_files(files);
+    }
+}
+
+// Checks a channel number from the frontend, channels are numbered from 0 to 15
+fn check_channel(channel: u8) -> Result<u8, String> {
+    if (channel as usize) < NUM_CHANNELS {
+        Ok(channel)
+    } else {
+        Err(format!("Invalid channel: {}", channel))
+    }
+}
+
+#[tauri::command]
+fn get_channel(
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+) -> Result<ChannelSettings, String> {
+    let channel = check_channel(channel)?;
+    Ok(synth_state.synth.lock().unwrap().channel_settings(channel).clone())
+}
+
+#[tauri::command]
+fn set_channel(
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+    settings: ChannelSettings,
+) -> Result<(), String> {
+    let channel = check_channel(channel)?;
+    load_patch_files(&synth_state.synth, [&settings.patch]);
+    synth_state.synth.lock().unwrap().set_channel_settings(channel, settings);
+    Ok(())
+}
+
+// The modulation matrix is part of the channel's patch, these let the frontend edit it on its own
+#[tauri::command]
+fn get_mod_matrix(
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+) -> Result<Vec<ModSlot>, String> {
+    let channel = check_channel(channel)?;
+    Ok(synth_state.synth.lock().unwrap().patch(channel).mod_matrix.clone())
+}
+
+#[tauri::command]
+fn set_mod_matrix(
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+    mod_matrix: Vec<ModSlot>,
+) -> Result<(), String> {
+    let channel = check_channel(channel)?;
+    let mut synth = synth_state.synth.lock().unwrap();
+    let mut patch = synth.patch(channel).clone();
+    patch.mod_matrix = mod_matrix;
+    synth.set_patch(channel, patch);
+    Ok(())
+}
+
+// Lets the user pick a WAV file for one of the oscillators of a channel's patch to play as a wavetable.
+// Returns the path of the file, or None if the dialog was cancelled.
+#[tauri::command]
+async fn load_wavetable(
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+    oscillator: usize,
+) -> Result<Option<String>, String> {
+    let channel = check_channel(channel)?;
+    if oscillator >= NUM_OSCILLATORS {
+        return Err(format!("Invalid oscillator: {}", oscillator));
+    }
+    let path_buf = dialog::blocking::FileDialogBuilder::default()
+        .add_filter("Wave", &["wav"])
+        .pick_file();
+    let path_buf = match path_buf {
+        Some(path_buf) => path_buf,
+        None => return Ok(None),
+    };
+    let wavetable = wavetable::load_wavetable(&path_buf).map_err(|e| e.to_string())?;
+    let path = path_buf.to_string_lossy().to_string();
+
+    let mut synth = synth_state.synth.lock().unwrap();
+    synth.add_wavetable(path.clone(), wavetable);
+    let mut patch = synth.patch(channel).clone();
+    patch.oscillators[oscillator].wave_type = WaveType::Wavetable;
+    patch.oscillators[oscillator].wavetable = Some(path.clone());
+    synth.set_patch(channel, patch);
+    Ok(Some(path))
+}
+
+// Asks for an audio file and adds it to a channel's patch as a zone covering every key and velocity,
+// which also switches the patch to the sampler
+#[tauri::command]
+async fn add_sample_zone(
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+) -> Result<Option<SampleZone>, String> {
+    let channel = check_channel(channel)?;
+    let path_buf = dialog::blocking::FileDialogBuilder::default()
+        .add_filter("Audio", &["wav", "flac", "ogg"])
+        .pick_file();
+    let path_buf = match path_buf {
+        Some(path_buf) => path_buf,
+        None => return Ok(None),
+    };
+    let sample = sampler::load_sample(&path_buf).map_err(|e| e.to_string())?;
+    let path = path_buf.to_string_lossy().to_string();
+
+    let mut synth = synth_state.synth.lock().unwrap();
+    synth.add_sample(path.clone(), sample);
+    let zone = SampleZone {
+        path,
+        ..SampleZone::default()
+    };
+    let mut patch = synth.patch(channel).clone();
+    patch.patch_type = PatchType::Sampler;
+    patch.sampler.zones.push(zone.clone());
+    synth.set_patch(channel, patch);
+    Ok(Some(zone))
+}
+
+#[tauri::command]
+fn get_polyphony(synth_state: tauri::State<'_, SynthState>) -> PolyphonySettings {
+    synth_state.synth.lock().unwrap().polyphony().clone()
+}
+
+#[tauri::command]
+fn set_polyphony(synth_state: tauri::State<'_, SynthState>, polyphony: PolyphonySettings) {
+    synth_state.synth.lock().unwrap().set_polyphony(polyphony);
+}
+
+#[tauri::command]
+fn get_voice_stats(synth_state: tauri::State<'_, SynthState>) -> VoiceStats {
+    synth_state.synth.lock().unwrap().stats()
+}
+
+// Presets are saved as JSON files in the app data directory
+fn presets_dir(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    app_handle
+        .path_resolver()
+        .app_data_dir()
+        .map(|dir| dir.join("presets"))
+        .ok_or_else(|| "Could not find the app data directory".to_string())
+}
+
+#[tauri::command]
+fn list_presets(app_handle: AppHandle) -> Result<Vec<String>, String> {
+    presets::list_presets(&presets_dir(&app_handle)?).map_err(|e| e.to_string())
+}
+
+// Saves a channel's patch as a preset
+#[tauri::command]
+fn save_preset(
+    app_handle: AppHandle,
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+    name: String,
+) -> Result<(), String> {
+    let channel = check_channel(channel)?;
+    let patch = synth_state.synth.lock().unwrap().patch(channel).clone();
+    presets::save_preset(&presets_dir(&app_handle)?, &name, &patch).map_err(|e| e.to_string())
+}
+
+// Loads a preset into a channel, and returns it so the frontend can show its settings
+#[tauri::command]
+fn load_preset(
+    app_handle: AppHandle,
+    synth_state: tauri::State<'_, SynthState>,
+    channel: u8,
+    name: String,
+) -> Result<Patch, String> {
+    let channel = check_channel(channel)?;
+    let patch =
+        presets::load_preset(&presets_dir(&app_handle)?, &name).map_err(|e| e.to_string())?;
+    load_patch_files(&synth_state.synth, [&patch]);
+    synth_state.synth.lock().unwrap().set_patch(channel, patch.clone());
+    Ok(patch)
+}
+
+// The program map is saved as a JSON file in the app data directory, once the user has changed it
+fn program_map_path(app_handle: &AppHandle) -> Result<PathBuf, String> {
+    app_handle
+        .path_resolver()
+        .app_data_dir()
+        .map(|dir| dir.join("programs.json"))
+        .ok_or_else(|| "Could not find the app data directory".to_string())
+}
+
+#[tauri::command]
+fn get_program_map(synth_state: tauri::State<'_, SynthState>) -> ProgramMap {
+    synth_state.synth.lock().unwrap().program_map().clone()
+}
+
+// Sets the patch that a program number switches to, and saves the program map
+#[tauri::command]
+fn set_program(
+    app_handle: AppHandle,
+    synth_state: tauri::State<'_, SynthState>,
+    program: u8,
+    patch: Patch,
+) -> Result<(), String> {
+    if program as usize >= NUM_PROGRAMS {
+        return Err(format!("Invalid program: {}", program));
+    }
+    load_patch_files(&synth_state.synth, [&patch]);
+    let mut synth = synth_state.synth.lock().unwrap();
+    let mut program_map = synth.program_map().clone();
+    program_map.set_program(program, patch);
+    programs::save_program_map(&program_map_path(&app_handle)?, &program_map)
+        .map_err(|e| e.to_string())?;
+    synth.set_program_map(program_map);
+    Ok(())
+}
+
+// Asks for a SoundFont and switches the program map to its instruments, so that MIDI files play with them.
+// The program map is saved with the SoundFont's path, so the SoundFont is loaded again when the app starts.
+#[tauri::command]
+async fn load_soundfont(
+    app_handle: AppHandle,
+    synth_state: tauri::State<'_, SynthState>,
+) -> Result<(), String> {
+    let path_buf = dialog::blocking::FileDialogBuilder::default()
+        .add_filter("SoundFont", &["sf2"])
+        .pick_file();
+    let path_buf = match path_buf {
+        Some(path_buf) => path_buf,
+        None => return Ok(()),
+    };
+    let soundfont = soundfont::load_soundfont(&path_buf).map_err(|e| e.to_string())?;
+    let path = path_buf.to_string_lossy().to_string();
+
+    let mut synth = synth_state.synth.lock().unwrap();
+    synth.set_soundfont(&path, soundfont);
+    programs::save_program_map(&program_map_path(&app_handle)?, synth.program_map())
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn delete_preset(app_handle: AppHandle, name: String) -> Result<(), String> {
+    presets::delete_preset(&presets_dir(&app_handle)?, &name).map_err(|e| e.to_string())
 }
 
 #[tauri::command(async)]
@@ -265,108 +570,66 @@ fn play_arrangement(window: Window<Wry>, midi_player_state: tauri::State<'_, Mid
         .ok();
     // END SENDING DATA TO FRONT END
 
-    let mut next_track_times = vec![0; arangements.len()];
-    let mut track_iterators = vec![];
-    for track in arangements.iter() {
-        let track_iterator = track.track.iter();
-        track_iterators.push(track_iterator);
-    }
-    let mut track_timings = vec![];
-    for track in arangements.iter() {
-        let track_timing = track.timing;
-        track_timings.push(track_timing);
-    }
-    let mut time_per_ticks = vec![];
-    let mut length_in_microseconds = 0.0;
-    for track_timing in track_timings.iter() {
-        match track_timing {
-            midly::Timing::Metrical(ticks_per_beat) => {
-                let time_per_tick = (tempo.clone() as f32) / ticks_per_beat.as_int() as f32;
-                time_per_ticks.push(time_per_tick);
-                // Update length in microseconds if new calculated length is longer
-                // println!("length in microseconds: {}", length_in_microseconds);
-                // println!("length in ticks: {}", *length_in_ticks);
-                if length_in_microseconds < (*length_in_ticks as f32 * time_per_tick) {
-                    // println!("Length in ticks: {}", length_in_ticks);
-                    // println!("Time per tick: {}", time_per_tick);
-                    length_in_microseconds = *length_in_ticks as f32 * time_per_tick;
-                    // println!("New length in microseconds: {}", length_in_microseconds);
-                }
-            }
-            midly::Timing::Timecode(fps, _) => {
-                let time_per_tick = 1.0 / fps.as_f32();
-                time_per_ticks.push(time_per_tick);
-                // Update length in microseconds if new calculated length is longer
-                if length_in_microseconds < (*length_in_ticks as f32 * time_per_tick) {
-                    length_in_microseconds = *length_in_ticks as f32 * time_per_tick;
-                }
-            }
-        }
-    }
+    // The tracks are merged into one list and timed with every tempo change in the file, the same way as when rendering,
+    // so that controllers on one track stay in order with the notes they change on the others
+    let tracks: Vec<Track> = arangements.iter().map(|track| track.track.clone()).collect();
+    let timing = arangements.first().map_or(midly::Timing::Metrical(480.into()), |track| track.timing);
+    let tempo_map = TempoMap::new(timing, &tracks);
+    let length_in_microseconds = tempo_map.microseconds(*length_in_ticks as u64) as f32;
+    let events = timed_events(timing, &tracks);
     println!("Length in microseconds: {}", length_in_microseconds);
     // Length in minutes
     println!("Length in minutes: {}", length_in_microseconds / 1000000.0 / 60.0);
-    // print time per ticks vec
-    println!("Time per ticks: {:?}", time_per_ticks);
 
     let mut current_line = 0;
     let mut active_notes = Vec::new();
     let mut last_line_time = 0;
     let mut front_end_notes = Vec::new();
 
+    let mut events = events.iter().peekable();
+    let mut tempo_changes = tempo_map.tempo_changes().peekable();
+    let synth = &handle.state::<SynthState>().synth;
     let now = std::time::Instant::now();
     loop {
-        let mut num_finished_tracks = 0;
-        for (i, track_time) in next_track_times.iter_mut().enumerate() {
-            if *track_time <= full_track_time {
-                let event = track_iterators[i].next();
-                match event {
-                    Some(event) => {
-                        let delta_time = event.delta.as_int();
-                        *track_time += delta_time * time_per_ticks[i] as u32;
-                        match mildy_event_handler(*event, handle.clone()) {
-                            Some(SimpleNote::On(key, vel)) => {
-                                handle.emit_and_trigger("midi_message", MidiMessage { message: vec![144, key, vel] }).map_err(|e| {
-                                    println!("Error sending midi message: {}", e);
-                                })
-                                .ok();
-
-                                let time_since_last_line = full_track_time - last_line_time;
-                                active_notes.push([key as u32, vel as u32, time_since_last_line]);
-                            },
-                            Some(SimpleNote::Off(key)) => {
-                                handle.emit_and_trigger("midi_message", MidiMessage { message: vec![128, key, 0] }).map_err(|e| {
-                                    println!("Error sending midi message: {}", e);
-                                })
-                                .ok();
-
-                                // Iterate over active notes and remove the one with the same key
-                                for (i, note) in active_notes.iter().enumerate() {
-                                    if note[0] == key as u32 {
-                                        let note = active_notes.remove(i);
-                                        front_end_notes.push(FrontEndNote {
-                                            start_time: note[2],
-                                            end_time: full_track_time,
-                                            note: note[0] as u8,
-                                            velocity: note[1] as u8,
-                                        });
-                                        break;
-                                    }
-                                }
-                            },
-                            None => {}
+        // Synced LFOs follow every tempo change that is due, like when rendering
+        while let Some((_, tempo)) = tempo_changes.next_if(|(time, _)| *time <= full_track_time as u64) {
+            synth.lock().unwrap().set_tempo(tempo);
+        }
+        // Play every event that is due
+        while let Some(timed_event) = events.next_if(|timed_event| timed_event.time <= full_track_time as u64) {
+            // Every channel message is played, like when rendering, so that controllers, pitch bends and program changes are heard too
+            let mut message_bytes = Vec::new();
+            if timed_event.event.write_std(&mut message_bytes).is_ok() {
+                handle.emit_and_trigger("midi_message", MidiMessage { message: message_bytes }).map_err(|e| {
+                    println!("Error sending midi message: {}", e);
+                })
+                .ok();
+            }
+            // Notes are also sent to the front end to draw
+            match timed_event.event {
+                LiveEvent::Midi { message: midly::MidiMessage::NoteOn { key, vel }, .. } => {
+                    let time_since_last_line = full_track_time - last_line_time;
+                    active_notes.push([key.as_int() as u32, vel.as_int() as u32, time_since_last_line]);
+                },
+                LiveEvent::Midi { message: midly::MidiMessage::NoteOff { key, .. }, .. } => {
+                    // Iterate over active notes and remove the one with the same key
+                    for (i, note) in active_notes.iter().enumerate() {
+                        if note[0] == key.as_int() as u32 {
+                            let note = active_notes.remove(i);
+                            front_end_notes.push(FrontEndNote {
+                                start_time: note[2],
+                                end_time: full_track_time,
+                                note: note[0] as u8,
+                                velocity: note[1] as u8,
+                            });
+                            break;
                         }
                     }
-                    None => {
-                        // If .next() returns None, the track is finished
-                        num_finished_tracks += 1;
-                        // Set the next track time to the max value so it won't be played again
-                        *track_time = std::u32::MAX;
-                    }
-                }
+                },
+                _ => {}
             }
         }
-        if num_finished_tracks == track_iterators.len() {
+        if events.peek().is_none() {
             println!("Finished playing");
             break;
         }
@@ -400,64 +663,57 @@ fn play_arrangement(window: Window<Wry>, midi_player_state: tauri::State<'_, Mid
         // println!("Full track time: {}", full_track_time);
 
         // Wait until the closest track time
-        full_track_time = std::cmp::min(*next_track_times.iter().min().unwrap(), current_line*microseconds_per_line);
+        full_track_time = std::cmp::min(events.peek().map_or(std::u32::MAX, |timed_event| timed_event.time as u32), current_line*microseconds_per_line);
+        full_track_time = std::cmp::min(tempo_changes.peek().map_or(std::u32::MAX, |(time, _)| *time as u32), full_track_time);
         let wait_time = now + std::time::Duration::from_micros(full_track_time as u64);
         while std::time::Instant::now() < wait_time {}
     }
     println!("Finished playing");
 }
 
-fn mildy_event_handler(event: midly::TrackEvent, handle: Arc<tauri::Window>) -> Option<SimpleNote> {
-    // println!("Event: {:?}", event);
-    // Match the event
-    match event.kind {
-        // If the event is a note on event
-        midly::TrackEventKind::Midi { channel: _, message } => {
-            match message {
-                // If the message is a note on message
-                midly::MidiMessage::NoteOn { key, vel } => {
-                    if vel > 0 {
-                        // // println!("Note on");
-                        // handle.emit_and_trigger("midi_message", MidiMessage { message: vec![144, key.into(), vel.into()] }).map_err(|e| {
-                        //     println!("Error sending midi message: {}", e);
-                        // })
-                        // .ok();
-                        return Some(SimpleNote::On(key.into(), vel.into()));
-                    } else {
-                        // handle.emit_and_trigger("midi_message", MidiMessage { message: vec![128, key.into(), vel.into()] }).map_err(|e| {
-                        //     println!("Error sending midi message: {}", e);
-                        // })
-                        // .ok();
-                        return Some(SimpleNote::Off(key.into()));
-                    }
-                }
-                // If the message is a note off message
-                midly::MidiMessage::NoteOff { key, vel } => {
-                    // handle.emit_and_trigger("midi_message", MidiMessage { message: vec![128, key.into(), vel.into()] }).map_err(|e| {
-                    //     println!("Error sending midi message: {}", e);
-                    // })
-                    // .ok();
-                    return Some(SimpleNote::Off(key.into()));
-                }
-                _ => {None}
-            }
-        }
-        _ => {None}
-    }
-}
-
 fn main() {
     // Get an output stream handle to the default physical sound device
-    let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-
-    let synth = Mutex::new(Synth::new(stream_handle));
+    let device = rodio::cpal::default_host()
+        .default_output_device()
+        .expect("No output device found");
+    let config = device
+        .default_output_config()
+        .expect("Failed to get output config");
+    // Run the synth at the device's sample rate so that rodio doesn't have to resample it
+    let sample_rate = config.sample_rate().0;
+    let (_stream, stream_handle) = OutputStream::try_from_device_config(&device, config).unwrap();
+
+    // The synth is played as a single source which mixes all of the notes itself
+    let synth = Arc::new(Mutex::new(Synth::new(sample_rate)));
+    stream_handle
+        .play_raw(SynthSource::new(synth.clone()))
+        .expect("Failed to play synth");
 
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
             open_midi_connection, 
-            update_synth, 
+            list_midi_ports,
+            connect_midi_port,
+            disconnect_midi_port,
             file_upload, 
-            play_arrangement
+            play_arrangement,
+            render_arrangement,
+            get_channel,
+            set_channel,
+            list_presets,
+            save_preset,
+            load_preset,
+            delete_preset,
+            get_program_map,
+            set_program,
+            get_polyphony,
+            set_polyphony,
+            get_voice_stats,
+            get_mod_matrix,
+            set_mod_matrix,
+            load_wavetable,
+            add_sample_zone,
+            load_soundfont
         ])
         .manage(MidiState::default())
         .manage(SynthState { synth })
@@ -467,28 +723,45 @@ fn main() {
         })
         .setup(|app| {
             let handle = app.handle();
+
+            // Give new users some presets to start with
+            let installed = presets_dir(&handle)
+                .and_then(|dir| presets::install_factory_presets(&dir).map_err(|e| e.to_string()));
+            if let Err(e) = installed {
+                println!("Error installing factory presets: {}", e);
+            }
+
+            // Use the program map the user has saved, if there is one
+            let program_map = program_map_path(&handle)
+                .and_then(|path| programs::load_program_map(&path).map_err(|e| e.to_string()));
+            match program_map {
+                Ok(program_map) => {
+                    let synth = &handle.state::<SynthState>().synth;
+                    load_patch_files(synth, program_map.patches());
+                    synth.lock().unwrap().set_program_map(program_map);
+                }
+                Err(e) => println!("Error loading program map: {}", e),
+            }
+
             let _id = app.listen_global("midi_message", move |event| {
                 // Get the synth state
                 let synth_state = &handle.state::<SynthState>().synth;
                 let mut synth = synth_state.lock().unwrap();
 
                 // Deserialize the payload
-                let message =
-                    serde_json::from_str::<MidiMessage>(event.payload().unwrap()).unwrap();
-                let message = message.message;
-
-                let hz = 440.0 * 2.0_f32.powf((message[1] as f32 - 69.0) / 12.0);
-                let pressure = message[2] as f32 / 127.0;
-
-                if message[0] == 144 {
-                    // 144 is the event for note on
-                    let audio_source = Oscillator::sawtooth_wave(hz).amplify(pressure);
-                    let envelope = Envelope::new(0.0, 2.0, 0.0, 0.0); // example envelope
-                    synth.play_source(Box::new(audio_source), message[1], envelope)
-                }
-                if message[0] == 128 {
-                    // 128 is the event for note off
-                    synth.release_source(message[1])
+                let message = match event
+                    .payload()
+                    .map(serde_json::from_str::<MidiMessage>)
+                {
+                    Some(Ok(message)) => message.message,
+                    _ => {
+                        println!("Invalid midi message payload: {:?}", event.payload());
+                        return;
+                    }
+                };
+
+                if let Some(event) = parse_message(&message) {
+                    synth.handle_event(event);
                 }
             });
             Ok(())
@@ -496,30 +769,3 @@ fn main() {
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
-
-fn get_midi_data(smf: &midly::Smf) -> MidiData {
-    let mut tempo = 500000;
-    let mut length_in_ticks = 0;
-    let mut meta_track_index = None;
-    for (i, track) in smf.tracks.iter().enumerate() {
-        let mut length_in_ticks_tmp = 0;
-        for event in track.iter() {
-            let delta_time = event.delta.as_int();
-            length_in_ticks_tmp += delta_time;
-            match event.kind {
-                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo_event)) => {
-                    tempo = tempo_event.as_int();
-                    meta_track_index = Some(i);
-                }
-                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) => {
-                    if length_in_ticks_tmp > length_in_ticks {
-                        length_in_ticks = length_in_ticks_tmp;
-                    }
-                    break;
-                }
-                _ => {}
-            }
-        }
-    };
-    MidiData { tempo, length_in_ticks, meta_track_index }
-}
\ No newline at end of file
@@ -0,0 +1,110 @@
+// This file is for turning raw MIDI bytes into typed messages, using midly's parser for live MIDI.
+// Every channel voice, system common and system realtime message is understood, and anything
+// that is too short or malformed is ignored instead of causing a panic.
+
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+
+pub fn parse_message(bytes: &[u8]) -> Option<LiveEvent<'_>> {
+    match LiveEvent::parse(bytes) {
+        Ok(event) => Some(normalize(event)),
+        Err(e) => {
+            println!("Error parsing midi message {:?}: {}", bytes, e);
+            None
+        }
+    }
+}
+
+// A note on with a velocity of 0 is how many keyboards send note off, so it is turned into a
+// note off here rather than checked for everywhere that notes are handled
+pub fn normalize(event: LiveEvent) -> LiveEvent {
+    match event {
+        LiveEvent::Midi {
+            channel,
+            message: MidiMessage::NoteOn { key, vel },
+        } if vel == 0 => LiveEvent::Midi {
+            channel,
+            message: MidiMessage::NoteOff { key, vel },
+        },
+        event => event,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::live::SystemRealtime;
+
+    #[test]
+    fn realtime_messages_are_one_byte() {
+        assert!(matches!(
+            parse_message(&[0xF8]),
+            Some(LiveEvent::Realtime(SystemRealtime::TimingClock))
+        ));
+        assert!(matches!(
+            parse_message(&[0xFA]),
+            Some(LiveEvent::Realtime(SystemRealtime::Start))
+        ));
+    }
+
+    #[test]
+    fn short_and_malformed_messages_are_ignored() {
+        assert!(parse_message(&[]).is_none());
+        assert!(parse_message(&[0x90, 60]).is_none()); // Missing the velocity
+        assert!(parse_message(&[0x40, 60, 100]).is_none()); // No status byte
+        assert!(parse_message(&[0x90, 0xBC, 100]).is_none()); // A status byte where a data byte should be
+    }
+
+    #[test]
+    fn note_on_with_no_velocity_is_note_off() {
+        match parse_message(&[0x90, 60, 0]) {
+            Some(LiveEvent::Midi {
+                message: MidiMessage::NoteOff { key, .. },
+                ..
+            }) => assert_eq!(key, 60),
+            event => panic!("{:?}", event),
+        }
+        assert!(matches!(
+            parse_message(&[0x90, 60, 1]),
+            Some(LiveEvent::Midi {
+                message: MidiMessage::NoteOn { .. },
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn the_channel_is_read_from_the_status_byte() {
+        match parse_message(&[0x9F, 60, 100]) {
+            Some(LiveEvent::Midi { channel, .. }) => assert_eq!(channel, 15),
+            event => panic!("{:?}", event),
+        }
+        match parse_message(&[0xB3, 64, 127]) {
+            Some(LiveEvent::Midi {
+                channel,
+                message: MidiMessage::Controller { controller, value },
+            }) => assert_eq!(
+                (channel.as_int(), controller.as_int(), value.as_int()),
+                (3, 64, 127)
+            ),
+            event => panic!("{:?}", event),
+        }
+    }
+
+    #[test]
+    fn pitch_bends_are_14_bits_with_the_low_byte_first() {
+        let bend = |bytes: &[u8]| match parse_message(bytes) {
+            Some(LiveEvent::Midi {
+                message: MidiMessage::PitchBend { bend },
+                ..
+            }) => bend,
+            event => panic!("{:?}", event),
+        };
+        assert_eq!(bend(&[0xE0, 0x00, 0x40]).as_int(), 0);
+        assert_eq!(bend(&[0xE0, 0x01, 0x40]).as_int(), 1);
+        assert_eq!(bend(&[0xE0, 0x00, 0x41]).as_int(), 128);
+        assert_eq!(bend(&[0xE0, 0x00, 0x00]).as_int(), -8192);
+        assert_eq!(bend(&[0xE0, 0x7F, 0x7F]).as_int(), 8191);
+        assert_eq!(bend(&[0xE0, 0x00, 0x00]).as_f32(), -1.0);
+    }
+}
@@ -0,0 +1,253 @@
+// This file is for reading notes out of MIDI files, shared by the live player and the offline renderer.
+
+use midly::live::LiveEvent;
+
+use crate::midi::normalize;
+
+pub struct MidiData {
+    pub tempo: u32, // The tempo the file starts at, in microseconds per beat
+    pub length_in_ticks: u32,
+    pub meta_track_index: Option<usize>,
+}
+
+// A MIDI message with the time it happens at, in microseconds from the start of the file
+pub struct TimedEvent {
+    pub time: u64,
+    pub event: LiveEvent<'static>,
+}
+
+pub fn get_midi_data(smf: &midly::Smf) -> MidiData {
+    let mut tempo = 500000;
+    let mut tempo_ticks = None; // When the earliest tempo event happens, which sets the starting tempo
+    let mut length_in_ticks = 0;
+    let mut meta_track_index = None;
+    for (i, track) in smf.tracks.iter().enumerate() {
+        let mut length_in_ticks_tmp = 0;
+        for event in track.iter() {
+            let delta_time = event.delta.as_int();
+            length_in_ticks_tmp += delta_time;
+            match event.kind {
+                midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo_event)) => {
+                    if tempo_ticks.map_or(true, |ticks| length_in_ticks_tmp < ticks) {
+                        tempo = tempo_event.as_int();
+                        tempo_ticks = Some(length_in_ticks_tmp);
+                    }
+                    meta_track_index = Some(i);
+                }
+                midly::TrackEventKind::Meta(midly::MetaMessage::EndOfTrack) => {
+                    if length_in_ticks_tmp > length_in_ticks {
+                        length_in_ticks = length_in_ticks_tmp;
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    MidiData {
+        tempo,
+        length_in_ticks,
+        meta_track_index,
+    }
+}
+
+// Converts times in ticks to microseconds, following every tempo change in the file.
+// Files timed in frames of timecode don't depend on the tempo, so their ticks are always the same length.
+pub struct TempoMap {
+    changes: Vec<TempoChange>, // Sorted by tick, the first one is at tick 0
+}
+
+struct TempoChange {
+    ticks: u64,
+    microseconds: f64, // When the change happens
+    microseconds_per_tick: f64,
+    tempo: Option<u32>, // In microseconds per beat, None for files timed in timecode
+}
+
+impl TempoMap {
+    pub fn new(timing: midly::Timing, tracks: &[midly::Track]) -> TempoMap {
+        let ticks_per_beat = match timing {
+            midly::Timing::Metrical(ticks_per_beat) => ticks_per_beat.as_int().max(1) as f64,
+            midly::Timing::Timecode(fps, ticks_per_frame) => {
+                return TempoMap {
+                    changes: vec![TempoChange {
+                        ticks: 0,
+                        microseconds: 0.0,
+                        microseconds_per_tick: 1_000_000.0
+                            / (fps.as_f32() as f64 * ticks_per_frame.max(1) as f64),
+                        tempo: None,
+                    }],
+                };
+            }
+        };
+
+        let mut tempos = Vec::new();
+        for track in tracks.iter() {
+            let mut ticks: u64 = 0;
+            for event in track.iter() {
+                ticks += event.delta.as_int() as u64;
+                if let midly::TrackEventKind::Meta(midly::MetaMessage::Tempo(tempo)) = event.kind {
+                    tempos.push((ticks, tempo.as_int()));
+                }
+            }
+        }
+        // The sort is stable, so when tracks change the tempo at the same time the last track wins
+        tempos.sort_by_key(|(ticks, _)| *ticks);
+
+        let mut changes = vec![TempoChange {
+            ticks: 0,
+            microseconds: 0.0,
+            microseconds_per_tick: 500_000.0 / ticks_per_beat, // 120 bpm until the file says otherwise
+            tempo: Some(500_000),
+        }];
+        for (ticks, tempo) in tempos {
+            let microseconds = changes.last().unwrap().microseconds_at(ticks);
+            // A change at the same time as the one before replaces it
+            if changes.last().unwrap().ticks == ticks {
+                changes.pop();
+            }
+            changes.push(TempoChange {
+                ticks,
+                microseconds,
+                microseconds_per_tick: tempo as f64 / ticks_per_beat,
+                tempo: Some(tempo),
+            });
+        }
+        TempoMap { changes }
+    }
+
+    pub fn microseconds(&self, ticks: u64) -> u64 {
+        let index = self
+            .changes
+            .partition_point(|change| change.ticks <= ticks)
+            .max(1);
+        self.changes[index - 1].microseconds_at(ticks) as u64
+    }
+
+    // The tempo at the start and after every change, with the time it starts in microseconds, for synced LFOs to follow
+    pub fn tempo_changes(&self) -> impl Iterator<Item = (u64, u32)> + '_ {
+        self.changes.iter().filter_map(|change| {
+            change
+                .tempo
+                .map(|tempo| (change.microseconds as u64, tempo))
+        })
+    }
+}
+
+impl TempoChange {
+    fn microseconds_at(&self, ticks: u64) -> f64 {
+        self.microseconds + (ticks - self.ticks) as f64 * self.microseconds_per_tick
+    }
+}
+
+// Collects the MIDI messages from every track into a single list, sorted by the time they are played
+pub fn get_timed_events(smf: &midly::Smf) -> Vec<TimedEvent> {
+    timed_events(smf.header.timing, &smf.tracks)
+}
+
+// The same as get_timed_events, for tracks that have been taken out of their file
+pub fn timed_events(timing: midly::Timing, tracks: &[midly::Track]) -> Vec<TimedEvent> {
+    let tempo_map = TempoMap::new(timing, tracks);
+
+    let mut events = Vec::new();
+    for track in tracks.iter() {
+        let mut ticks: u64 = 0;
+        for event in track.iter() {
+            ticks += event.delta.as_int() as u64;
+            if let midly::TrackEventKind::Midi { channel, message } = event.kind {
+                events.push(TimedEvent {
+                    time: tempo_map.microseconds(ticks),
+                    event: normalize(LiveEvent::Midi { channel, message }),
+                });
+            }
+        }
+    }
+    // The sort is stable, so events at the same time stay in track order
+    events.sort_by_key(|event| event.time);
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::{MetaMessage, MidiMessage, TrackEvent, TrackEventKind};
+
+    const TICKS_PER_BEAT: u16 = 480;
+
+    fn tempo(delta: u32, microseconds_per_beat: u32) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(microseconds_per_beat.into())),
+        }
+    }
+
+    fn note_on(delta: u32, key: u8) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind: TrackEventKind::Midi {
+                channel: 0.into(),
+                message: MidiMessage::NoteOn {
+                    key: key.into(),
+                    vel: 100.into(),
+                },
+            },
+        }
+    }
+
+    fn smf(tracks: Vec<Vec<TrackEvent<'static>>>) -> midly::Smf<'static> {
+        let mut smf = midly::Smf::new(midly::Header::new(
+            midly::Format::Parallel,
+            midly::Timing::Metrical(TICKS_PER_BEAT.into()),
+        ));
+        smf.tracks = tracks;
+        smf
+    }
+
+    #[test]
+    fn ticks_are_timed_at_120_bpm_without_a_tempo() {
+        let smf = smf(vec![vec![note_on(0, 60), note_on(480, 62)]]);
+        let events = get_timed_events(&smf);
+        assert_eq!(events[0].time, 0);
+        assert_eq!(events[1].time, 500_000);
+    }
+
+    #[test]
+    fn every_tempo_change_is_followed() {
+        // One beat at 120 bpm, one at 60 bpm, then one at 240 bpm
+        let smf = smf(vec![
+            vec![
+                tempo(0, 500_000),
+                tempo(480, 1_000_000),
+                tempo(480, 250_000),
+            ],
+            vec![
+                note_on(480, 60),
+                note_on(480, 62),
+                note_on(480, 64),
+                note_on(480, 65),
+            ],
+        ]);
+        let times: Vec<u64> = get_timed_events(&smf)
+            .iter()
+            .map(|event| event.time)
+            .collect();
+        assert_eq!(times, vec![500_000, 1_500_000, 1_750_000, 2_000_000]);
+    }
+
+    #[test]
+    fn tempo_changes_part_way_through_a_beat_split_it() {
+        let smf = smf(vec![vec![tempo(240, 1_000_000)]]);
+        let tempo_map = TempoMap::new(smf.header.timing, &smf.tracks);
+        assert_eq!(tempo_map.microseconds(240), 250_000);
+        assert_eq!(tempo_map.microseconds(480), 750_000);
+    }
+
+    #[test]
+    fn the_starting_tempo_is_the_earliest_one() {
+        let smf = smf(vec![
+            vec![tempo(0, 400_000), tempo(960, 600_000)],
+            vec![tempo(480, 300_000)],
+        ]);
+        assert_eq!(get_midi_data(&smf).tempo, 400_000);
+    }
+}
@@ -0,0 +1,114 @@
+// This file is for connecting to MIDI input ports. Any number of ports can be connected at once,
+// and ports that are plugged in while the app is running are connected when the ports are rescanned.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use midir::{MidiInput, MidiInputConnection, MidiInputPort};
+use serde::Serialize;
+
+const CLIENT_NAME: &str = "ui_synth";
+
+// Called with the bytes of every MIDI message received from any port
+pub type MessageCallback = Arc<dyn Fn(&[u8]) + Send + Sync>;
+
+// A port and whether the synth is listening to it, sent to the frontend
+#[derive(Clone, Serialize)]
+pub struct MidiPort {
+    pub name: String,
+    pub connected: bool,
+}
+
+#[derive(Default)]
+pub struct MidiInputs {
+    connections: HashMap<String, MidiInputConnection<()>>,
+    known_ports: Vec<String>,            // The ports found by the last scan
+    disconnected_ports: HashSet<String>, // Ports the user has disconnected, these aren't reconnected by a rescan
+    on_message: Option<MessageCallback>,
+}
+
+impl MidiInputs {
+    // Sets the function that receives messages from every port connected after this
+    pub fn set_callback(&mut self, on_message: MessageCallback) {
+        self.on_message = Some(on_message);
+    }
+
+    pub fn ports(&self) -> Vec<MidiPort> {
+        self.known_ports
+            .iter()
+            .map(|name| MidiPort {
+                name: name.clone(),
+                connected: self.connections.contains_key(name),
+            })
+            .collect()
+    }
+
+    pub fn connect(&mut self, name: &str) -> Result<(), String> {
+        self.disconnected_ports.remove(name);
+        if self.connections.contains_key(name) {
+            return Ok(());
+        }
+        let on_message = self
+            .on_message
+            .clone()
+            .ok_or_else(|| "MIDI input hasn't been opened yet".to_string())?;
+
+        // Each connection needs its own MidiInput, because connecting uses it up
+        let midi_in = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+        let port = find_port(&midi_in, name).ok_or_else(|| format!("No port named {}", name))?;
+        let connection = midi_in
+            .connect(&port, "midir", move |_, message, _| on_message(message), ())
+            .map_err(|e| e.to_string())?;
+        self.connections.insert(name.to_string(), connection);
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self, name: &str) {
+        self.disconnected_ports.insert(name.to_string());
+        // Dropping the connection closes it
+        self.connections.remove(name);
+    }
+
+    // Looks for ports that have been plugged in or unplugged since the last scan.
+    // New ports are connected automatically, and true is returned if the list of ports changed.
+    pub fn rescan(&mut self) -> Result<bool, String> {
+        let names = port_names()?;
+
+        // Forget about ports that have been unplugged
+        self.connections.retain(|name, _| names.contains(name));
+
+        let new_ports: Vec<String> = names
+            .iter()
+            .filter(|name| !self.known_ports.contains(name))
+            .cloned()
+            .collect();
+        for name in new_ports.iter() {
+            if !self.disconnected_ports.contains(name) {
+                if let Err(e) = self.connect(name) {
+                    println!("Error connecting to {}: {}", name, e);
+                }
+            }
+        }
+
+        let changed = names != self.known_ports;
+        self.known_ports = names;
+        Ok(changed)
+    }
+}
+
+pub fn port_names() -> Result<Vec<String>, String> {
+    let midi_in = MidiInput::new(CLIENT_NAME).map_err(|e| e.to_string())?;
+    Ok(midi_in
+        .ports()
+        .iter()
+        .filter_map(|port| midi_in.port_name(port).ok())
+        .collect())
+}
+
+fn find_port(midi_in: &MidiInput, name: &str) -> Option<MidiInputPort> {
+    midi_in.ports().into_iter().find(|port| {
+        midi_in
+            .port_name(port)
+            .map_or(false, |port_name| port_name == name)
+    })
+}
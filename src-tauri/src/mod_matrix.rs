@@ -0,0 +1,363 @@
+// This file is for the modulation matrix, which lets a patch route any modulation source to any of its numeric settings.
+// Each slot of the matrix adds a source, multiplied by the slot's amount, to a destination.
+// Destinations that a voice can change while it plays are modulated every sample. The rest are settings that are
+// used when a note starts, so they are modulated once at note on, using the value each source has at that moment.
+
+use serde::{Deserialize, Serialize};
+
+use crate::lfo::{LfoDestination, LfoSettings};
+use crate::patch::Patch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModSource {
+    AmpEnvelope,    // From 0 to 1
+    FilterEnvelope, // From 0 to 1
+    VoiceLfo,       // From -1 to 1
+    GlobalLfo,      // From -1 to 1
+    Velocity,       // From 0 to 1
+    Key,            // From -1 to 1, with middle C at 0
+    ModWheel,       // From 0 to 1
+    Aftertouch,     // From 0 to 1, the higher of the channel and key pressure
+    PitchBend,      // From -1 to 1
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ModDestination {
+    // Modulated every sample
+    Pitch,             // In semitones
+    Cutoff,            // In octaves
+    Resonance,         // Added to the resonance
+    Amplitude,         // Added to a volume multiplier of 1
+    Pan,               // Added to the pan
+    PulseWidth,        // Added to the width of the pulse waves
+    WavetablePosition, // Added to the position of the wavetables
+    VoiceLfoAmount,    // Added to the voice LFO's amount
+    GlobalLfoAmount,   // Added to the global LFO's amount
+    // Modulated at note on
+    Gain,
+    Attack, // Envelope times are in seconds
+    Decay,
+    Sustain,
+    Release,
+    FilterEnvelopeAmount, // In octaves
+    FilterKeyTracking,
+    FilterAttack,
+    FilterDecay,
+    FilterSustain,
+    FilterRelease,
+    VoiceLfoRate, // In Hz
+    VibratoRate,  // In Hz
+    VibratoDepth, // In semitones
+    PitchBendRange,
+    Portamento,       // The glide time, in seconds
+    OscillatorDetune, // In cents, tunes the second and third oscillators away from the first
+    UnisonDetune,     // In cents
+    UnisonSpread,
+    FmRatio1, // Added to an FM operator's frequency ratio
+    FmRatio2,
+    FmRatio3,
+    FmRatio4,
+    FmLevel1, // Added to an FM operator's level
+    FmLevel2,
+    FmLevel3,
+    FmLevel4,
+}
+
+impl ModDestination {
+    // Whether the destination is modulated every sample, rather than only at note on
+    pub fn is_continuous(self) -> bool {
+        matches!(
+            self,
+            ModDestination::Pitch
+                | ModDestination::Cutoff
+                | ModDestination::Resonance
+                | ModDestination::Amplitude
+                | ModDestination::Pan
+                | ModDestination::PulseWidth
+                | ModDestination::WavetablePosition
+                | ModDestination::VoiceLfoAmount
+                | ModDestination::GlobalLfoAmount
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModSlot {
+    pub source: ModSource,
+    pub destination: ModDestination,
+    pub amount: f32,
+}
+
+// The value of every modulation source for one voice, at one moment
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ModSources {
+    pub amp_envelope: f32,
+    pub filter_envelope: f32,
+    pub voice_lfo: f32,
+    pub global_lfo: f32,
+    pub velocity: f32,
+    pub key: f32,
+    pub mod_wheel: f32,
+    pub aftertouch: f32,
+    pub pitch_bend: f32,
+}
+
+impl ModSources {
+    pub fn value(&self, source: ModSource) -> f32 {
+        match source {
+            ModSource::AmpEnvelope => self.amp_envelope,
+            ModSource::FilterEnvelope => self.filter_envelope,
+            ModSource::VoiceLfo => self.voice_lfo,
+            ModSource::GlobalLfo => self.global_lfo,
+            ModSource::Velocity => self.velocity,
+            ModSource::Key => self.key,
+            ModSource::ModWheel => self.mod_wheel,
+            ModSource::Aftertouch => self.aftertouch,
+            ModSource::PitchBend => self.pitch_bend,
+        }
+    }
+}
+
+// Scales a key number so that middle C is 0 and the ends of the keyboard are about -1 and 1
+pub fn key_value(key: u8) -> f32 {
+    (key as f32 - 60.0) / 64.0
+}
+
+pub fn uses_source(matrix: &[ModSlot], source: ModSource) -> bool {
+    matrix
+        .iter()
+        .any(|slot| slot.source == source && slot.amount != 0.0)
+}
+
+// Changes the settings that are modulated at note on, in a copy of the patch made for one note
+pub fn apply_to_patch(patch: &mut Patch, sources: &ModSources) {
+    let slots = patch.mod_matrix.clone();
+    for slot in slots
+        .iter()
+        .filter(|slot| !slot.destination.is_continuous())
+    {
+        let value = sources.value(slot.source) * slot.amount;
+        let time = |seconds: &mut f32| *seconds = (*seconds + value).max(0.0);
+        let non_negative = |setting: &mut f32| *setting = (*setting + value).max(0.0);
+        let level = |level: &mut f32| *level = (*level + value).clamp(0.0, 1.0);
+        let operators = &mut patch.fm.operators;
+        match slot.destination {
+            ModDestination::Gain => patch.gain = (patch.gain + value).max(0.0),
+            ModDestination::Attack => time(&mut patch.envelope.attack),
+            ModDestination::Decay => time(&mut patch.envelope.decay),
+            ModDestination::Sustain => {
+                patch.envelope.sustain = (patch.envelope.sustain + value).clamp(0.0, 1.0)
+            }
+            ModDestination::Release => time(&mut patch.envelope.release),
+            ModDestination::FilterEnvelopeAmount => patch.filter.envelope_amount += value,
+            ModDestination::FilterKeyTracking => {
+                patch.filter.key_tracking = (patch.filter.key_tracking + value).clamp(0.0, 1.0)
+            }
+            ModDestination::FilterAttack => time(&mut patch.filter.envelope.attack),
+            ModDestination::FilterDecay => time(&mut patch.filter.envelope.decay),
+            ModDestination::FilterSustain => {
+                let sustain = &mut patch.filter.envelope.sustain;
+                *sustain = (*sustain + value).clamp(0.0, 1.0)
+            }
+            ModDestination::FilterRelease => time(&mut patch.filter.envelope.release),
+            ModDestination::VoiceLfoRate => time(&mut patch.voice_lfo.rate),
+            ModDestination::VibratoRate => time(&mut patch.vibrato_rate),
+            ModDestination::VibratoDepth => patch.vibrato_depth += value,
+            ModDestination::PitchBendRange => patch.pitch_bend_range += value,
+            ModDestination::Portamento => time(&mut patch.portamento),
+            ModDestination::OscillatorDetune => {
+                for oscillator in patch.oscillators.iter_mut().skip(1) {
+                    oscillator.cents += value;
+                }
+            }
+            ModDestination::UnisonDetune => non_negative(&mut patch.unison.detune),
+            ModDestination::UnisonSpread => level(&mut patch.unison.spread),
+            ModDestination::FmRatio1 => non_negative(&mut operators[0].ratio),
+            ModDestination::FmRatio2 => non_negative(&mut operators[1].ratio),
+            ModDestination::FmRatio3 => non_negative(&mut operators[2].ratio),
+            ModDestination::FmRatio4 => non_negative(&mut operators[3].ratio),
+            ModDestination::FmLevel1 => level(&mut operators[0].level),
+            ModDestination::FmLevel2 => level(&mut operators[1].level),
+            ModDestination::FmLevel3 => level(&mut operators[2].level),
+            ModDestination::FmLevel4 => level(&mut operators[3].level),
+            _ => {}
+        }
+    }
+}
+
+// The total modulation of a voice's continuous destinations, for one sample
+#[derive(Clone, Copy, Debug)]
+pub struct Modulation {
+    pub pitch: f32,              // In semitones
+    pub cutoff: f32,             // In octaves
+    pub resonance: f32,          // Added to the resonance
+    pub amplitude: f32,          // Multiplies the volume
+    pub pan: f32,                // Moves the note from its channel's pan
+    pub pulse_width: f32,        // Added to the width of the pulse waves
+    pub wavetable_position: f32, // Added to the position of the wavetables
+    pub voice_lfo_amount: f32,
+    pub global_lfo_amount: f32,
+}
+
+impl Default for Modulation {
+    fn default() -> Modulation {
+        Modulation {
+            pitch: 0.0,
+            cutoff: 0.0,
+            resonance: 0.0,
+            amplitude: 1.0,
+            pan: 0.0,
+            pulse_width: 0.0,
+            wavetable_position: 0.0,
+            voice_lfo_amount: 0.0,
+            global_lfo_amount: 0.0,
+        }
+    }
+}
+
+impl Modulation {
+    // Adds the slots of the matrix that modulate continuous destinations
+    pub fn add_matrix(&mut self, matrix: &[ModSlot], sources: &ModSources) {
+        for slot in matrix {
+            let value = sources.value(slot.source) * slot.amount;
+            match slot.destination {
+                ModDestination::Pitch => self.pitch += value,
+                ModDestination::Cutoff => self.cutoff += value,
+                ModDestination::Resonance => self.resonance += value,
+                ModDestination::Amplitude => self.amplitude = (self.amplitude + value).max(0.0),
+                ModDestination::Pan => self.pan += value,
+                ModDestination::PulseWidth => self.pulse_width += value,
+                ModDestination::WavetablePosition => self.wavetable_position += value,
+                ModDestination::VoiceLfoAmount => self.voice_lfo_amount += value,
+                ModDestination::GlobalLfoAmount => self.global_lfo_amount += value,
+                _ => {}
+            }
+        }
+    }
+
+    // Adds an LFO's value, from -1 to 1, to its destination. The amount is on top of the LFO's own amount.
+    pub fn add_lfo(&mut self, settings: &LfoSettings, value: f32, extra_amount: f32) {
+        let amount = settings.amount + extra_amount;
+        match settings.destination {
+            LfoDestination::Pitch => self.pitch += value * amount,
+            LfoDestination::Cutoff => self.cutoff += value * amount,
+            // Tremolo only turns the volume down from full, so that it never clips
+            LfoDestination::Amplitude => {
+                self.amplitude *= (1.0 - amount * (1.0 - value) / 2.0).max(0.0)
+            }
+            LfoDestination::Pan => self.pan += value * amount,
+            LfoDestination::PulseWidth => self.pulse_width += value * amount,
+            LfoDestination::WavetablePosition => self.wavetable_position += value * amount,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot(source: ModSource, destination: ModDestination, amount: f32) -> ModSlot {
+        ModSlot {
+            source,
+            destination,
+            amount,
+        }
+    }
+
+    fn with_matrix(mod_matrix: Vec<ModSlot>) -> Patch {
+        Patch {
+            mod_matrix,
+            ..Patch::default()
+        }
+    }
+
+    fn sources() -> ModSources {
+        ModSources {
+            velocity: 1.0,
+            mod_wheel: 0.5,
+            voice_lfo: -1.0,
+            ..ModSources::default()
+        }
+    }
+
+    #[test]
+    fn slots_add_their_source_times_their_amount() {
+        let matrix = vec![
+            slot(ModSource::ModWheel, ModDestination::Cutoff, 2.0),
+            slot(ModSource::Velocity, ModDestination::Cutoff, 0.5),
+            slot(ModSource::VoiceLfo, ModDestination::Pitch, 3.0),
+        ];
+        let mut modulation = Modulation::default();
+        modulation.add_matrix(&matrix, &sources());
+        assert_eq!(modulation.cutoff, 1.5);
+        assert_eq!(modulation.pitch, -3.0);
+        assert_eq!(modulation.pan, 0.0);
+    }
+
+    #[test]
+    fn note_on_destinations_only_change_the_patch() {
+        let mut patch = with_matrix(vec![
+            slot(ModSource::Velocity, ModDestination::Attack, 0.5),
+            slot(ModSource::Velocity, ModDestination::FmRatio2, 1.0),
+            slot(ModSource::ModWheel, ModDestination::UnisonDetune, 10.0),
+            slot(ModSource::Velocity, ModDestination::Cutoff, 1.0),
+        ]);
+        let original = Patch::default();
+        apply_to_patch(&mut patch, &sources());
+        assert_eq!(patch.envelope.attack, original.envelope.attack + 0.5);
+        assert_eq!(
+            patch.fm.operators[1].ratio,
+            original.fm.operators[1].ratio + 1.0
+        );
+        assert_eq!(patch.unison.detune, original.unison.detune + 5.0);
+        // The cutoff is modulated every sample instead, so the patch keeps its own
+        assert_eq!(patch.filter.cutoff, original.filter.cutoff);
+
+        let mut modulation = Modulation::default();
+        modulation.add_matrix(&patch.mod_matrix, &sources());
+        assert_eq!(modulation.cutoff, 1.0);
+        assert_eq!(modulation.pitch, 0.0);
+        assert!(!ModDestination::Attack.is_continuous());
+        assert!(ModDestination::Cutoff.is_continuous());
+    }
+
+    #[test]
+    fn oscillator_detune_leaves_the_first_oscillator_in_tune() {
+        let mut patch = with_matrix(vec![slot(
+            ModSource::Velocity,
+            ModDestination::OscillatorDetune,
+            7.0,
+        )]);
+        apply_to_patch(&mut patch, &sources());
+        assert_eq!(patch.oscillators[0].cents, 0.0);
+        assert_eq!(patch.oscillators[1].cents, 7.0);
+        assert_eq!(patch.oscillators[2].cents, 7.0);
+    }
+
+    #[test]
+    fn settings_are_kept_in_their_range() {
+        let mut patch = with_matrix(vec![
+            slot(ModSource::VoiceLfo, ModDestination::Release, 10.0),
+            slot(ModSource::Velocity, ModDestination::Sustain, 5.0),
+            slot(ModSource::VoiceLfo, ModDestination::Gain, 10.0),
+            slot(ModSource::Velocity, ModDestination::UnisonSpread, 5.0),
+            slot(ModSource::VoiceLfo, ModDestination::FmRatio1, 10.0),
+            slot(ModSource::VoiceLfo, ModDestination::FmLevel3, 10.0),
+        ]);
+        apply_to_patch(&mut patch, &sources());
+        assert_eq!(patch.envelope.release, 0.0);
+        assert_eq!(patch.envelope.sustain, 1.0);
+        assert_eq!(patch.gain, 0.0);
+        assert_eq!(patch.unison.spread, 1.0);
+        assert_eq!(patch.fm.operators[0].ratio, 0.0);
+        assert_eq!(patch.fm.operators[2].level, 0.0);
+
+        // The volume can't be turned down past silence
+        let mut modulation = Modulation::default();
+        modulation.add_matrix(
+            &[slot(ModSource::VoiceLfo, ModDestination::Amplitude, 5.0)],
+            &sources(),
+        );
+        assert_eq!(modulation.amplitude, 0.0);
+    }
+}
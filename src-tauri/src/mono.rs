@@ -0,0 +1,105 @@
+// This file is for the settings that make a patch monophonic, which is how most lead and bass sounds are played.
+// In mono modes a channel only plays one note at a time, and remembers which keys are held down
+// so that it can go back to one of them when the sounding key is released.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoiceMode {
+    Poly,   // Every note gets its own voice
+    Mono,   // One voice, which restarts its envelope for every note
+    Legato, // One voice, which only restarts its envelope when no other key is held
+}
+
+// Which of the held keys is played in a mono mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotePriority {
+    Last,
+    Low,
+    High,
+}
+
+// Picks the key to play out of the held keys, which are stored as (key, velocity) in the order they were pressed
+pub fn choose_key(held_keys: &[(u8, u8)], priority: NotePriority) -> Option<(u8, u8)> {
+    match priority {
+        NotePriority::Last => held_keys.last().copied(),
+        NotePriority::Low => held_keys.iter().min_by_key(|(key, _)| *key).copied(),
+        NotePriority::High => held_keys.iter().max_by_key(|(key, _)| *key).copied(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patch::Patch;
+    use crate::synth::Synth;
+
+    // Held in the order 64, 60, 67
+    const HELD_KEYS: [(u8, u8); 3] = [(64, 90), (60, 100), (67, 80)];
+
+    #[test]
+    fn last_note_priority_plays_the_newest_key() {
+        assert_eq!(choose_key(&HELD_KEYS, NotePriority::Last), Some((67, 80)));
+    }
+
+    #[test]
+    fn low_note_priority_plays_the_lowest_key() {
+        assert_eq!(choose_key(&HELD_KEYS, NotePriority::Low), Some((60, 100)));
+    }
+
+    #[test]
+    fn high_note_priority_plays_the_highest_key() {
+        assert_eq!(choose_key(&HELD_KEYS, NotePriority::High), Some((67, 80)));
+    }
+
+    #[test]
+    fn nothing_plays_without_held_keys() {
+        for priority in [NotePriority::Last, NotePriority::Low, NotePriority::High] {
+            assert_eq!(choose_key(&[], priority), None);
+        }
+    }
+
+    fn mono_synth(voice_mode: VoiceMode, note_priority: NotePriority) -> Synth {
+        let mut synth = Synth::new(48000);
+        synth.set_patch(
+            0,
+            Patch {
+                voice_mode,
+                note_priority,
+                ..Patch::default()
+            },
+        );
+        synth
+    }
+
+    #[test]
+    fn mono_restarts_the_voice_for_every_new_key() {
+        let mut synth = mono_synth(VoiceMode::Mono, NotePriority::Last);
+        synth.note_on(0, 60, 100);
+        // The old voice fades out while the new one starts
+        synth.note_on(0, 64, 100);
+        assert_eq!(synth.voice_count(), 2);
+        // Releasing the sounding key goes back to the one still held
+        synth.note_off(0, 64);
+        assert_eq!(synth.voice_count(), 3);
+    }
+
+    #[test]
+    fn legato_glides_one_voice_between_keys() {
+        let mut synth = mono_synth(VoiceMode::Legato, NotePriority::Last);
+        synth.note_on(0, 60, 100);
+        synth.note_on(0, 64, 100);
+        synth.note_off(0, 64);
+        assert_eq!(synth.voice_count(), 1);
+    }
+
+    #[test]
+    fn keys_that_are_not_sounding_do_not_move_the_voice() {
+        // With low note priority 60 keeps sounding, so pressing and releasing 64 changes nothing
+        let mut synth = mono_synth(VoiceMode::Mono, NotePriority::Low);
+        synth.note_on(0, 60, 100);
+        synth.note_on(0, 64, 100);
+        synth.note_off(0, 64);
+        assert_eq!(synth.voice_count(), 1);
+    }
+}
@@ -1,68 +1,319 @@
 // This file is for the Oscillator struct, which implements the rodio source trait.
 
 use rodio::source::Source;
+use serde::{Deserialize, Serialize};
 use std::f32::consts::PI;
+use std::sync::Arc;
 
-const SAMPLE_RATE: u32 = 48000; // The sample rate of the audio in Hz.
+use crate::wavetable::Wavetable;
+
+// The number of detuned sawtooths in a supersaw, and how far each one is tuned from the note in semitones
+const SUPERSAW_DETUNES: [f32; 7] = [-0.25, -0.16, -0.06, 0.0, 0.06, 0.16, 0.25];
+// Spreads out starting phases, like those of the supersaw's sawtooths, so that they don't all line up
+pub const GOLDEN_RATIO: f64 = 0.618_034;
+
+// Scale the pink and brown noise filters so that they are about as loud as white noise
+const PINK_GAIN: f32 = 0.33;
+const BROWN_GAIN: f32 = 8.0;
 
 // The wave type of the oscillator
-#[derive(Clone, Debug)]
-enum WaveType {
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum WaveType {
     Sine,
     Square,
     Sawtooth,
     Triangle,
+    Pulse,    // A square wave with a variable width
+    Supersaw, // Several detuned sawtooths at once
+    WhiteNoise,
+    PinkNoise, // Noise with less treble than white noise, which sounds more even to the ear
+    BrownNoise, // Noise with even less treble, which rumbles
+    Wavetable, // A wave loaded from a file, which is silent until a table is given to the oscillator
 }
 
 #[derive(Clone, Debug)]
 pub struct Oscillator {
-    freq: f32,
-    num_sample: usize, // The number of samples that have been played
+    glide: Glide, // The frequency, which can slide to another note
+    phase: f64, // How far through the current cycle the wave is, from 0 to 1. f64 so it stays precise however long it plays
     wave_type: WaveType,
+    sample_rate: u32,          // The sample rate of the audio in Hz.
+    band_limited: bool,        // Smooths out the jumps in the wave so that high notes don't alias
+    pitch_bend: f32, // Multiplies the frequency, so bends don't change the note being played
+    pulse_width: f32, // How much of each cycle the pulse wave is high for, from 0 to 1
+    supersaw_phases: Vec<f64>, // The phase of each of the supersaw's sawtooths, empty for other waves
+    noise: Noise,
+    wavetable: Option<Arc<Wavetable>>,
+    wavetable_position: f32, // Which frame of the wavetable plays, from 0 for the first to 1 for the last
 }
 
-// Allow dead code is used because main.rs doesn't use all of the wave types, just one of them
-// Without this, the compiler would complain about unused code.
 impl Oscillator {
-    #[allow(dead_code)]
-    pub fn sine_wave(freq: f32) -> Oscillator {
-        // Create a new sine wave oscillator
+    pub fn new(freq: f32, wave_type: WaveType, sample_rate: u32) -> Oscillator {
+        let supersaw_phases = match wave_type {
+            WaveType::Supersaw => (0..SUPERSAW_DETUNES.len())
+                .map(|i| (i as f64 * GOLDEN_RATIO).fract())
+                .collect(),
+            _ => Vec::new(),
+        };
         Oscillator {
-            freq,
-            num_sample: 0,
-            wave_type: WaveType::Sine,
+            glide: Glide::new(freq),
+            phase: 0.0,
+            wave_type,
+            sample_rate,
+            band_limited: true,
+            pitch_bend: 1.0,
+            pulse_width: 0.5,
+            supersaw_phases,
+            noise: Noise::default(),
+            wavetable: None,
+            wavetable_position: 0.0,
         }
     }
 
-    #[allow(dead_code)]
-    pub fn square_wave(freq: f32) -> Oscillator {
+    pub fn sine_wave(freq: f32, sample_rate: u32) -> Oscillator {
+        // Create a new sine wave oscillator
+        Oscillator::new(freq, WaveType::Sine, sample_rate)
+    }
+
+    pub fn square_wave(freq: f32, sample_rate: u32) -> Oscillator {
         // Create a new square wave oscillator
-        Oscillator {
-            freq,
-            num_sample: 0,
-            wave_type: WaveType::Square,
-        }
+        Oscillator::new(freq, WaveType::Square, sample_rate)
     }
 
-    #[allow(dead_code)]
-    pub fn sawtooth_wave(freq: f32) -> Oscillator {
+    pub fn sawtooth_wave(freq: f32, sample_rate: u32) -> Oscillator {
         // Create a new sawtooth wave oscillator
-        Oscillator {
-            freq,
-            num_sample: 0,
-            wave_type: WaveType::Sawtooth,
-        }
+        Oscillator::new(freq, WaveType::Sawtooth, sample_rate)
     }
 
-    #[allow(dead_code)]
-    pub fn triangle_wave(freq: f32) -> Oscillator {
+    pub fn triangle_wave(freq: f32, sample_rate: u32) -> Oscillator {
         // Create a new triangle wave oscillator
-        Oscillator {
-            freq,
-            num_sample: 0,
-            wave_type: WaveType::Triangle,
+        Oscillator::new(freq, WaveType::Triangle, sample_rate)
+    }
+
+    pub fn pulse_wave(freq: f32, pulse_width: f32, sample_rate: u32) -> Oscillator {
+        // Create a new pulse wave oscillator
+        let mut oscillator = Oscillator::new(freq, WaveType::Pulse, sample_rate);
+        oscillator.set_pulse_width(pulse_width);
+        oscillator
+    }
+
+    pub fn supersaw(freq: f32, sample_rate: u32) -> Oscillator {
+        // Create a new supersaw oscillator
+        Oscillator::new(freq, WaveType::Supersaw, sample_rate)
+    }
+
+    pub fn white_noise(sample_rate: u32) -> Oscillator {
+        // Create a new white noise oscillator, noise has no pitch so the frequency is only used for key tracking
+        Oscillator::new(0.0, WaveType::WhiteNoise, sample_rate)
+    }
+
+    pub fn pink_noise(sample_rate: u32) -> Oscillator {
+        // Create a new pink noise oscillator
+        Oscillator::new(0.0, WaveType::PinkNoise, sample_rate)
+    }
+
+    pub fn brown_noise(sample_rate: u32) -> Oscillator {
+        // Create a new brown noise oscillator
+        Oscillator::new(0.0, WaveType::BrownNoise, sample_rate)
+    }
+
+    pub fn wavetable(freq: f32, wavetable: Arc<Wavetable>, sample_rate: u32) -> Oscillator {
+        // Create a new oscillator which plays a wavetable
+        Oscillator::new(freq, WaveType::Wavetable, sample_rate).with_wavetable(wavetable)
+    }
+
+    // Turns off band limiting, which makes high notes alias but is useful for comparison
+    pub fn naive(mut self) -> Oscillator {
+        self.band_limited = false;
+        self
+    }
+
+    // Starts the wave part of the way through its cycle, the phase is from 0 to 1
+    pub fn with_phase(mut self, phase: f32) -> Oscillator {
+        let offset = phase.rem_euclid(1.0) as f64 - self.phase;
+        self.phase += offset;
+        for supersaw_phase in self.supersaw_phases.iter_mut() {
+            *supersaw_phase = (*supersaw_phase + offset).rem_euclid(1.0);
+        }
+        self
+    }
+
+    // Gives the oscillator a wavetable to play, which is only used when the wave type is Wavetable
+    pub fn with_wavetable(mut self, wavetable: Arc<Wavetable>) -> Oscillator {
+        self.wavetable = Some(wavetable);
+        self
+    }
+
+    // Starts the noise waves from a different point, so that several noise oscillators don't play the same noise
+    pub fn with_seed(mut self, seed: u32) -> Oscillator {
+        self.noise = Noise::new(seed);
+        self
+    }
+
+    // The frequency currently being played, not including pitch bend
+    pub fn frequency(&self) -> f32 {
+        self.glide.frequency()
+    }
+
+    // Changes the frequency straight away. The phase carries on from where it was, so there is no click
+    pub fn set_frequency(&mut self, freq: f32) {
+        self.glide.set_frequency(freq);
+    }
+
+    // Slides to a new frequency over the given number of seconds
+    pub fn glide_to(&mut self, freq: f32, seconds: f32) {
+        self.glide
+            .glide_to(freq, (seconds * self.sample_rate as f32) as u32);
+    }
+
+    // Bends the pitch up or down by a number of semitones
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = 2.0_f32.powf(semitones / 12.0);
+    }
+
+    // Changes the width of the pulse wave, moving this over time is known as pulse width modulation
+    pub fn set_pulse_width(&mut self, pulse_width: f32) {
+        self.pulse_width = pulse_width.clamp(0.01, 0.99);
+    }
+
+    // Morphs through the frames of the wavetable, from 0 for the first frame to 1 for the last
+    pub fn set_wavetable_position(&mut self, position: f32) {
+        self.wavetable_position = position.clamp(0.0, 1.0);
+    }
+
+    // Returns the next sample, read from further along the cycle by the offset.
+    // Moving the offset with another oscillator's output is phase modulation, which is how FM synths make their sound.
+    pub fn next_with_phase_offset(&mut self, offset: f32) -> f32 {
+        let phase = if offset == 0.0 {
+            self.phase as f32
+        } else {
+            (self.phase as f32 + offset).rem_euclid(1.0)
+        };
+        let phase_increment =
+            (self.glide.frequency() * self.pitch_bend) as f64 / self.sample_rate as f64;
+
+        let value = 2.0 * PI * phase;
+        let sample = match self.wave_type {
+            WaveType::Supersaw => self.next_supersaw(phase_increment),
+            WaveType::WhiteNoise => self.noise.white(),
+            WaveType::PinkNoise => self.noise.pink(),
+            WaveType::BrownNoise => self.noise.brown(),
+            // Wavetables are band limited by the table itself, so they sound the same when band limiting is off
+            WaveType::Wavetable => match self.wavetable.as_ref() {
+                Some(wavetable) => {
+                    wavetable.sample(self.wavetable_position, phase, phase_increment as f32)
+                }
+                None => 0.0,
+            },
+            _ if self.band_limited => band_limited_wave(
+                &self.wave_type,
+                phase,
+                phase_increment as f32,
+                self.pulse_width,
+            ),
+            WaveType::Sine => value.sin(),            // Sine wave
+            WaveType::Square => value.sin().signum(), // Signing the sine wave locks it to 1 or -1, making it a square wave.
+            WaveType::Sawtooth => 2.0 * phase - 1.0, // The phase rises from 0 to 1 every cycle, just like a sawtooth.
+            WaveType::Triangle => value.sin().asin(), // The arcsine of the sine wave makes it a triangle wave.
+            // High for the first part of the cycle, and low for the rest
+            WaveType::Pulse => {
+                if phase < self.pulse_width {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        };
+
+        // Move the phase along, wrapping it back to 0 at the end of each cycle
+        self.phase = (self.phase + phase_increment).fract();
+        self.glide.next_sample();
+
+        sample
+    }
+
+    // Sums the supersaw's sawtooths, moving each of them along by its own phase increment
+    fn next_supersaw(&mut self, phase_increment: f64) -> f32 {
+        let mut sample = 0.0;
+        for (phase, detune) in self.supersaw_phases.iter_mut().zip(SUPERSAW_DETUNES.iter()) {
+            let increment = phase_increment * 2.0_f64.powf(*detune as f64 / 12.0);
+            let saw_phase = *phase as f32;
+            sample += 2.0 * saw_phase - 1.0;
+            if self.band_limited {
+                sample -= poly_blep(saw_phase, increment as f32);
+            }
+            *phase = (*phase + increment).fract();
+        }
+        // The sawtooths aren't in phase, so they add up to about the square root of how many there are
+        sample / (SUPERSAW_DETUNES.len() as f32).sqrt()
+    }
+}
+
+// Random numbers for noise and sample and hold, using xorshift because the values only need to sound random
+#[derive(Clone, Debug)]
+pub struct Random {
+    state: u32,
+}
+
+impl Random {
+    // Generators with different seeds give different numbers
+    pub fn new(seed: u32) -> Random {
+        Random {
+            // Xorshift gets stuck at 0, so the seed is mixed into a constant that keeps it away from there
+            state: (0x9E37_79B9 ^ seed.wrapping_mul(0x85EB_CA6B)).max(1),
         }
     }
+
+    // A random number from -1 to 1
+    pub fn next_value(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+// The state of the noise waves, which have no pitch so they don't use the phase
+#[derive(Clone, Debug)]
+struct Noise {
+    random: Random,
+    pink: [f32; 3], // The three low pass filters which are summed to make pink noise
+    brown: f32,     // The running total of white noise which makes brown noise
+}
+
+impl Default for Noise {
+    fn default() -> Noise {
+        Noise::new(0)
+    }
+}
+
+impl Noise {
+    fn new(seed: u32) -> Noise {
+        Noise {
+            random: Random::new(seed),
+            pink: [0.0; 3],
+            brown: 0.0,
+        }
+    }
+
+    fn white(&mut self) -> f32 {
+        self.random.next_value()
+    }
+
+    // Paul Kellet's economy pink noise filter, which is within a decibel of pink above 40 Hz
+    fn pink(&mut self) -> f32 {
+        let white = self.white();
+        self.pink[0] = 0.99765 * self.pink[0] + white * 0.099046;
+        self.pink[1] = 0.963 * self.pink[1] + white * 0.2965164;
+        self.pink[2] = 0.57 * self.pink[2] + white * 1.0526913;
+        (self.pink[0] + self.pink[1] + self.pink[2] + white * 0.1848) * PINK_GAIN
+    }
+
+    // Leaks a little each sample, so that it wanders around 0 instead of drifting away
+    fn brown(&mut self) -> f32 {
+        let white = self.white();
+        self.brown = (self.brown + 0.02 * white) / 1.02;
+        self.brown * BROWN_GAIN
+    }
 }
 
 // Rodio requires that Iterator is implemented
@@ -71,16 +322,124 @@ impl Iterator for Oscillator {
     type Item = f32;
 
     fn next(&mut self) -> Option<f32> {
-        self.num_sample = self.num_sample.wrapping_add(1);
+        Some(self.next_with_phase_offset(0.0))
+    }
+}
+
+// Uses PolyBLEP and PolyBLAMP to round off the jumps and corners of the naive waves.
+// A jump in a naive wave contains harmonics above the Nyquist frequency, which fold back down as inharmonic noise.
+fn band_limited_wave(
+    wave_type: &WaveType,
+    phase: f32,
+    phase_increment: f32,
+    pulse_width: f32,
+) -> f32 {
+    match wave_type {
+        WaveType::Sine => (2.0 * PI * phase).sin(), // Sine waves have no harmonics to alias
+        WaveType::Square => {
+            let naive = if phase < 0.5 { 1.0 } else { -1.0 };
+            naive + poly_blep(phase, phase_increment)
+                - poly_blep((phase + 0.5).fract(), phase_increment)
+        }
+        WaveType::Sawtooth => 2.0 * phase - 1.0 - poly_blep(phase, phase_increment),
+        WaveType::Triangle => {
+            // Matches the phase of the naive triangle, which peaks a quarter of the way through the cycle
+            let naive = 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs();
+            // The slope changes by 8 * phase_increment at each corner, and poly_blamp is scaled for a change of 2
+            naive - 4.0 * phase_increment * poly_blamp((phase + 0.75).fract(), phase_increment)
+                + 4.0 * phase_increment * poly_blamp((phase + 0.25).fract(), phase_increment)
+        }
+        WaveType::Pulse => {
+            // Like the square wave, but it jumps down at the pulse width instead of half way through the cycle
+            let naive = if phase < pulse_width { 1.0 } else { -1.0 };
+            naive + poly_blep(phase, phase_increment)
+                - poly_blep((phase + 1.0 - pulse_width).fract(), phase_increment)
+        }
+        // These waves aren't made from the phase, so they are handled before band limiting
+        WaveType::Supersaw
+        | WaveType::WhiteNoise
+        | WaveType::PinkNoise
+        | WaveType::BrownNoise
+        | WaveType::Wavetable => 0.0,
+    }
+}
+
+// The correction for a jump of 2 in a wave, spread over the samples either side of it
+fn poly_blep(phase: f32, phase_increment: f32) -> f32 {
+    if phase < phase_increment {
+        let t = phase / phase_increment;
+        t + t - t * t - 1.0
+    } else if phase > 1.0 - phase_increment {
+        let t = (phase - 1.0) / phase_increment;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+// The correction for a sharp corner in a wave, the integral of poly_blep
+fn poly_blamp(phase: f32, phase_increment: f32) -> f32 {
+    if phase < phase_increment {
+        let t = phase / phase_increment - 1.0;
+        -t * t * t / 3.0
+    } else if phase > 1.0 - phase_increment {
+        let t = (phase - 1.0) / phase_increment + 1.0;
+        t * t * t / 3.0
+    } else {
+        0.0
+    }
+}
 
-        let t = self.num_sample as f32 / SAMPLE_RATE as f32; // Time
-        let value = 2.0 * PI * self.freq * t;
+// A frequency which can slide to another one over a number of samples, used for portamento by the oscillators and the sampler.
+// The frequency changes by the same number of semitones every sample, which sounds like an even slide.
+#[derive(Clone, Debug)]
+pub struct Glide {
+    freq: f32,
+    target: f32,  // The frequency being glided towards
+    ratio: f32,   // Multiplies the frequency every sample while gliding
+    samples: u32, // The number of samples left in the glide
+}
+
+impl Glide {
+    pub fn new(freq: f32) -> Glide {
+        Glide {
+            freq,
+            target: freq,
+            ratio: 1.0,
+            samples: 0,
+        }
+    }
+
+    pub fn frequency(&self) -> f32 {
+        self.freq
+    }
+
+    // Jumps to a frequency straight away, ending any glide
+    pub fn set_frequency(&mut self, freq: f32) {
+        self.freq = freq;
+        self.target = freq;
+        self.samples = 0;
+    }
+
+    pub fn glide_to(&mut self, freq: f32, samples: u32) {
+        if samples == 0 || self.freq <= 0.0 || freq <= 0.0 {
+            self.set_frequency(freq);
+            return;
+        }
+        self.target = freq;
+        self.ratio = (freq / self.freq).powf(1.0 / samples as f32);
+        self.samples = samples;
+    }
 
-        match self.wave_type {
-            WaveType::Sine => Some(value.sin()),            // Sine wave
-            WaveType::Square => Some(value.sin().signum()), // Signing the sine wave locks it to 1 or -1, making it a square wave.
-            WaveType::Sawtooth => Some(2.0 * (t % (1.0 / self.freq)) * self.freq - 1.0), // Sawtooth wave using modulo.
-            WaveType::Triangle => Some(value.sin().asin()), // The arcsine of the sine wave makes it a triangle wave.
+    // Moves the glide on by one sample
+    pub fn next_sample(&mut self) {
+        if self.samples > 0 {
+            self.samples -= 1;
+            self.freq = if self.samples == 0 {
+                self.target // Land exactly on the target, rather than wherever rounding leaves it
+            } else {
+                self.freq * self.ratio
+            };
         }
     }
 }
@@ -95,10 +454,98 @@ impl Source for Oscillator {
     }
 
     fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+        self.sample_rate
     }
 
     fn total_duration(&self) -> Option<std::time::Duration> {
         None // Will continue indefinitely until stopped
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const NUM_SAMPLES: usize = 4800;
+    // A high note which repeats exactly 249 times in NUM_SAMPLES, so each harmonic lands on its own DFT bin
+    const FREQ: f32 = 2490.0;
+
+    // Returns the fraction of the wave's energy that is not at a harmonic of the note
+    fn aliased_energy(oscillator: Oscillator) -> f32 {
+        let samples: Vec<f32> = oscillator.take(NUM_SAMPLES).collect();
+        let bin_spacing = SAMPLE_RATE as f32 / NUM_SAMPLES as f32;
+        let fundamental_bin = (FREQ / bin_spacing) as usize;
+
+        let mut harmonic_energy = 0.0;
+        let mut aliased_energy = 0.0;
+        for bin in 1..NUM_SAMPLES / 2 {
+            let (mut re, mut im) = (0.0_f64, 0.0_f64);
+            for (n, sample) in samples.iter().enumerate() {
+                let angle = 2.0 * std::f64::consts::PI * (bin * n % NUM_SAMPLES) as f64
+                    / NUM_SAMPLES as f64;
+                re += *sample as f64 * angle.cos();
+                im -= *sample as f64 * angle.sin();
+            }
+            let energy = re * re + im * im;
+            if bin % fundamental_bin == 0 {
+                harmonic_energy += energy;
+            } else {
+                aliased_energy += energy;
+            }
+        }
+        (aliased_energy / (harmonic_energy + aliased_energy)) as f32
+    }
+
+    #[test]
+    fn band_limited_waves_alias_less_than_naive_waves() {
+        let oscillators = [
+            Oscillator::square_wave(FREQ, SAMPLE_RATE),
+            Oscillator::sawtooth_wave(FREQ, SAMPLE_RATE),
+            Oscillator::triangle_wave(FREQ, SAMPLE_RATE),
+            Oscillator::pulse_wave(FREQ, 0.25, SAMPLE_RATE),
+        ];
+        for oscillator in oscillators {
+            let naive = aliased_energy(oscillator.clone().naive());
+            let band_limited = aliased_energy(oscillator.clone());
+            // At least 10 dB less aliasing
+            assert!(band_limited < naive / 10.0);
+        }
+    }
+
+    #[test]
+    fn glides_move_evenly_and_land_on_the_target() {
+        let mut glide = Glide::new(220.0);
+        glide.glide_to(440.0, 4);
+        let mut frequencies = Vec::new();
+        for _ in 0..6 {
+            glide.next_sample();
+            frequencies.push(glide.frequency());
+        }
+        // A quarter of an octave every sample
+        let step = 2.0_f32.powf(0.25);
+        for (i, frequency) in frequencies.iter().take(3).enumerate() {
+            assert!((frequency - 220.0 * step.powi(i as i32 + 1)).abs() < 0.01);
+        }
+        assert_eq!(&frequencies[3..], &[440.0; 3]);
+    }
+
+    #[test]
+    fn no_seed_leaves_the_random_numbers_stuck() {
+        // The seed that the constant would be mixed back to 0 by, found with the inverse of the multiplier
+        let mut inverse: u32 = 0x85EB_CA6B;
+        for _ in 0..5 {
+            inverse =
+                inverse.wrapping_mul(2u32.wrapping_sub(0x85EB_CA6B_u32.wrapping_mul(inverse)));
+        }
+        for seed in [0, 1, 0x9E37_79B9_u32.wrapping_mul(inverse)] {
+            let mut random = Random::new(seed);
+            let values: Vec<f32> = (0..10).map(|_| random.next_value()).collect();
+            assert!(
+                values.windows(2).any(|values| values[0] != values[1]),
+                "{}",
+                seed
+            );
+        }
+    }
+}
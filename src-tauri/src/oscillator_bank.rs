@@ -0,0 +1,363 @@
+// This file is for the OscillatorBank, which mixes together the oscillators that make up a voice.
+// A patch has up to three oscillators, each with its own wave and tuning. Unison plays several copies of every
+// oscillator, detuned from each other and spread across the stereo field, which makes the sound thicker.
+
+use std::time::Duration;
+
+use rodio::source::Source;
+use serde::{Deserialize, Serialize};
+
+use crate::oscillator::{Oscillator, WaveType, GOLDEN_RATIO};
+use crate::wavetable::Wavetables;
+
+pub const NUM_OSCILLATORS: usize = 3;
+pub const MAX_UNISON: u32 = 16;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OscillatorSettings {
+    pub wave_type: WaveType,
+    pub octave: i32,
+    pub semitone: i32,
+    pub cents: f32,
+    pub level: f32,                // 0 turns the oscillator off
+    pub pulse_width: f32,          // Only used by the pulse wave, from 0 to 1
+    pub wavetable: Option<String>, // The path of the WAV file the wavetable wave plays
+    pub wavetable_position: f32, // Which frame of the wavetable plays, from 0 for the first to 1 for the last
+}
+
+impl Default for OscillatorSettings {
+    fn default() -> OscillatorSettings {
+        OscillatorSettings {
+            wave_type: WaveType::Sawtooth,
+            octave: 0,
+            semitone: 0,
+            cents: 0.0,
+            level: 1.0,
+            pulse_width: 0.5,
+            wavetable: None,
+            wavetable_position: 0.0,
+        }
+    }
+}
+
+impl OscillatorSettings {
+    pub fn new(wave_type: WaveType) -> OscillatorSettings {
+        OscillatorSettings {
+            wave_type,
+            ..OscillatorSettings::default()
+        }
+    }
+
+    pub fn off() -> OscillatorSettings {
+        OscillatorSettings {
+            level: 0.0,
+            ..OscillatorSettings::default()
+        }
+    }
+
+    // How far the oscillator is tuned from the note, in semitones
+    pub fn offset(&self) -> f32 {
+        self.octave as f32 * 12.0 + self.semitone as f32 + self.cents / 100.0
+    }
+}
+
+// The oscillators for a patch that only uses one of them
+pub fn single_oscillator(wave_type: WaveType) -> [OscillatorSettings; NUM_OSCILLATORS] {
+    [
+        OscillatorSettings::new(wave_type),
+        OscillatorSettings::off(),
+        OscillatorSettings::off(),
+    ]
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UnisonSettings {
+    pub voices: u32, // How many copies of each oscillator play, 1 turns unison off
+    pub detune: f32, // How far apart the highest and lowest copies are, in cents
+    pub spread: f32, // From 0 for every copy in the centre, to 1 for the outermost copies all the way left and right
+}
+
+impl Default for UnisonSettings {
+    fn default() -> UnisonSettings {
+        UnisonSettings {
+            voices: 1,
+            detune: 20.0,
+            spread: 1.0,
+        }
+    }
+}
+
+// One copy of one of the patch's oscillators
+#[derive(Clone, Debug)]
+struct BankOscillator {
+    oscillator: Oscillator,
+    ratio: f32, // Multiplies the note's frequency, for the oscillator's tuning and unison detune
+    pulse_width: f32,
+    wavetable_position: f32,
+    left_gain: f32,
+    right_gain: f32,
+}
+
+// An audio source which plays every oscillator of a voice.
+// It is stereo when unison is spread out, and mono otherwise so that simple patches don't do any extra work.
+#[derive(Clone, Debug)]
+pub struct OscillatorBank {
+    oscillators: Vec<BankOscillator>,
+    freq: f32,
+    stereo: bool,
+    right: Option<f32>, // The right sample of the current frame, which is returned after the left one
+    sample_rate: u32,
+}
+
+impl OscillatorBank {
+    // The seed picks the noise the oscillators play, so that banks started with different seeds don't play the same noise
+    pub fn new(
+        freq: f32,
+        settings: &[OscillatorSettings],
+        unison: &UnisonSettings,
+        wavetables: &Wavetables,
+        seed: u32,
+        sample_rate: u32,
+    ) -> OscillatorBank {
+        let voices = unison.voices.clamp(1, MAX_UNISON);
+        let spread = unison.spread.clamp(0.0, 1.0);
+        let stereo = voices > 1 && spread > 0.0;
+        // Copies that aren't in phase add up quieter than copies that are, so this keeps the volume about the same
+        let unison_gain = 1.0 / (voices as f32).sqrt();
+
+        let mut oscillators = Vec::new();
+        for settings in settings.iter().filter(|settings| settings.level != 0.0) {
+            let wavetable = settings
+                .wavetable
+                .as_ref()
+                .and_then(|path| wavetables.get(path));
+            for i in 0..voices {
+                // From -1 for the first copy to 1 for the last
+                let position = if voices > 1 {
+                    2.0 * i as f32 / (voices - 1) as f32 - 1.0
+                } else {
+                    0.0
+                };
+                let semitones = settings.offset() + position * unison.detune / 200.0;
+                let ratio = 2.0_f32.powf(semitones / 12.0);
+                // The copies start at different phases, so that they don't all line up at the start of the note
+                let mut oscillator =
+                    Oscillator::new(freq * ratio, settings.wave_type.clone(), sample_rate)
+                        .with_phase((i as f32 * GOLDEN_RATIO as f32).fract())
+                        .with_seed(
+                            seed.wrapping_mul(NUM_OSCILLATORS as u32 * MAX_UNISON)
+                                .wrapping_add(oscillators.len() as u32),
+                        );
+                oscillator.set_pulse_width(settings.pulse_width);
+                oscillator.set_wavetable_position(settings.wavetable_position);
+                if let Some(wavetable) = wavetable.as_ref() {
+                    oscillator = oscillator.with_wavetable(wavetable.clone());
+                }
+                // Equal power panning, which leaves copies in the centre at full volume on both sides
+                let pan = position * spread;
+                let gain = settings.level * unison_gain;
+                let (left_gain, right_gain) = if stereo {
+                    (gain * (1.0 - pan).sqrt(), gain * (1.0 + pan).sqrt())
+                } else {
+                    (gain, gain)
+                };
+                oscillators.push(BankOscillator {
+                    oscillator,
+                    ratio,
+                    pulse_width: settings.pulse_width,
+                    wavetable_position: settings.wavetable_position,
+                    left_gain,
+                    right_gain,
+                });
+            }
+        }
+
+        OscillatorBank {
+            oscillators,
+            freq,
+            stereo,
+            right: None,
+            sample_rate,
+        }
+    }
+
+    // The frequency of the note, before each oscillator's tuning
+    pub fn frequency(&self) -> f32 {
+        self.freq
+    }
+
+    pub fn set_frequency(&mut self, freq: f32) {
+        self.freq = freq;
+        for bank_oscillator in self.oscillators.iter_mut() {
+            let ratio = bank_oscillator.ratio;
+            bank_oscillator.oscillator.set_frequency(freq * ratio);
+        }
+    }
+
+    // Slides every oscillator to a new note, keeping them tuned the same distance apart
+    pub fn glide_to(&mut self, freq: f32, seconds: f32) {
+        self.freq = freq;
+        for bank_oscillator in self.oscillators.iter_mut() {
+            let ratio = bank_oscillator.ratio;
+            bank_oscillator.oscillator.glide_to(freq * ratio, seconds);
+        }
+    }
+
+    // Bends the pitch up or down by a number of semitones
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        for bank_oscillator in self.oscillators.iter_mut() {
+            bank_oscillator.oscillator.set_pitch_bend(semitones);
+        }
+    }
+
+    // Moves the width of every pulse wave, and the position of every wavetable, away from where the patch sets them
+    pub fn set_shape_modulation(&mut self, pulse_width: f32, wavetable_position: f32) {
+        for bank_oscillator in self.oscillators.iter_mut() {
+            let oscillator = &mut bank_oscillator.oscillator;
+            oscillator.set_pulse_width(bank_oscillator.pulse_width + pulse_width);
+            oscillator
+                .set_wavetable_position(bank_oscillator.wavetable_position + wavetable_position);
+        }
+    }
+}
+
+impl Iterator for OscillatorBank {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for bank_oscillator in self.oscillators.iter_mut() {
+            let sample = bank_oscillator.oscillator.next().unwrap_or(0.0);
+            left += sample * bank_oscillator.left_gain;
+            right += sample * bank_oscillator.right_gain;
+        }
+        if self.stereo {
+            self.right = Some(right);
+        }
+        Some(left)
+    }
+}
+
+impl Source for OscillatorBank {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        if self.stereo {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None // Will continue indefinitely until stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+
+    fn bank(settings: &[OscillatorSettings], unison: &UnisonSettings, seed: u32) -> OscillatorBank {
+        OscillatorBank::new(
+            440.0,
+            settings,
+            unison,
+            &Wavetables::default(),
+            seed,
+            SAMPLE_RATE,
+        )
+    }
+
+    fn unison(voices: u32, detune: f32, spread: f32) -> UnisonSettings {
+        UnisonSettings {
+            voices,
+            detune,
+            spread,
+        }
+    }
+
+    #[test]
+    fn every_oscillator_that_is_on_gets_a_copy_for_each_unison_voice() {
+        let mut settings = single_oscillator(WaveType::Sawtooth);
+        settings[2] = OscillatorSettings::new(WaveType::Square);
+        assert_eq!(
+            bank(&settings, &unison(1, 20.0, 1.0), 0).oscillators.len(),
+            2
+        );
+        assert_eq!(
+            bank(&settings, &unison(5, 20.0, 1.0), 0).oscillators.len(),
+            10
+        );
+        // The number of copies is limited
+        let copies = bank(&settings, &unison(100, 20.0, 1.0), 0)
+            .oscillators
+            .len();
+        assert_eq!(copies, 2 * MAX_UNISON as usize);
+    }
+
+    #[test]
+    fn unison_copies_are_detuned_evenly_either_side_of_the_note() {
+        let settings = single_oscillator(WaveType::Sawtooth);
+        let ratios: Vec<f32> = bank(&settings, &unison(5, 20.0, 1.0), 0)
+            .oscillators
+            .iter()
+            .map(|oscillator| oscillator.ratio)
+            .collect();
+        for (low, high) in ratios.iter().zip(ratios.iter().rev()) {
+            assert!((low * high - 1.0).abs() < 1e-5, "{:?}", ratios);
+        }
+        // The outermost copies are the detune apart, in cents
+        let cents = 1200.0 * (ratios[4] / ratios[0]).log2();
+        assert!((cents - 20.0).abs() < 1e-3, "{:?}", ratios);
+        assert_eq!(ratios[2], 1.0);
+    }
+
+    #[test]
+    fn unison_copies_are_spread_across_the_stereo_field() {
+        let settings = single_oscillator(WaveType::Sawtooth);
+        let spread = bank(&settings, &unison(3, 20.0, 1.0), 0);
+        assert_eq!(spread.channels(), 2);
+        let gains: Vec<(f32, f32)> = spread
+            .oscillators
+            .iter()
+            .map(|oscillator| (oscillator.left_gain, oscillator.right_gain))
+            .collect();
+        // The first copy is all the way left, the last all the way right, and the middle one in the centre
+        assert_eq!(gains[0].1, 0.0);
+        assert_eq!(gains[2].0, 0.0);
+        assert_eq!(gains[1].0, gains[1].1);
+        assert_eq!(gains[0].0, gains[2].1);
+
+        // Without any spread, or without unison, the bank is mono
+        assert_eq!(bank(&settings, &unison(3, 20.0, 0.0), 0).channels(), 1);
+        assert_eq!(bank(&settings, &unison(1, 20.0, 1.0), 0).channels(), 1);
+    }
+
+    #[test]
+    fn noise_is_different_for_every_seed() {
+        let settings = single_oscillator(WaveType::WhiteNoise);
+        let noise = |seed| -> Vec<f32> {
+            bank(&settings, &unison(1, 0.0, 0.0), seed)
+                .take(100)
+                .collect()
+        };
+        assert_eq!(noise(1), noise(1));
+        assert_ne!(noise(1), noise(2));
+    }
+}
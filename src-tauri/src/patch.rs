@@ -0,0 +1,81 @@
+// This file is for the Patch struct, which holds the settings that decide how the synth sounds.
+// Patches are serializable so that the frontend can edit them.
+
+use serde::{Deserialize, Serialize};
+
+use crate::envelope::Envelope;
+use crate::filter::FilterSettings;
+use crate::fm::FmSettings;
+use crate::lfo::LfoSettings;
+use crate::mod_matrix::ModSlot;
+use crate::mono::{NotePriority, VoiceMode};
+use crate::oscillator::WaveType;
+use crate::oscillator_bank::{
+    single_oscillator, OscillatorSettings, UnisonSettings, NUM_OSCILLATORS,
+};
+use crate::sampler::SamplerSettings;
+
+// How a patch makes its sound, before the filter and envelope
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PatchType {
+    Subtractive, // Mixes the oscillators
+    Fm,          // Plays the FM operators
+    Sampler,     // Plays the sample zone that the note falls in
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)] // Any settings missing from the JSON use the default patch's values
+pub struct Patch {
+    pub patch_type: PatchType,
+    pub oscillators: [OscillatorSettings; NUM_OSCILLATORS], // Mixed together, oscillators with no level are skipped
+    pub unison: UnisonSettings,
+    pub fm: FmSettings,           // Only used by FM patches
+    pub sampler: SamplerSettings, // Only used by sampler patches
+    pub envelope: Envelope,
+    pub gain: f32, // Multiplies the volume of every note, on top of the note's velocity
+    pub filter: FilterSettings,
+    pub voice_lfo: LfoSettings,  // Starts again with every note
+    pub global_lfo: LfoSettings, // Runs freely, and is shared by every note on the channel
+    pub mod_matrix: Vec<ModSlot>,
+    pub pitch_bend_range: f32, // How far the pitch bend wheel bends notes, in semitones
+    pub vibrato_rate: f32,     // In Hz
+    pub vibrato_depth: f32, // How far the vibrato bends notes when the mod wheel is all the way up, in semitones
+    pub fixed_key: Option<u8>, // Plays every note at this key instead of the one pressed, used for drums
+    pub voice_mode: VoiceMode,
+    pub note_priority: NotePriority, // Which held key plays in the mono modes
+    pub portamento: f32, // How long notes take to glide to the next note in the mono modes, in seconds
+}
+
+impl Default for Patch {
+    fn default() -> Patch {
+        Patch {
+            patch_type: PatchType::Subtractive,
+            oscillators: single_oscillator(WaveType::Sawtooth),
+            unison: UnisonSettings::default(),
+            fm: FmSettings::default(),
+            sampler: SamplerSettings::default(),
+            envelope: Envelope::new(0.0, 2.0, 0.0, 0.0),
+            gain: 1.0,
+            filter: FilterSettings::default(),
+            voice_lfo: LfoSettings::default(),
+            global_lfo: LfoSettings::default(),
+            mod_matrix: Vec::new(),
+            pitch_bend_range: 2.0,
+            vibrato_rate: 5.0,
+            vibrato_depth: 0.5,
+            fixed_key: None,
+            voice_mode: VoiceMode::Poly,
+            note_priority: NotePriority::Last,
+            portamento: 0.0,
+        }
+    }
+}
+
+impl Patch {
+    // Whether notes keep sounding for as long as their key is held, instead of dying away by themselves
+    pub fn sustains(&self) -> bool {
+        self.envelope.sustain > 0.0
+            || (self.patch_type == PatchType::Sampler
+                && self.sampler.zones.iter().any(|zone| zone.looping))
+    }
+}
@@ -0,0 +1,104 @@
+// This file is for loading the files that patches use, the wavetables, the samples and the SoundFonts.
+// Reading and decoding files is slow, so it is done without the synth locked, which would stop the audio.
+// The synth lists the files it is missing, they are loaded here, and then they are added to the synth in one go.
+
+use std::path::Path;
+
+use crate::sampler::{load_sample, Sample};
+use crate::soundfont::load_soundfont;
+use crate::wavetable::{load_wavetable, Wavetable};
+
+// The files that patches use which the synth hasn't loaded yet, from Synth::missing_files
+#[derive(Debug, Default)]
+pub struct MissingFiles {
+    pub wavetables: Vec<String>,
+    pub samples: Vec<String>,
+    pub soundfonts: Vec<String>,
+}
+
+impl MissingFiles {
+    pub fn is_empty(&self) -> bool {
+        self.wavetables.is_empty() && self.samples.is_empty() && self.soundfonts.is_empty()
+    }
+
+    // Adds a wavetable to load, if it isn't in the list already
+    pub fn add_wavetable(&mut self, path: String) {
+        if !self.wavetables.contains(&path) {
+            self.wavetables.push(path);
+        }
+    }
+
+    // Adds an audio file to load for the sampler, if it isn't in the list already
+    pub fn add_sample(&mut self, path: String) {
+        if !self.samples.contains(&path) {
+            self.samples.push(path);
+        }
+    }
+
+    // Adds a SoundFont to load, if it isn't in the list already. Each one is only parsed once, however many zones use it.
+    pub fn add_soundfont(&mut self, path: String) {
+        if !self.soundfonts.contains(&path) {
+            self.soundfonts.push(path);
+        }
+    }
+
+    // Reads and decodes every file, files that can't be loaded are left out of the result's values
+    pub fn load(self) -> LoadedFiles {
+        let wavetables = self
+            .wavetables
+            .into_iter()
+            .map(|path| {
+                let wavetable = match load_wavetable(Path::new(&path)) {
+                    Ok(wavetable) => Some(wavetable),
+                    Err(e) => {
+                        println!("Error loading wavetable {}: {}", path, e);
+                        None
+                    }
+                };
+                (path, wavetable)
+            })
+            .collect();
+        let samples = self
+            .samples
+            .into_iter()
+            .map(|path| {
+                let sample = match load_sample(Path::new(&path)) {
+                    Ok(sample) => Some(sample),
+                    Err(e) => {
+                        println!("Error loading sample {}: {}", path, e);
+                        None
+                    }
+                };
+                (path, sample)
+            })
+            .collect();
+        let soundfonts = self
+            .soundfonts
+            .into_iter()
+            .map(|path| {
+                let samples = match load_soundfont(Path::new(&path)) {
+                    Ok(soundfont) => Some(soundfont.samples),
+                    Err(e) => {
+                        println!("Error loading SoundFont {}: {}", path, e);
+                        None
+                    }
+                };
+                (path, samples)
+            })
+            .collect();
+        LoadedFiles {
+            wavetables,
+            samples,
+            soundfonts,
+        }
+    }
+}
+
+// Files that have been loaded, ready to add to the synth with Synth::add_files.
+// Files that couldn't be loaded are None, so that the synth doesn't try them again.
+#[derive(Default)]
+pub struct LoadedFiles {
+    pub wavetables: Vec<(String, Option<Wavetable>)>,
+    pub samples: Vec<(String, Option<Sample>)>,
+    pub soundfonts: Vec<(String, Option<Vec<Sample>>)>,
+}
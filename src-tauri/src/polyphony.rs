@@ -0,0 +1,205 @@
+// This file is for limiting how many voices the synth plays at once.
+// When a new note would go over the limit, a voice is chosen to be stolen, and it fades out quickly to make room.
+
+use serde::{Deserialize, Serialize};
+
+use crate::voice::Voice;
+
+pub const MAX_POLYPHONY: usize = 256; // The highest limit the user can choose
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StealPolicy {
+    Oldest,                // The voice that started first
+    Quietest,              // The voice with the lowest volume right now
+    SameNote, // A voice playing the same note on the same channel, or the oldest if there isn't one
+    LowestPriorityChannel, // The oldest voice on the channel with the lowest priority
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PolyphonySettings {
+    pub max_voices: usize,
+    pub steal_policy: StealPolicy,
+}
+
+impl Default for PolyphonySettings {
+    fn default() -> PolyphonySettings {
+        PolyphonySettings {
+            max_voices: 64,
+            steal_policy: StealPolicy::Oldest,
+        }
+    }
+}
+
+// Counters shown in the UI
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VoiceStats {
+    pub active_voices: usize, // Including voices that are releasing or being stolen
+    pub peak_voices: usize,   // The most voices that have played at once
+    pub stolen_voices: u64,   // How many voices have been stolen since the synth started
+}
+
+// Picks the voice to steal for a new note, out of the voices that aren't already being stolen.
+// Voices that have been released are stolen before ones that are still held, whatever the policy.
+// The channel priorities are indexed by channel, a lower number is stolen from first.
+pub fn choose_voice_to_steal(
+    voices: &[Voice],
+    policy: StealPolicy,
+    channel: u8,
+    key: u8,
+    channel_priorities: &[u8],
+) -> Option<usize> {
+    let candidates = voices
+        .iter()
+        .enumerate()
+        .filter(|(_, voice)| !voice.is_stolen());
+    // Sort by whether the voice is held first, so released voices come before held ones
+    let held = |voice: &Voice| voice.is_held() || voice.is_sustained();
+
+    let chosen = match policy {
+        StealPolicy::Oldest => candidates.min_by_key(|(_, voice)| (held(voice), voice.id.id)),
+        StealPolicy::Quietest => candidates.min_by(|(_, a), (_, b)| {
+            (held(a), a.level())
+                .partial_cmp(&(held(b), b.level()))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        }),
+        StealPolicy::SameNote => candidates.min_by_key(|(_, voice)| {
+            let same_note = voice.id.channel == channel && voice.id.key == key;
+            (!same_note, held(voice), voice.id.id)
+        }),
+        StealPolicy::LowestPriorityChannel => candidates.min_by_key(|(_, voice)| {
+            let priority = channel_priorities
+                .get(voice.id.channel as usize)
+                .copied()
+                .unwrap_or_default();
+            (priority, held(voice), voice.id.id)
+        }),
+    };
+    chosen.map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::patch::Patch;
+    use crate::sampler::Samples;
+    use crate::voice::VoiceId;
+    use crate::wavetable::Wavetables;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const PRIORITIES: [u8; 16] = [64; 16];
+
+    // Voices are given ids in the order they are listed, and play for a moment so their levels settle
+    fn voices(notes: &[(u8, u8, u8)]) -> Vec<Voice> {
+        let patch = Patch {
+            envelope: Envelope::new(0.0, 0.0, 1.0, 1.0),
+            ..Patch::default()
+        };
+        notes
+            .iter()
+            .enumerate()
+            .map(|(i, &(channel, key, velocity))| {
+                let id = VoiceId {
+                    channel,
+                    key,
+                    id: i as u64,
+                };
+                let mut voice = Voice::new(
+                    &patch,
+                    id,
+                    velocity,
+                    &Wavetables::default(),
+                    &Samples::default(),
+                    500_000,
+                    SAMPLE_RATE,
+                );
+                voice.nth(100);
+                voice
+            })
+            .collect()
+    }
+
+    #[test]
+    fn the_oldest_voice_is_stolen() {
+        let voices = voices(&[(0, 60, 100), (0, 62, 100), (0, 64, 100)]);
+        let chosen = choose_voice_to_steal(&voices, StealPolicy::Oldest, 0, 65, &PRIORITIES);
+        assert_eq!(chosen, Some(0));
+    }
+
+    #[test]
+    fn released_voices_are_stolen_before_held_ones() {
+        let mut voices = voices(&[(0, 60, 100), (0, 62, 100), (0, 64, 100)]);
+        voices[2].release();
+        for policy in [
+            StealPolicy::Oldest,
+            StealPolicy::Quietest,
+            StealPolicy::SameNote,
+            StealPolicy::LowestPriorityChannel,
+        ] {
+            let chosen = choose_voice_to_steal(&voices, policy, 0, 65, &PRIORITIES);
+            assert_eq!(chosen, Some(2), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn voices_being_stolen_are_not_stolen_again() {
+        let mut voices = voices(&[(0, 60, 100), (0, 62, 100)]);
+        voices[0].steal();
+        let chosen = choose_voice_to_steal(&voices, StealPolicy::Oldest, 0, 65, &PRIORITIES);
+        assert_eq!(chosen, Some(1));
+        voices[1].steal();
+        let chosen = choose_voice_to_steal(&voices, StealPolicy::Oldest, 0, 65, &PRIORITIES);
+        assert_eq!(chosen, None);
+    }
+
+    #[test]
+    fn the_quietest_voice_is_stolen() {
+        let voices = voices(&[(0, 60, 100), (0, 62, 20), (0, 64, 60)]);
+        let chosen = choose_voice_to_steal(&voices, StealPolicy::Quietest, 0, 65, &PRIORITIES);
+        assert_eq!(chosen, Some(1));
+    }
+
+    #[test]
+    fn a_voice_playing_the_same_note_is_stolen() {
+        let voices = voices(&[(0, 60, 100), (1, 62, 100), (0, 62, 100)]);
+        let chosen = choose_voice_to_steal(&voices, StealPolicy::SameNote, 0, 62, &PRIORITIES);
+        assert_eq!(chosen, Some(2));
+        // Without a voice on the same note, the oldest one is stolen
+        let chosen = choose_voice_to_steal(&voices, StealPolicy::SameNote, 0, 70, &PRIORITIES);
+        assert_eq!(chosen, Some(0));
+    }
+
+    #[test]
+    fn voices_on_the_lowest_priority_channel_are_stolen() {
+        let voices = voices(&[(0, 60, 100), (1, 62, 100), (2, 64, 100), (1, 65, 100)]);
+        let mut priorities = PRIORITIES;
+        priorities[1] = 10;
+        let chosen = choose_voice_to_steal(
+            &voices,
+            StealPolicy::LowestPriorityChannel,
+            0,
+            67,
+            &priorities,
+        );
+        assert_eq!(chosen, Some(1));
+    }
+
+    #[test]
+    fn the_synth_steals_voices_over_the_limit() {
+        let mut synth = crate::synth::Synth::new(SAMPLE_RATE);
+        synth.set_polyphony(PolyphonySettings {
+            max_voices: 2,
+            steal_policy: StealPolicy::Oldest,
+        });
+        for key in 60..64 {
+            synth.note_on(0, key, 100);
+        }
+        assert_eq!(synth.stats().stolen_voices, 2);
+        // Stolen voices fade out and are removed
+        let mut buffer = vec![0.0; SAMPLE_RATE as usize];
+        synth.render(&mut buffer);
+        assert_eq!(synth.voice_count(), 2);
+        assert_eq!(synth.stats().peak_voices, 4);
+    }
+}
@@ -0,0 +1,289 @@
+// This file is for saving patches as JSON preset files, and loading them back in.
+// Every preset stores the version of the format it was saved with, so that presets saved by older versions can be upgraded.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::envelope::Envelope;
+use crate::filter::FilterSettings;
+use crate::fm::FmSettings;
+use crate::oscillator::WaveType;
+use crate::oscillator_bank::single_oscillator;
+use crate::patch::{Patch, PatchType};
+
+// Bump this whenever a change to Patch would stop old presets from loading, and add a step to upgrade_preset
+pub const PRESET_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize)]
+struct Preset {
+    version: u32,
+    patch: Patch,
+}
+
+// The presets that are installed the first time the app is run
+pub fn factory_presets() -> Vec<(&'static str, Patch)> {
+    vec![
+        ("Init", Patch::default()),
+        (
+            "Soft Pad",
+            Patch {
+                oscillators: single_oscillator(WaveType::Triangle),
+                envelope: Envelope::new(0.8, 1.0, 0.7, 1.5),
+                gain: 0.8,
+                filter: FilterSettings::low_pass(4000.0),
+                ..Patch::default()
+            },
+        ),
+        (
+            "Pluck",
+            Patch {
+                oscillators: single_oscillator(WaveType::Square),
+                envelope: Envelope::new(0.0, 0.3, 0.0, 0.1),
+                gain: 0.7,
+                filter: FilterSettings::low_pass(3000.0),
+                ..Patch::default()
+            },
+        ),
+        (
+            "Bell",
+            Patch {
+                oscillators: single_oscillator(WaveType::Sine),
+                envelope: Envelope::new(0.0, 2.5, 0.0, 2.0),
+                gain: 1.0,
+                filter: FilterSettings::low_pass(20000.0),
+                ..Patch::default()
+            },
+        ),
+        (
+            "Organ",
+            Patch {
+                oscillators: single_oscillator(WaveType::Square),
+                envelope: Envelope::new(0.01, 0.0, 1.0, 0.05),
+                gain: 0.5,
+                filter: FilterSettings::low_pass(2000.0),
+                ..Patch::default()
+            },
+        ),
+        (
+            "Bass",
+            Patch {
+                oscillators: single_oscillator(WaveType::Sawtooth),
+                envelope: Envelope::new(0.0, 0.5, 0.6, 0.1),
+                gain: 1.0,
+                filter: FilterSettings::low_pass(800.0),
+                ..Patch::default()
+            },
+        ),
+        (
+            "FM Piano",
+            Patch {
+                patch_type: PatchType::Fm,
+                fm: FmSettings::default(),
+                envelope: Envelope::new(0.0, 0.0, 1.0, 0.5),
+                ..Patch::default()
+            },
+        ),
+    ]
+}
+
+// Writes the factory presets to the directory, leaving alone any that the user has changed or deleted before
+pub fn install_factory_presets(dir: &Path) -> io::Result<()> {
+    if dir.exists() {
+        return Ok(());
+    }
+    for (name, patch) in factory_presets() {
+        save_preset(dir, name, &patch)?;
+    }
+    Ok(())
+}
+
+// Returns the names of all of the presets in the directory, in alphabetical order
+pub fn list_presets(dir: &Path) -> io::Result<Vec<String>> {
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "json")
+        {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn save_preset(dir: &Path, name: &str, patch: &Patch) -> io::Result<()> {
+    let path = preset_path(dir, name)?;
+    fs::create_dir_all(dir)?;
+    let preset = Preset {
+        version: PRESET_VERSION,
+        patch: patch.clone(),
+    };
+    fs::write(path, serde_json::to_string_pretty(&preset)?)
+}
+
+pub fn load_preset(dir: &Path, name: &str) -> io::Result<Patch> {
+    let path = preset_path(dir, name)?;
+    let preset: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    let preset: Preset = serde_json::from_value(upgrade_preset(preset)?)?;
+    Ok(preset.patch)
+}
+
+pub fn delete_preset(dir: &Path, name: &str) -> io::Result<()> {
+    fs::remove_file(preset_path(dir, name)?)
+}
+
+// Upgrades a preset one version at a time until it matches the current format.
+// Settings that were added to Patch without changing the format are filled in by serde's defaults.
+fn upgrade_preset(mut preset: Value) -> io::Result<Value> {
+    // Presets without a version were saved before versions were added, which matches version 1
+    let version = preset
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(1) as u32;
+    if version > PRESET_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Preset version {} is newer than this app supports ({})",
+                version, PRESET_VERSION
+            ),
+        ));
+    }
+
+    if let Some(patch) = preset.get_mut("patch") {
+        upgrade_patch(patch, version);
+    }
+
+    if let Some(preset) = preset.as_object_mut() {
+        preset.insert("version".to_string(), PRESET_VERSION.into());
+    }
+    Ok(preset)
+}
+
+// Upgrades a patch saved with an older version of the format, this is shared with the program map
+pub fn upgrade_patch(patch: &mut Value, version: u32) {
+    let patch = match patch.as_object_mut() {
+        Some(patch) => patch,
+        None => return,
+    };
+
+    // Version 2 replaced the cutoff with filter settings, which hold the cutoff along with the rest of the filter
+    if version < 2 {
+        if let Some(cutoff) = patch.remove("cutoff") {
+            patch.insert(
+                "filter".to_string(),
+                serde_json::json!({ "cutoff": cutoff }),
+            );
+        }
+    }
+
+    // Version 3 replaced the wave type with a list of oscillators, the old wave becomes the first oscillator
+    if version < 3 {
+        if let Some(wave_type) = patch.remove("wave_type") {
+            patch.insert(
+                "oscillators".to_string(),
+                serde_json::json!([
+                    { "wave_type": wave_type },
+                    { "level": 0.0 },
+                    { "level": 0.0 },
+                ]),
+            );
+        }
+    }
+}
+
+// Presets are named by their file name, so names that could point outside of the directory aren't allowed
+fn preset_path(dir: &Path, name: &str) -> io::Result<PathBuf> {
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid preset name: {}", name),
+        ));
+    }
+    Ok(dir.join(format!("{}.json", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn upgraded_patch(preset: Value) -> Patch {
+        let preset: Preset = serde_json::from_value(upgrade_preset(preset).unwrap()).unwrap();
+        assert_eq!(preset.version, PRESET_VERSION);
+        preset.patch
+    }
+
+    #[test]
+    fn presets_without_a_version_are_upgraded_from_version_1() {
+        let patch = upgraded_patch(json!({
+            "patch": { "wave_type": "Square", "cutoff": 1234.0, "gain": 0.5 }
+        }));
+        assert_eq!(patch.filter.cutoff, 1234.0);
+        assert!(matches!(patch.oscillators[0].wave_type, WaveType::Square));
+        assert_eq!(patch.oscillators[0].level, 1.0);
+        assert_eq!(patch.oscillators[1].level, 0.0);
+        assert_eq!(patch.oscillators[2].level, 0.0);
+        assert_eq!(patch.gain, 0.5);
+    }
+
+    #[test]
+    fn version_2_presets_keep_their_filter() {
+        let patch = upgraded_patch(json!({
+            "version": 2,
+            "patch": { "wave_type": "Triangle", "filter": { "cutoff": 600.0, "resonance": 0.5 } }
+        }));
+        assert_eq!(patch.filter.cutoff, 600.0);
+        assert_eq!(patch.filter.resonance, 0.5);
+        assert!(matches!(patch.oscillators[0].wave_type, WaveType::Triangle));
+    }
+
+    #[test]
+    fn current_presets_are_left_alone() {
+        let (_, patch) = factory_presets().remove(1);
+        let preset = json!({ "version": PRESET_VERSION, "patch": patch });
+        assert_eq!(upgrade_preset(preset.clone()).unwrap(), preset);
+    }
+
+    #[test]
+    fn presets_from_newer_versions_are_rejected() {
+        let preset = json!({ "version": PRESET_VERSION + 1, "patch": {} });
+        assert!(upgrade_preset(preset).is_err());
+    }
+
+    #[test]
+    fn saved_presets_load_back_the_same() {
+        let dir = std::env::temp_dir().join(format!("ui_synth_presets_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        install_factory_presets(&dir).unwrap();
+        assert_eq!(list_presets(&dir).unwrap().len(), factory_presets().len());
+
+        let bass = load_preset(&dir, "Bass").unwrap();
+        assert_eq!(bass.filter.cutoff, 800.0);
+        assert!(matches!(bass.oscillators[0].wave_type, WaveType::Sawtooth));
+
+        delete_preset(&dir, "Bass").unwrap();
+        assert!(load_preset(&dir, "Bass").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_outside_of_the_directory_are_rejected() {
+        let dir = Path::new("presets");
+        assert!(preset_path(dir, "").is_err());
+        assert!(preset_path(dir, "../Bass").is_err());
+        assert!(preset_path(dir, ".hidden").is_err());
+        assert!(preset_path(dir, "Bass").is_ok());
+    }
+}
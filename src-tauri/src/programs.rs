@@ -0,0 +1,315 @@
+// This file is for the program map, which decides the patch a channel switches to when it gets a Program Change.
+// The default map is a General MIDI style bank, where every family of 8 programs shares a patch built from the basic waveforms.
+// Channel 10 is the drum channel, where every key plays a different drum sound instead of a different note.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::envelope::Envelope;
+use crate::filter::FilterSettings;
+use crate::oscillator::WaveType;
+use crate::oscillator_bank::single_oscillator;
+use crate::patch::Patch;
+use crate::presets::{upgrade_patch, PRESET_VERSION};
+
+pub const NUM_PROGRAMS: usize = 128;
+const PROGRAMS_PER_FAMILY: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProgramMap {
+    pub programs: Vec<Patch>, // The patch for each program number, from 0 to 127
+    pub drums: BTreeMap<u8, Patch>, // The drum sound for each key on the drum channel
+}
+
+impl Default for ProgramMap {
+    fn default() -> ProgramMap {
+        let programs = family_patches()
+            .into_iter()
+            .flat_map(|patch| std::iter::repeat(patch).take(PROGRAMS_PER_FAMILY))
+            .collect();
+        ProgramMap {
+            programs,
+            drums: drum_kit(),
+        }
+    }
+}
+
+impl ProgramMap {
+    // Programs missing from the map fall back to the default patch
+    pub fn program(&self, program: u8) -> Patch {
+        self.programs
+            .get(program as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn set_program(&mut self, program: u8, patch: Patch) {
+        let program = program as usize;
+        if self.programs.len() <= program {
+            self.programs.resize(program + 1, Patch::default());
+        }
+        self.programs[program] = patch;
+    }
+
+    // Keys without a drum sound are silent
+    pub fn drum(&self, key: u8) -> Option<&Patch> {
+        self.drums.get(&key)
+    }
+
+    // Every patch in the map, the programs and then the drums
+    pub fn patches(&self) -> impl Iterator<Item = &Patch> {
+        self.programs.iter().chain(self.drums.values())
+    }
+}
+
+// Loads the program map saved by the user, or the default map if it hasn't been changed.
+// The map is saved with the preset version its patches use, so that maps saved by older versions can be upgraded.
+pub fn load_program_map(path: &Path) -> io::Result<ProgramMap> {
+    if !path.exists() {
+        return Ok(ProgramMap::default());
+    }
+    let mut program_map: Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+    // Maps without a version were saved before versions were added, which matches version 1
+    let version = program_map
+        .get("version")
+        .and_then(|version| version.as_u64())
+        .unwrap_or(1) as u32;
+    if let Some(programs) = program_map
+        .get_mut("programs")
+        .and_then(|programs| programs.as_array_mut())
+    {
+        programs
+            .iter_mut()
+            .for_each(|patch| upgrade_patch(patch, version));
+    }
+    if let Some(drums) = program_map
+        .get_mut("drums")
+        .and_then(|drums| drums.as_object_mut())
+    {
+        drums
+            .values_mut()
+            .for_each(|patch| upgrade_patch(patch, version));
+    }
+    Ok(serde_json::from_value(program_map)?)
+}
+
+pub fn save_program_map(path: &Path, program_map: &ProgramMap) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut value = serde_json::to_value(program_map)?;
+    if let Some(value) = value.as_object_mut() {
+        value.insert("version".to_string(), PRESET_VERSION.into());
+    }
+    fs::write(path, serde_json::to_string_pretty(&value)?)
+}
+
+fn patch(wave_type: WaveType, envelope: Envelope, gain: f32, cutoff: f32) -> Patch {
+    Patch {
+        oscillators: single_oscillator(wave_type),
+        envelope,
+        gain,
+        filter: FilterSettings::low_pass(cutoff),
+        ..Patch::default()
+    }
+}
+
+// One patch for each of the 16 General MIDI families, in program order
+fn family_patches() -> Vec<Patch> {
+    use WaveType::*;
+    vec![
+        patch(Triangle, Envelope::new(0.0, 1.5, 0.2, 0.3), 1.0, 5000.0), // Piano
+        patch(Sine, Envelope::new(0.0, 1.0, 0.0, 0.8), 1.0, 20000.0),    // Chromatic Percussion
+        patch(Square, Envelope::new(0.01, 0.0, 1.0, 0.05), 0.5, 2000.0), // Organ
+        patch(Sawtooth, Envelope::new(0.0, 0.8, 0.1, 0.2), 0.7, 2500.0), // Guitar
+        patch(Sawtooth, Envelope::new(0.0, 0.5, 0.6, 0.1), 1.0, 800.0),  // Bass
+        patch(Sawtooth, Envelope::new(0.2, 0.5, 0.8, 0.5), 0.6, 3000.0), // Strings
+        patch(Sawtooth, Envelope::new(0.3, 0.5, 0.8, 0.8), 0.5, 2500.0), // Ensemble
+        patch(Sawtooth, Envelope::new(0.05, 0.3, 0.8, 0.2), 0.7, 4000.0), // Brass
+        patch(Square, Envelope::new(0.05, 0.2, 0.8, 0.1), 0.6, 2000.0),  // Reed
+        patch(Triangle, Envelope::new(0.05, 0.2, 0.9, 0.2), 0.9, 6000.0), // Pipe
+        patch(Square, Envelope::new(0.0, 0.2, 0.8, 0.1), 0.6, 8000.0),   // Synth Lead
+        patch(Triangle, Envelope::new(0.8, 1.0, 0.7, 1.5), 0.8, 4000.0), // Synth Pad
+        patch(Sawtooth, Envelope::new(0.5, 2.0, 0.5, 2.0), 0.6, 1500.0), // Synth Effects
+        patch(Triangle, Envelope::new(0.0, 0.6, 0.0, 0.3), 1.0, 6000.0), // Ethnic
+        patch(Sine, Envelope::new(0.0, 0.4, 0.0, 0.2), 1.0, 20000.0),    // Percussive
+        patch(Square, Envelope::new(0.1, 1.0, 0.3, 1.0), 0.4, 1000.0),   // Sound Effects
+    ]
+}
+
+// A drum sound is a short note played at a fixed key, so that it sounds the same whichever key triggers it
+fn drum(wave_type: WaveType, pitch: u8, decay: f32, gain: f32, cutoff: f32) -> Patch {
+    Patch {
+        fixed_key: Some(pitch),
+        ..patch(
+            wave_type,
+            Envelope::new(0.0, decay, 0.0, decay),
+            gain,
+            cutoff,
+        )
+    }
+}
+
+// The drum sounds on the General MIDI keys
+fn drum_kit() -> BTreeMap<u8, Patch> {
+    use WaveType::*;
+    let kick = drum(Sine, 28, 0.3, 1.5, 20000.0);
+    let snare = drum(Square, 62, 0.15, 0.6, 6000.0);
+    let closed_hat = drum(Square, 118, 0.05, 0.3, 20000.0);
+    let open_hat = drum(Square, 118, 0.4, 0.3, 20000.0);
+    let cymbal = drum(Square, 114, 1.2, 0.3, 20000.0);
+    let tom = |pitch| drum(Sine, pitch, 0.3, 1.0, 20000.0);
+
+    let mut drums = BTreeMap::new();
+    drums.insert(35, kick.clone()); // Acoustic Bass Drum
+    drums.insert(36, kick); // Bass Drum
+    drums.insert(37, drum(Triangle, 76, 0.05, 0.8, 20000.0)); // Side Stick
+    drums.insert(38, snare.clone()); // Acoustic Snare
+    drums.insert(39, drum(Square, 70, 0.1, 0.5, 8000.0)); // Hand Clap
+    drums.insert(40, snare); // Electric Snare
+    drums.insert(41, tom(40)); // Low Floor Tom
+    drums.insert(42, closed_hat.clone()); // Closed Hi-Hat
+    drums.insert(43, tom(43)); // High Floor Tom
+    drums.insert(44, closed_hat); // Pedal Hi-Hat
+    drums.insert(45, tom(47)); // Low Tom
+    drums.insert(46, open_hat); // Open Hi-Hat
+    drums.insert(47, tom(50)); // Low-Mid Tom
+    drums.insert(48, tom(53)); // Hi-Mid Tom
+    drums.insert(49, cymbal.clone()); // Crash Cymbal 1
+    drums.insert(50, tom(57)); // High Tom
+    drums.insert(51, drum(Square, 110, 0.8, 0.3, 20000.0)); // Ride Cymbal 1
+    drums.insert(53, drum(Sine, 98, 0.6, 0.6, 20000.0)); // Ride Bell
+    drums.insert(56, drum(Triangle, 80, 0.2, 0.8, 20000.0)); // Cowbell
+    drums.insert(57, cymbal); // Crash Cymbal 2
+    drums.insert(60, tom(72)); // Hi Bongo
+    drums.insert(61, tom(67)); // Low Bongo
+    drums.insert(75, drum(Sine, 96, 0.1, 0.8, 20000.0)); // Claves
+    drums.insert(76, drum(Triangle, 84, 0.08, 0.8, 20000.0)); // Hi Wood Block
+    drums.insert(77, drum(Triangle, 79, 0.08, 0.8, 20000.0)); // Low Wood Block
+    drums
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::channel::DRUM_CHANNEL;
+    use crate::midi_file::get_timed_events;
+    use crate::synth::Synth;
+    use midly::live::LiveEvent;
+    use midly::{Format, Header, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+    // A map where one program has a patch that can be told apart from the others by its gain
+    fn marked_map(program: u8) -> ProgramMap {
+        let mut program_map = ProgramMap::default();
+        program_map.set_program(
+            program,
+            Patch {
+                gain: 0.123,
+                ..Patch::default()
+            },
+        );
+        program_map
+    }
+
+    fn program_change(channel: u8, program: u8) -> LiveEvent<'static> {
+        LiveEvent::Midi {
+            channel: channel.into(),
+            message: MidiMessage::ProgramChange {
+                program: program.into(),
+            },
+        }
+    }
+
+    #[test]
+    fn programs_use_the_patch_for_their_family() {
+        let program_map = ProgramMap::default();
+        assert_eq!(program_map.programs.len(), NUM_PROGRAMS);
+        // Acoustic Grand Piano and Clavi are both pianos
+        assert!(matches!(
+            program_map.program(0).oscillators[0].wave_type,
+            WaveType::Triangle
+        ));
+        assert!(matches!(
+            program_map.program(7).oscillators[0].wave_type,
+            WaveType::Triangle
+        ));
+        // Drawbar Organ
+        assert!(matches!(
+            program_map.program(16).oscillators[0].wave_type,
+            WaveType::Square
+        ));
+        // Acoustic Bass
+        let bass = program_map.program(32);
+        assert!(matches!(bass.oscillators[0].wave_type, WaveType::Sawtooth));
+        assert_eq!(bass.filter.cutoff, 800.0);
+    }
+
+    #[test]
+    fn unmapped_programs_fall_back_to_the_default_patch() {
+        let mut program_map = ProgramMap {
+            programs: Vec::new(),
+            drums: BTreeMap::new(),
+        };
+        let default = Patch::default();
+        assert_eq!(program_map.program(0).gain, default.gain);
+        assert_eq!(program_map.program(127).gain, default.gain);
+
+        // Setting a program past the end fills the gap with the default patch
+        program_map.set_program(
+            20,
+            Patch {
+                gain: 0.123,
+                ..Patch::default()
+            },
+        );
+        assert_eq!(program_map.programs.len(), 21);
+        assert_eq!(program_map.program(10).gain, default.gain);
+        assert_eq!(program_map.program(20).gain, 0.123);
+        assert!(program_map.drum(36).is_none());
+    }
+
+    #[test]
+    fn the_drum_channel_ignores_program_changes() {
+        let mut synth = Synth::new(44100);
+        synth.set_program_map(marked_map(5));
+        synth.handle_event(program_change(DRUM_CHANNEL, 5));
+        assert_ne!(synth.channel_settings(DRUM_CHANNEL).patch.gain, 0.123);
+        synth.handle_event(program_change(0, 5));
+        assert_eq!(synth.channel_settings(0).patch.gain, 0.123);
+    }
+
+    #[test]
+    fn program_changes_in_midi_files_switch_the_channels_patch() {
+        let track = vec![
+            TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: 2.into(),
+                    message: MidiMessage::ProgramChange { program: 40.into() },
+                },
+            },
+            TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(midly::MetaMessage::EndOfTrack),
+            },
+        ];
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(480.into())),
+            tracks: vec![track],
+        };
+
+        let mut synth = Synth::new(44100);
+        synth.set_program_map(marked_map(40));
+        for timed_event in get_timed_events(&smf) {
+            synth.handle_event(timed_event.event);
+        }
+        assert_eq!(synth.channel_settings(2).patch.gain, 0.123);
+        assert_ne!(synth.channel_settings(0).patch.gain, 0.123);
+    }
+}
@@ -0,0 +1,332 @@
+// This file is for rendering MIDI files to WAV files faster than real time, without needing a sound card.
+
+use std::path::Path;
+
+use crate::midi_file::{get_timed_events, TempoMap};
+use crate::synth::Synth;
+
+const MAX_TAIL_SECONDS: u32 = 30; // How long to wait for notes to finish releasing after the last note off
+const BLOCK_FRAMES: usize = 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum SampleFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl SampleFormat {
+    pub fn from_name(name: &str) -> Option<SampleFormat> {
+        match name {
+            "int16" => Some(SampleFormat::Int16),
+            "int24" => Some(SampleFormat::Int24),
+            "float32" => Some(SampleFormat::Float32),
+            _ => None,
+        }
+    }
+
+    fn wav_spec(&self, sample_rate: u32) -> hound::WavSpec {
+        let (bits_per_sample, sample_format) = match self {
+            SampleFormat::Int16 => (16, hound::SampleFormat::Int),
+            SampleFormat::Int24 => (24, hound::SampleFormat::Int),
+            SampleFormat::Float32 => (32, hound::SampleFormat::Float),
+        };
+        hound::WavSpec {
+            channels: 2,
+            sample_rate,
+            bits_per_sample,
+            sample_format,
+        }
+    }
+}
+
+// Plays the notes of a MIDI file through a synth and writes the result to a WAV file.
+// The file is written in stereo at the synth's sample rate.
+pub fn render_midi_to_wav(
+    smf: &midly::Smf,
+    path: &Path,
+    format: SampleFormat,
+    mut synth: Synth,
+) -> Result<(), hound::Error> {
+    let sample_rate = synth.sample_rate();
+    let mut writer = hound::WavWriter::create(path, format.wav_spec(sample_rate))?;
+    let mut buffer = vec![0.0; BLOCK_FRAMES * 2]; // Stereo, so there are two samples in every frame
+    let mut frames_rendered: u64 = 0;
+    let to_frame = |time: u64| time * sample_rate as u64 / 1_000_000;
+
+    let tempo_map = TempoMap::new(smf.header.timing, &smf.tracks);
+    let mut tempo_changes = tempo_map.tempo_changes().peekable();
+    for timed_event in get_timed_events(smf) {
+        // Synced LFOs follow every tempo change up to the event
+        while let Some((time, tempo)) = tempo_changes.next_if(|(time, _)| *time <= timed_event.time)
+        {
+            render_until(
+                &mut synth,
+                &mut writer,
+                &mut buffer,
+                &mut frames_rendered,
+                to_frame(time),
+                format,
+            )?;
+            synth.set_tempo(tempo);
+        }
+        // Render everything up to the event, then play it
+        render_until(
+            &mut synth,
+            &mut writer,
+            &mut buffer,
+            &mut frames_rendered,
+            to_frame(timed_event.time),
+            format,
+        )?;
+        synth.handle_event(timed_event.event);
+    }
+
+    // Let any notes that are still releasing ring out
+    let max_tail = frames_rendered + (MAX_TAIL_SECONDS * sample_rate) as u64;
+    while synth.voice_count() > 0 && frames_rendered < max_tail {
+        let frames = (max_tail - frames_rendered).min(BLOCK_FRAMES as u64) as usize;
+        synth.render(&mut buffer[..frames * 2]);
+        write_samples(&mut writer, &buffer[..frames * 2], format)?;
+        frames_rendered += frames as u64;
+    }
+
+    writer.finalize()
+}
+
+// Renders the synth up to a frame, in blocks that fit in the buffer
+fn render_until<W>(
+    synth: &mut Synth,
+    writer: &mut hound::WavWriter<W>,
+    buffer: &mut [f32],
+    frames_rendered: &mut u64,
+    frame: u64,
+    format: SampleFormat,
+) -> Result<(), hound::Error>
+where
+    W: std::io::Write + std::io::Seek,
+{
+    while *frames_rendered < frame {
+        let frames = (frame - *frames_rendered).min((buffer.len() / 2) as u64) as usize;
+        synth.render(&mut buffer[..frames * 2]);
+        write_samples(writer, &buffer[..frames * 2], format)?;
+        *frames_rendered += frames as u64;
+    }
+    Ok(())
+}
+
+fn write_samples<W>(
+    writer: &mut hound::WavWriter<W>,
+    samples: &[f32],
+    format: SampleFormat,
+) -> Result<(), hound::Error>
+where
+    W: std::io::Write + std::io::Seek,
+{
+    for sample in samples {
+        let sample = sample.clamp(-1.0, 1.0);
+        match format {
+            SampleFormat::Int16 => writer.write_sample((sample * i16::MAX as f32) as i16)?,
+            SampleFormat::Int24 => writer.write_sample((sample * 8_388_607.0) as i32)?,
+            SampleFormat::Float32 => writer.write_sample(sample)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::lfo::{LfoDestination, LfoSettings, LfoShape};
+    use crate::patch::Patch;
+    use midly::{
+        Format, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind,
+    };
+
+    const SAMPLE_RATE: u32 = 1000;
+    const TICKS_PER_BEAT: u16 = 480;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent {
+            delta: delta.into(),
+            kind,
+        }
+    }
+
+    fn note(delta: u32, on: bool) -> TrackEvent<'static> {
+        let message = if on {
+            MidiMessage::NoteOn {
+                key: 60.into(),
+                vel: 100.into(),
+            }
+        } else {
+            MidiMessage::NoteOff {
+                key: 60.into(),
+                vel: 0.into(),
+            }
+        };
+        event(
+            delta,
+            TrackEventKind::Midi {
+                channel: 0.into(),
+                message,
+            },
+        )
+    }
+
+    // A file with one note, which is let go after half a second if it has a note off
+    fn one_note(note_off: bool) -> Smf<'static> {
+        let mut track = vec![
+            event(0, TrackEventKind::Meta(MetaMessage::Tempo(500_000.into()))),
+            note(0, true),
+        ];
+        if note_off {
+            track.push(note(TICKS_PER_BEAT as u32, false));
+        }
+        track.push(event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+        Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(TICKS_PER_BEAT.into())),
+            tracks: vec![track],
+        }
+    }
+
+    fn held_note(release: f32) -> Patch {
+        Patch {
+            envelope: Envelope::new(0.0, 0.0, 1.0, release),
+            ..Patch::default()
+        }
+    }
+
+    // Renders the file with a patch, and reads back the WAV file's spec and its samples as floats
+    fn render(smf: &Smf, format: SampleFormat, patch: Patch) -> (hound::WavSpec, Vec<f32>) {
+        let path = std::env::temp_dir().join(format!(
+            "ui_synth_render_{}_{:?}.wav",
+            std::process::id(),
+            format
+        ));
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_patch(0, patch);
+        render_midi_to_wav(smf, &path, format, synth).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let spec = reader.spec();
+        let samples = match spec.sample_format {
+            hound::SampleFormat::Float => reader
+                .samples::<f32>()
+                .map(|sample| sample.unwrap())
+                .collect(),
+            hound::SampleFormat::Int => {
+                let scale = (1 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .map(|sample| sample.unwrap() as f32 / scale)
+                    .collect()
+            }
+        };
+        std::fs::remove_file(&path).unwrap();
+        (spec, samples)
+    }
+
+    #[test]
+    fn every_format_writes_its_header() {
+        for (format, bits_per_sample, sample_format) in [
+            (SampleFormat::Int16, 16, hound::SampleFormat::Int),
+            (SampleFormat::Int24, 24, hound::SampleFormat::Int),
+            (SampleFormat::Float32, 32, hound::SampleFormat::Float),
+        ] {
+            let (spec, samples) = render(&one_note(true), format, held_note(0.0));
+            assert_eq!(spec.channels, 2);
+            assert_eq!(spec.sample_rate, SAMPLE_RATE);
+            assert_eq!(spec.bits_per_sample, bits_per_sample);
+            assert_eq!(spec.sample_format, sample_format);
+            assert!(
+                samples.iter().any(|sample| sample.abs() > 0.01),
+                "{:?}",
+                format
+            );
+        }
+    }
+
+    #[test]
+    fn the_release_is_rendered_after_the_last_event() {
+        let (_, samples) = render(&one_note(true), SampleFormat::Float32, held_note(0.2));
+        let frames = samples.len() / 2;
+        // The note off is half a second in, and the release takes a fifth of a second more
+        assert!((700..700 + BLOCK_FRAMES).contains(&frames), "{}", frames);
+        // The note is still sounding at the start of its release
+        assert!(samples[1000..1100].iter().any(|sample| sample.abs() > 0.01));
+    }
+
+    #[test]
+    fn notes_that_never_end_are_cut_off() {
+        let (_, samples) = render(&one_note(false), SampleFormat::Int16, held_note(0.0));
+        assert_eq!(samples.len() / 2, (MAX_TAIL_SECONDS * SAMPLE_RATE) as usize);
+    }
+
+    #[test]
+    fn synced_lfos_follow_every_tempo_change() {
+        // A note held for a second at 120 bpm and then a second at 240 bpm
+        let track = vec![
+            event(0, TrackEventKind::Meta(MetaMessage::Tempo(500_000.into()))),
+            note(0, true),
+            event(
+                TICKS_PER_BEAT as u32 * 2,
+                TrackEventKind::Meta(MetaMessage::Tempo(250_000.into())),
+            ),
+            note(TICKS_PER_BEAT as u32 * 4, false),
+        ];
+        let smf = Smf {
+            header: Header::new(Format::SingleTrack, Timing::Metrical(TICKS_PER_BEAT.into())),
+            tracks: vec![track],
+        };
+        // The LFO pans the note from one side to the other every half a beat
+        let patch = Patch {
+            global_lfo: LfoSettings {
+                shape: LfoShape::Square,
+                sync: true,
+                beats: 1.0,
+                destination: LfoDestination::Pan,
+                amount: 1.0,
+                ..LfoSettings::default()
+            },
+            ..held_note(0.0)
+        };
+        let (_, samples) = render(&smf, SampleFormat::Float32, patch);
+
+        // The frames where the note moves to the other side
+        let mut side = 0.0;
+        let mut moves = Vec::new();
+        for (frame, values) in samples.chunks(2).enumerate() {
+            let difference = values[0].abs() - values[1].abs();
+            if difference.abs() > 0.01 {
+                if side != 0.0 && difference.signum() != side {
+                    moves.push(frame);
+                }
+                side = difference.signum();
+            }
+        }
+        let gaps: Vec<usize> = moves.windows(2).map(|moves| moves[1] - moves[0]).collect();
+        let gaps_in = |start: usize, end: usize| {
+            moves
+                .windows(2)
+                .zip(gaps.iter())
+                .filter(|(moves, _)| moves[0] >= start && moves[1] <= end)
+                .map(|(_, gap)| *gap)
+                .collect::<Vec<usize>>()
+        };
+        let before = gaps_in(0, 1000);
+        let after = gaps_in(1050, 2000);
+        assert!(!before.is_empty() && !after.is_empty(), "{:?}", moves);
+        assert!(
+            before.iter().all(|gap| gap.abs_diff(250) <= 10),
+            "{:?}",
+            moves
+        );
+        assert!(
+            after.iter().all(|gap| gap.abs_diff(125) <= 10),
+            "{:?}",
+            moves
+        );
+    }
+}
@@ -0,0 +1,471 @@
+// This file is for the sampler, which plays recordings loaded from audio files instead of generating a wave.
+// A sampler patch has a list of zones, each one a file with the range of keys and velocities it plays for.
+// Samples are repitched to match the note, and can loop between two points for as long as the note is held.
+// Zones can also play one of the samples inside a SoundFont file, which is loaded along with all of its other samples.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rodio::source::Source;
+use rodio::Decoder;
+use serde::{Deserialize, Serialize};
+
+use crate::oscillator::Glide;
+use crate::patch::Patch;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SampleZone {
+    pub path: String, // The audio file, which can be WAV, FLAC or OGG, or a SoundFont
+    pub sample_index: Option<usize>, // Which of a SoundFont's samples plays, None for other audio files
+    pub root_key: u8,                // The key the sample plays at its recorded pitch
+    pub low_key: u8,
+    pub high_key: u8,
+    pub low_velocity: u8,
+    pub high_velocity: u8,
+    pub looping: bool, // Whether the sample loops while the note plays, instead of stopping at the end
+    pub loop_start: u32, // In frames from the start of the file
+    pub loop_end: u32, // In frames from the start of the file, 0 loops to the end of the file
+    pub tune: f32,     // In cents, for samples that weren't recorded exactly in tune
+    pub gain: f32,     // Multiplies the volume of the sample
+}
+
+impl Default for SampleZone {
+    fn default() -> SampleZone {
+        SampleZone {
+            path: String::new(),
+            sample_index: None,
+            root_key: 60,
+            low_key: 0,
+            high_key: 127,
+            low_velocity: 1,
+            high_velocity: 127,
+            looping: false,
+            loop_start: 0,
+            loop_end: 0,
+            tune: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl SampleZone {
+    pub fn contains(&self, key: u8, velocity: u8) -> bool {
+        (self.low_key..=self.high_key).contains(&key)
+            && (self.low_velocity..=self.high_velocity).contains(&velocity)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SamplerSettings {
+    pub zones: Vec<SampleZone>,
+}
+
+impl SamplerSettings {
+    // The first zone that the key and velocity fall in, notes outside of every zone are silent
+    pub fn zone(&self, key: u8, velocity: u8) -> Option<&SampleZone> {
+        self.zones.iter().find(|zone| zone.contains(key, velocity))
+    }
+}
+
+// The decoded audio of a file, the samples of each frame are interleaved
+#[derive(Clone, Debug)]
+pub struct Sample {
+    data: Vec<f32>,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl Sample {
+    pub fn new(data: Vec<f32>, channels: u16, sample_rate: u32) -> Sample {
+        Sample {
+            data,
+            channels,
+            sample_rate,
+        }
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.data.len() / self.channels.max(1) as usize
+    }
+}
+
+// Decodes an audio file with rodio, which supports WAV, FLAC and OGG
+pub fn load_sample(path: &Path) -> io::Result<Sample> {
+    let decoder = Decoder::new(BufReader::new(File::open(path)?))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let channels = decoder.channels();
+    let sample_rate = decoder.sample_rate();
+    let data: Vec<f32> = decoder.convert_samples().collect();
+    if data.is_empty() || channels == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The sample has no audio",
+        ));
+    }
+    Ok(Sample::new(data, channels, sample_rate))
+}
+
+// The samples used by the synth's patches, keyed by their path and their index in SoundFonts.
+// Samples are loaded before a patch is set rather than when a note starts, so that notes don't wait for the disk.
+#[derive(Default)]
+pub struct Samples {
+    samples: HashMap<(String, Option<usize>), Option<Arc<Sample>>>, // None for files that couldn't be loaded, so they aren't tried again
+    soundfonts: HashSet<String>, // The SoundFonts that have been loaded or tried, zones past the end of them are silent
+}
+
+impl Samples {
+    pub fn get(&self, zone: &SampleZone) -> Option<Arc<Sample>> {
+        self.samples
+            .get(&(zone.path.clone(), zone.sample_index))
+            .cloned()
+            .flatten()
+    }
+
+    pub fn insert(&mut self, path: String, sample: Sample) {
+        self.samples.insert((path, None), Some(Arc::new(sample)));
+    }
+
+    // Adds every sample in a SoundFont, in the order that zones refer to them by
+    pub fn insert_soundfont(&mut self, path: String, samples: Vec<Sample>) {
+        for (index, sample) in samples.into_iter().enumerate() {
+            self.samples
+                .insert((path.clone(), Some(index)), Some(Arc::new(sample)));
+        }
+        self.soundfonts.insert(path);
+    }
+
+    // Remembers a file that couldn't be loaded, so that zones using it play silence
+    pub fn insert_missing(&mut self, path: String) {
+        self.samples.entry((path, None)).or_insert(None);
+    }
+
+    // Remembers a SoundFont that couldn't be loaded, so that zones using it play silence
+    pub fn insert_missing_soundfont(&mut self, path: String) {
+        self.soundfonts.insert(path);
+    }
+
+    // The audio files that the patch uses which haven't been loaded, or tried, yet. SoundFonts aren't included.
+    pub fn missing(&self, patch: &Patch) -> Vec<String> {
+        patch
+            .sampler
+            .zones
+            .iter()
+            .filter(|zone| zone.sample_index.is_none())
+            .filter(|zone| !self.samples.contains_key(&(zone.path.clone(), None)))
+            .map(|zone| zone.path.clone())
+            .collect()
+    }
+
+    // The SoundFonts that the patch uses which haven't been loaded, or tried, yet
+    pub fn missing_soundfonts(&self, patch: &Patch) -> Vec<String> {
+        patch
+            .sampler
+            .zones
+            .iter()
+            .filter(|zone| zone.sample_index.is_some())
+            .filter(|zone| !self.soundfonts.contains(&zone.path))
+            .map(|zone| zone.path.clone())
+            .collect()
+    }
+}
+
+// An audio source which plays a sample at the pitch of a note.
+// It is stereo for stereo samples, and ends when it reaches the end of a sample that doesn't loop.
+#[derive(Clone, Debug)]
+pub struct SamplePlayer {
+    sample: Option<Arc<Sample>>, // None when the note isn't in any zone, which plays nothing
+    position: f64,               // In frames of the sample
+    rate_scale: f64, // Multiplied by the frequency to get how many frames the position moves each output sample
+    loop_range: Option<(f64, f64)>, // The start and end of the loop, in frames
+    glide: Glide,
+    pitch_bend: f32, // Multiplies the frequency, so bends don't change the note being played
+    gain: f32,
+    right: Option<f32>, // The right sample of the current frame, which is returned after the left one
+    sample_rate: u32,
+}
+
+impl SamplePlayer {
+    pub fn new(
+        freq: f32,
+        zone: Option<&SampleZone>,
+        sample: Option<Arc<Sample>>,
+        sample_rate: u32,
+    ) -> SamplePlayer {
+        // The tuning moves the root down, so that the sample plays higher
+        let root_hz = zone.map_or(440.0, |zone| {
+            440.0 * 2.0_f32.powf((zone.root_key as f32 - 69.0 - zone.tune / 100.0) / 12.0)
+        });
+        let rate_scale = sample.as_ref().map_or(0.0, |sample| {
+            sample.sample_rate as f64 / (root_hz as f64 * sample_rate as f64)
+        });
+        let loop_range = match (zone, sample.as_ref()) {
+            (Some(zone), Some(sample)) if zone.looping => {
+                let num_frames = sample.num_frames() as u32;
+                let end = if zone.loop_end == 0 {
+                    num_frames
+                } else {
+                    zone.loop_end.min(num_frames)
+                };
+                // Loops that are too short to hold a whole frame would never move on
+                if end > zone.loop_start + 1 {
+                    Some((zone.loop_start as f64, end as f64))
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        SamplePlayer {
+            sample,
+            position: 0.0,
+            rate_scale,
+            loop_range,
+            glide: Glide::new(freq),
+            pitch_bend: 1.0,
+            gain: zone.map_or(1.0, |zone| zone.gain),
+            right: None,
+            sample_rate,
+        }
+    }
+
+    // The frequency of the note being played, not including pitch bend
+    pub fn frequency(&self) -> f32 {
+        self.glide.frequency()
+    }
+
+    pub fn set_frequency(&mut self, freq: f32) {
+        self.glide.set_frequency(freq);
+    }
+
+    // Slides to a new frequency over the given number of seconds, the same way as the oscillators
+    pub fn glide_to(&mut self, freq: f32, seconds: f32) {
+        self.glide
+            .glide_to(freq, (seconds * self.sample_rate as f32) as u32);
+    }
+
+    // Bends the pitch up or down by a number of semitones
+    pub fn set_pitch_bend(&mut self, semitones: f32) {
+        self.pitch_bend = 2.0_f32.powf(semitones / 12.0);
+    }
+}
+
+impl Iterator for SamplePlayer {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(right) = self.right.take() {
+            return Some(right);
+        }
+        let sample = self.sample.as_ref()?;
+
+        if let Some((start, end)) = self.loop_range {
+            if self.position >= end {
+                self.position = start + (self.position - end) % (end - start);
+            }
+        }
+        let frame = self.position as usize;
+        // The frame after the end of the loop is the start of the loop, so that it joins up smoothly
+        let next_frame = match self.loop_range {
+            Some((start, end)) if frame + 1 >= end as usize => start as usize,
+            _ => frame + 1,
+        };
+        if next_frame >= sample.num_frames() {
+            return None;
+        }
+
+        // Interpolate between the frames either side of the position
+        let fraction = (self.position - frame as f64) as f32;
+        let channels = sample.channels as usize;
+        let read = |channel: usize| {
+            let a = sample.data[frame * channels + channel];
+            let b = sample.data[next_frame * channels + channel];
+            (a + (b - a) * fraction) * self.gain
+        };
+        let left = read(0);
+        if channels > 1 {
+            self.right = Some(read(1));
+        }
+
+        self.position += (self.glide.frequency() * self.pitch_bend) as f64 * self.rate_scale;
+        self.glide.next_sample();
+        Some(left)
+    }
+}
+
+impl Source for SamplePlayer {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        match self.sample.as_ref() {
+            Some(sample) if sample.channels > 1 => 2,
+            _ => 1,
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None // Depends on the note and whether the sample loops
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 1000;
+
+    fn root_hz(key: u8) -> f32 {
+        440.0 * 2.0_f32.powf((key as f32 - 69.0) / 12.0)
+    }
+
+    // A mono sample whose value at each frame is the frame's number
+    fn ramp(num_frames: usize) -> Arc<Sample> {
+        Arc::new(Sample::new(
+            (0..num_frames).map(|frame| frame as f32).collect(),
+            1,
+            SAMPLE_RATE,
+        ))
+    }
+
+    fn assert_frames(values: &[f32], expected: &[f32]) {
+        assert_eq!(values.len(), expected.len(), "{:?}", values);
+        for (value, expected) in values.iter().zip(expected.iter()) {
+            assert!((value - expected).abs() < 0.01, "{:?}", values);
+        }
+    }
+
+    #[test]
+    fn the_first_zone_holding_the_key_and_velocity_plays() {
+        let zone = |low_key, high_key, low_velocity, high_velocity| SampleZone {
+            low_key,
+            high_key,
+            low_velocity,
+            high_velocity,
+            ..SampleZone::default()
+        };
+        let settings = SamplerSettings {
+            zones: vec![
+                zone(0, 59, 1, 127),
+                zone(60, 127, 1, 63),
+                zone(60, 127, 1, 127),
+            ],
+        };
+        let index = |key, velocity| {
+            settings.zone(key, velocity).map(|found| {
+                settings
+                    .zones
+                    .iter()
+                    .position(|zone| std::ptr::eq(zone, found))
+                    .unwrap()
+            })
+        };
+        assert_eq!(index(40, 100), Some(0));
+        assert_eq!(index(60, 40), Some(1));
+        assert_eq!(index(60, 100), Some(2));
+
+        let settings = SamplerSettings {
+            zones: vec![zone(48, 72, 1, 127)],
+        };
+        assert!(settings.zone(47, 100).is_none());
+        assert!(settings.zone(73, 100).is_none());
+    }
+
+    #[test]
+    fn the_root_key_plays_at_the_recorded_pitch() {
+        let zone = SampleZone::default();
+        let player = SamplePlayer::new(root_hz(60), Some(&zone), Some(ramp(5)), SAMPLE_RATE);
+        let values: Vec<f32> = player.collect();
+        // The last frame is only used to interpolate towards
+        assert_frames(&values, &[0.0, 1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn an_octave_up_plays_twice_as_fast() {
+        let zone = SampleZone::default();
+        let player = SamplePlayer::new(root_hz(72), Some(&zone), Some(ramp(9)), SAMPLE_RATE);
+        let values: Vec<f32> = player.collect();
+        assert_frames(&values, &[0.0, 2.0, 4.0, 6.0]);
+    }
+
+    #[test]
+    fn looping_samples_go_back_to_the_loop_start() {
+        let zone = SampleZone {
+            looping: true,
+            loop_start: 2,
+            loop_end: 5,
+            ..SampleZone::default()
+        };
+        let player = SamplePlayer::new(root_hz(60), Some(&zone), Some(ramp(8)), SAMPLE_RATE);
+        let values: Vec<f32> = player.take(10).collect();
+        assert_frames(&values, &[0.0, 1.0, 2.0, 3.0, 4.0, 2.0, 3.0, 4.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn loops_without_an_end_loop_the_whole_sample() {
+        let zone = SampleZone {
+            looping: true,
+            ..SampleZone::default()
+        };
+        let player = SamplePlayer::new(root_hz(60), Some(&zone), Some(ramp(3)), SAMPLE_RATE);
+        let values: Vec<f32> = player.take(7).collect();
+        assert_frames(&values, &[0.0, 1.0, 2.0, 0.0, 1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn notes_outside_every_zone_are_silent() {
+        let player = SamplePlayer::new(root_hz(60), None, None, SAMPLE_RATE);
+        assert_eq!(player.count(), 0);
+    }
+
+    #[test]
+    fn stereo_samples_play_both_channels() {
+        let sample = Arc::new(Sample::new(
+            vec![1.0, -1.0, 2.0, -2.0, 3.0, -3.0],
+            2,
+            SAMPLE_RATE,
+        ));
+        let zone = SampleZone::default();
+        let player = SamplePlayer::new(root_hz(60), Some(&zone), Some(sample), SAMPLE_RATE);
+        assert_eq!(player.channels(), 2);
+        let values: Vec<f32> = player.collect();
+        assert_frames(&values, &[1.0, -1.0, 2.0, -2.0]);
+    }
+
+    #[test]
+    fn soundfonts_are_only_missing_until_they_are_added() {
+        let zone = |path: &str, sample_index| SampleZone {
+            path: path.to_string(),
+            sample_index: Some(sample_index),
+            ..SampleZone::default()
+        };
+        let mut patch = Patch::default();
+        patch.sampler.zones = vec![zone("a.sf2", 0), zone("a.sf2", 5), zone("b.sf2", 0)];
+
+        let mut samples = Samples::default();
+        assert_eq!(samples.missing_soundfonts(&patch).len(), 3);
+        samples.insert_soundfont(
+            "a.sf2".to_string(),
+            vec![Sample::new(vec![0.0], 1, SAMPLE_RATE)],
+        );
+        samples.insert_missing_soundfont("b.sf2".to_string());
+        assert!(samples.missing_soundfonts(&patch).is_empty());
+
+        let zones = &patch.sampler.zones;
+        assert!(samples.get(&zones[0]).is_some());
+        // Past the end of the SoundFont, and in one that couldn't be loaded
+        assert!(samples.get(&zones[1]).is_none());
+        assert!(samples.get(&zones[2]).is_none());
+    }
+}
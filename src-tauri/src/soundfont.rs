@@ -0,0 +1,706 @@
+// This file is for loading SoundFont (.sf2) files, which hold a bank of sampled instruments.
+// Every preset in the General MIDI bank becomes a sampler patch in the program map, and the first drum kit becomes
+// the drum sounds, so that MIDI files play with the SoundFont's instruments. The zones, tuning, loops, volume and
+// volume envelopes are used, but the SoundFont's filters, LFOs and modulators aren't.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::envelope::Envelope;
+use crate::patch::{Patch, PatchType};
+use crate::programs::{ProgramMap, NUM_PROGRAMS};
+use crate::sampler::{Sample, SampleZone, SamplerSettings};
+
+// The bank that drum kits are stored in, the General MIDI instruments are in bank 0
+const DRUM_BANK: u16 = 128;
+
+// The generators that are used, numbered as in the SoundFont specification
+const START_LOOP_OFFSET: usize = 2;
+const END_LOOP_OFFSET: usize = 3;
+const ATTACK: usize = 34;
+const DECAY: usize = 36;
+const SUSTAIN: usize = 37;
+const RELEASE: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VELOCITY_RANGE: usize = 44;
+const START_LOOP_COARSE_OFFSET: usize = 45;
+const ATTENUATION: usize = 48;
+const END_LOOP_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const ROOT_KEY: usize = 58;
+const NUM_GENERATORS: usize = 61;
+
+// The envelope times are in timecents, and this is the shortest time, which is about a millisecond
+const MIN_TIMECENTS: i32 = -12000;
+// Attenuations are in centibels, and this is the quietest, which is 144 dB down
+const MAX_CENTIBELS: i32 = 1440;
+
+// The right half of a stereo pair is skipped, so that stereo instruments play their left half in the centre
+const RIGHT_SAMPLE: u16 = 2;
+// Samples stored in a sound card's memory rather than in the file
+const ROM_SAMPLE: u16 = 0x8000;
+
+// A zone of a preset, with the volume envelope it plays with
+#[derive(Clone, Debug)]
+pub struct SoundFontZone {
+    pub sample_zone: SampleZone,
+    pub envelope: Envelope,
+}
+
+#[derive(Clone, Debug)]
+pub struct SoundFontPreset {
+    pub name: String,
+    pub bank: u16,
+    pub program: u16,
+    pub zones: Vec<SoundFontZone>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SoundFont {
+    pub presets: Vec<SoundFontPreset>,
+    pub samples: Vec<Sample>, // In the order of the file's sample headers, which zones refer to them by
+}
+
+impl SoundFont {
+    // Builds a program map that plays the SoundFont from the file at the path.
+    // Programs that the SoundFont doesn't have keep the default patches, as does the drum kit if there isn't one.
+    pub fn program_map(&self, path: &str) -> ProgramMap {
+        let mut program_map = ProgramMap::default();
+        // Bank Select isn't supported, so only the first bank can be reached with Program Change
+        for preset in self
+            .presets
+            .iter()
+            .filter(|preset| preset.bank == 0 && (preset.program as usize) < NUM_PROGRAMS)
+        {
+            if let Some(patch) = preset_patch(preset, path, None) {
+                program_map.set_program(preset.program as u8, patch);
+            }
+        }
+
+        let kit = self
+            .presets
+            .iter()
+            .filter(|preset| preset.bank == DRUM_BANK)
+            .min_by_key(|preset| preset.program);
+        if let Some(kit) = kit {
+            program_map.drums = (0..=127)
+                .filter_map(|key| preset_patch(kit, path, Some(key)).map(|patch| (key, patch)))
+                .collect::<BTreeMap<u8, Patch>>();
+        }
+        program_map
+    }
+}
+
+// A sampler patch for a preset, or for just one of its keys for drum kits, which have a patch for every key.
+// Patches only have one envelope, so it is taken from the zone that plays middle C, or the key for drums.
+fn preset_patch(preset: &SoundFontPreset, path: &str, key: Option<u8>) -> Option<Patch> {
+    let covers = |zone: &SoundFontZone, key: u8| {
+        (zone.sample_zone.low_key..=zone.sample_zone.high_key).contains(&key)
+    };
+    let zones: Vec<&SoundFontZone> = preset
+        .zones
+        .iter()
+        .filter(|zone| key.map_or(true, |key| covers(zone, key)))
+        .collect();
+    let envelope_zone = zones
+        .iter()
+        .find(|zone| covers(zone, key.unwrap_or(60)))
+        .or_else(|| zones.first())?;
+
+    Some(Patch {
+        patch_type: PatchType::Sampler,
+        sampler: SamplerSettings {
+            zones: zones
+                .iter()
+                .map(|zone| SampleZone {
+                    path: path.to_string(),
+                    ..zone.sample_zone.clone()
+                })
+                .collect(),
+        },
+        envelope: envelope_zone.envelope,
+        ..Patch::default()
+    })
+}
+
+pub fn load_soundfont(path: &Path) -> io::Result<SoundFont> {
+    parse_soundfont(&fs::read(path)?)
+}
+
+// Reads a SoundFont from the contents of a file. The file is a RIFF file with three lists, the INFO list which
+// describes the file, the sdta list which holds the samples and the pdta list which holds the presets.
+pub fn parse_soundfont(data: &[u8]) -> io::Result<SoundFont> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+        return Err(invalid("Not a SoundFont file"));
+    }
+    let mut smpl: &[u8] = &[];
+    let mut pdta = HashMap::new();
+    for (id, list) in chunks(&data[12..]) {
+        if id != b"LIST" || list.len() < 4 {
+            continue;
+        }
+        for (sub_id, sub_chunk) in chunks(&list[4..]) {
+            match &list[0..4] {
+                b"sdta" if sub_id == b"smpl" => smpl = sub_chunk,
+                b"pdta" => {
+                    pdta.insert(sub_id, sub_chunk);
+                }
+                _ => {}
+            }
+        }
+    }
+    let chunk = |id: &[u8], size: usize| -> io::Result<Vec<&[u8]>> {
+        let chunk = pdta.get(id).ok_or_else(|| {
+            invalid(&format!(
+                "The SoundFont has no {} chunk",
+                String::from_utf8_lossy(id)
+            ))
+        })?;
+        Ok(chunk.chunks_exact(size).collect())
+    };
+
+    let sample_headers: Vec<SampleHeader> = chunk(b"shdr", 46)?
+        .iter()
+        .map(|record| SampleHeader::read(record))
+        .collect();
+    let instrument_zones = read_zones(
+        &chunk(b"inst", 22)?,
+        20,
+        &chunk(b"ibag", 4)?,
+        &chunk(b"igen", 4)?,
+    );
+    let preset_headers = chunk(b"phdr", 38)?;
+    let preset_zones = read_zones(
+        &preset_headers,
+        24,
+        &chunk(b"pbag", 4)?,
+        &chunk(b"pgen", 4)?,
+    );
+
+    let mut presets = Vec::new();
+    for (header, zones) in preset_headers.iter().zip(preset_zones) {
+        let mut preset = SoundFontPreset {
+            name: read_name(&header[0..20]),
+            program: read_u16(header, 20),
+            bank: read_u16(header, 22),
+            zones: Vec::new(),
+        };
+        for preset_generators in with_global_zone(zones, INSTRUMENT) {
+            let instrument_generators = match preset_generators
+                .get(INSTRUMENT)
+                .and_then(|instrument| instrument_zones.get(instrument as u16 as usize))
+            {
+                Some(zones) => with_global_zone(zones.clone(), SAMPLE_ID),
+                None => continue,
+            };
+            preset
+                .zones
+                .extend(instrument_generators.iter().filter_map(|generators| {
+                    read_zone(&preset_generators, generators, &sample_headers)
+                }));
+        }
+        presets.push(preset);
+    }
+
+    // The last sample header only marks the end of the list
+    let num_samples = sample_headers.len().saturating_sub(1);
+    let samples = sample_headers
+        .iter()
+        .take(num_samples)
+        .map(|header| header.sample(smpl))
+        .collect();
+    Ok(SoundFont { presets, samples })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+// Names are padded with zeros to 20 bytes
+fn read_name(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).trim().to_string()
+}
+
+// Splits the contents of a RIFF list into its chunks, as (id, data) pairs
+fn chunks(mut data: &[u8]) -> Vec<(&[u8], &[u8])> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let size = read_u32(data, 4) as usize;
+        let end = 8 + size.min(data.len() - 8);
+        chunks.push((&data[0..4], &data[8..end]));
+        // Chunks with an odd size are followed by a padding byte
+        data = &data[(end + size % 2).min(data.len())..];
+    }
+    chunks
+}
+
+// The generators of a zone, which are the settings that SoundFonts store as numbered amounts
+#[derive(Clone, Copy, Debug)]
+struct Generators([Option<i16>; NUM_GENERATORS]);
+
+impl Generators {
+    fn get(&self, generator: usize) -> Option<i16> {
+        self.0[generator]
+    }
+
+    fn value(&self, generator: usize, default: i32) -> i32 {
+        self.get(generator).map_or(default, |amount| amount as i32)
+    }
+
+    // Ranges are stored as the lowest and highest value in the two bytes of the amount
+    fn range(&self, generator: usize) -> (u8, u8) {
+        match self.get(generator) {
+            Some(amount) => {
+                let [low, high] = (amount as u16).to_le_bytes();
+                (low, high)
+            }
+            None => (0, 127),
+        }
+    }
+
+    // The generators of a zone, with any that it doesn't set taken from the global zone
+    fn over(&self, global: &Generators) -> Generators {
+        let mut generators = *global;
+        for (generator, amount) in self.0.iter().enumerate() {
+            if amount.is_some() {
+                generators.0[generator] = *amount;
+            }
+        }
+        generators
+    }
+}
+
+// Reads the zones of every preset or instrument. Each header has the index of its first bag at the bag offset,
+// each bag has the index of its first generator, and the last header and bag only mark where the list ends.
+fn read_zones(
+    headers: &[&[u8]],
+    bag_offset: usize,
+    bags: &[&[u8]],
+    generators: &[&[u8]],
+) -> Vec<Vec<Generators>> {
+    let bag_start = |header: &[u8]| (read_u16(header, bag_offset) as usize).min(bags.len());
+    let generator_start = |bag: usize| match bags.get(bag) {
+        Some(bag) => (read_u16(bag, 0) as usize).min(generators.len()),
+        None => generators.len(),
+    };
+
+    headers
+        .windows(2)
+        .map(|pair| {
+            (bag_start(pair[0])..bag_start(pair[1]).max(bag_start(pair[0])))
+                .map(|bag| {
+                    let mut zone = Generators([None; NUM_GENERATORS]);
+                    let start = generator_start(bag);
+                    let end = generator_start(bag + 1).max(start);
+                    for record in &generators[start..end] {
+                        let generator = read_u16(record, 0) as usize;
+                        if generator < NUM_GENERATORS {
+                            zone.0[generator] = Some(read_u16(record, 2) as i16);
+                        }
+                    }
+                    zone
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// The first zone is a global zone if it doesn't end with the generator that every other zone ends with.
+// Its generators apply to every other zone that doesn't set them itself.
+// Zones without that generator are left out.
+fn with_global_zone(mut zones: Vec<Generators>, last_generator: usize) -> Vec<Generators> {
+    let global = match zones.first() {
+        Some(first) if first.get(last_generator).is_none() => Some(zones.remove(0)),
+        _ => None,
+    };
+    zones
+        .iter()
+        .filter(|zone| zone.get(last_generator).is_some())
+        .map(|zone| match global.as_ref() {
+            Some(global) => zone.over(global),
+            None => *zone,
+        })
+        .collect()
+}
+
+// Combines a preset zone with one of its instrument's zones. The preset's ranges narrow the instrument's,
+// and its other generators are added on top of the instrument's.
+fn read_zone(
+    preset: &Generators,
+    instrument: &Generators,
+    sample_headers: &[SampleHeader],
+) -> Option<SoundFontZone> {
+    let sample_index = instrument.get(SAMPLE_ID)? as u16 as usize;
+    // The last sample header only marks the end of the list
+    let header = sample_headers.get(sample_index)?;
+    if sample_index + 1 >= sample_headers.len()
+        || header.sample_type & (RIGHT_SAMPLE | ROM_SAMPLE) != 0
+    {
+        return None;
+    }
+
+    let (preset_low_key, preset_high_key) = preset.range(KEY_RANGE);
+    let (low_key, high_key) = instrument.range(KEY_RANGE);
+    let (preset_low_velocity, preset_high_velocity) = preset.range(VELOCITY_RANGE);
+    let (low_velocity, high_velocity) = instrument.range(VELOCITY_RANGE);
+    let (low_key, high_key) = (low_key.max(preset_low_key), high_key.min(preset_high_key));
+    let (low_velocity, high_velocity) = (
+        low_velocity.max(preset_low_velocity),
+        high_velocity.min(preset_high_velocity),
+    );
+    if low_key > high_key || low_velocity > high_velocity {
+        return None;
+    }
+
+    let add = |generator: usize, default: i32| {
+        instrument.value(generator, default) + preset.value(generator, 0)
+    };
+    let root_key = match instrument.get(ROOT_KEY) {
+        Some(key) if (0..=127).contains(&key) => key as u8,
+        _ if header.original_pitch <= 127 => header.original_pitch,
+        _ => 60,
+    };
+    let tune = add(COARSE_TUNE, 0) * 100 + add(FINE_TUNE, 0) + header.pitch_correction as i32;
+    let attenuation = add(ATTENUATION, 0).clamp(0, MAX_CENTIBELS);
+    // Loop points are stored from the start of all the samples, but zones count them from the start of their sample
+    let loop_point = |point: u32, offset: usize, coarse_offset: usize| {
+        let point = point as i64 - header.start as i64
+            + instrument.value(offset, 0) as i64
+            + instrument.value(coarse_offset, 0) as i64 * 32768;
+        point.clamp(0, u32::MAX as i64) as u32
+    };
+    let loop_start = loop_point(
+        header.start_loop,
+        START_LOOP_OFFSET,
+        START_LOOP_COARSE_OFFSET,
+    );
+    let loop_end = loop_point(header.end_loop, END_LOOP_OFFSET, END_LOOP_COARSE_OFFSET);
+    // Mode 1 loops all the time, and mode 3 loops until the note is released, which is played here as always looping
+    let looping = matches!(instrument.value(SAMPLE_MODES, 0) & 3, 1 | 3) && loop_end > 0;
+
+    let seconds = |generator: usize| {
+        2.0_f32.powf(add(generator, MIN_TIMECENTS).max(MIN_TIMECENTS) as f32 / 1200.0)
+    };
+    let envelope = Envelope::new(
+        seconds(ATTACK),
+        seconds(DECAY),
+        centibels_to_gain(add(SUSTAIN, 0).clamp(0, MAX_CENTIBELS)),
+        seconds(RELEASE),
+    );
+
+    Some(SoundFontZone {
+        sample_zone: SampleZone {
+            path: String::new(), // Filled in with the SoundFont's path when the zone is added to a patch
+            sample_index: Some(sample_index),
+            root_key,
+            low_key,
+            high_key,
+            low_velocity,
+            high_velocity,
+            looping,
+            loop_start,
+            loop_end,
+            tune: tune as f32,
+            gain: centibels_to_gain(attenuation),
+        },
+        envelope,
+    })
+}
+
+fn centibels_to_gain(centibels: i32) -> f32 {
+    10.0_f32.powf(-centibels as f32 / 200.0)
+}
+
+// Where a sample is in the sdta list, and how it should be played
+struct SampleHeader {
+    start: u32, // In samples from the start of the smpl chunk
+    end: u32,
+    start_loop: u32,
+    end_loop: u32,
+    sample_rate: u32,
+    original_pitch: u8, // The key the sample was recorded at, 255 if it isn't pitched
+    pitch_correction: i8, // In cents
+    sample_type: u16,
+}
+
+impl SampleHeader {
+    fn read(record: &[u8]) -> SampleHeader {
+        SampleHeader {
+            start: read_u32(record, 20),
+            end: read_u32(record, 24),
+            start_loop: read_u32(record, 28),
+            end_loop: read_u32(record, 32),
+            sample_rate: read_u32(record, 36),
+            original_pitch: record[40],
+            pitch_correction: record[41] as i8,
+            sample_type: read_u16(record, 44),
+        }
+    }
+
+    // Reads the sample out of the smpl chunk, which holds every sample as 16 bit mono
+    fn sample(&self, smpl: &[u8]) -> Sample {
+        let num_samples = smpl.len() / 2;
+        let start = (self.start as usize).min(num_samples);
+        let end = (self.end as usize).clamp(start, num_samples);
+        let data = if self.sample_type & ROM_SAMPLE != 0 {
+            Vec::new()
+        } else {
+            smpl[start * 2..end * 2]
+                .chunks_exact(2)
+                .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 32768.0)
+                .collect()
+        };
+        Sample::new(data, 1, self.sample_rate.max(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SINE_FRAMES: u32 = 1000;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((data.len() as u32).to_le_bytes());
+        chunk.extend(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn list(list_type: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = list_type.to_vec();
+        for chunk in chunks {
+            data.extend(chunk);
+        }
+        chunk(b"LIST", &data)
+    }
+
+    fn padded_name(name: &str) -> Vec<u8> {
+        let mut data = name.as_bytes().to_vec();
+        data.resize(20, 0);
+        data
+    }
+
+    fn generator(generator: usize, amount: i16) -> Vec<u8> {
+        let mut data = (generator as u16).to_le_bytes().to_vec();
+        data.extend(amount.to_le_bytes());
+        data
+    }
+
+    fn range(generator: usize, low: u8, high: u8) -> Vec<u8> {
+        let mut data = (generator as u16).to_le_bytes().to_vec();
+        data.extend([low, high]);
+        data
+    }
+
+    // A zone's first generator, with no modulators
+    fn bag(first_generator: u16) -> Vec<u8> {
+        let mut data = first_generator.to_le_bytes().to_vec();
+        data.extend(0u16.to_le_bytes());
+        data
+    }
+
+    fn sample_header(
+        name: &str,
+        start: u32,
+        end: u32,
+        loop_start: u32,
+        loop_end: u32,
+        sample_type: u16,
+    ) -> Vec<u8> {
+        let mut data = padded_name(name);
+        for value in [start, end, loop_start, loop_end, 44100] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([69, 0]); // The original pitch and the pitch correction
+        data.extend(0u16.to_le_bytes());
+        data.extend(sample_type.to_le_bytes());
+        data
+    }
+
+    fn instrument(name: &str, first_zone: u16) -> Vec<u8> {
+        let mut data = padded_name(name);
+        data.extend(first_zone.to_le_bytes());
+        data
+    }
+
+    fn preset_header(name: &str, program: u16, bank: u16, first_zone: u16) -> Vec<u8> {
+        let mut data = padded_name(name);
+        for value in [program, bank, first_zone] {
+            data.extend(value.to_le_bytes());
+        }
+        data.extend([0; 12]);
+        data
+    }
+
+    // A SoundFont with a looping sine instrument in program 0, a kit with a kick drum on key 36,
+    // and a preset in another bank which can't be reached
+    fn test_soundfont() -> Vec<u8> {
+        let mut samples: Vec<u8> = Vec::new();
+        let mut add_frames = |frames: Vec<i16>| {
+            for frame in frames {
+                samples.extend(frame.to_le_bytes());
+            }
+        };
+        add_frames(vec![0; 10]);
+        add_frames(
+            (0..SINE_FRAMES)
+                .map(|frame| {
+                    ((frame as f32 / 100.0 * std::f32::consts::TAU).sin() * 16000.0) as i16
+                })
+                .collect(),
+        );
+        add_frames(vec![0; 46]); // Samples are followed by at least 46 zero frames
+
+        let sample_headers = [
+            sample_header("Sine", 10, 10 + SINE_FRAMES, 110, 910, 1),
+            sample_header("Right", 10, 10 + SINE_FRAMES, 110, 910, RIGHT_SAMPLE),
+            sample_header("EOS", 0, 0, 0, 0, 0),
+        ]
+        .concat();
+
+        // The sine has a global zone with a one second attack, a looping zone which is 6 dB quieter,
+        // and a zone with the right half of a stereo pair. The drum has one zone on key 36.
+        let instrument_zones = [bag(0), bag(1), bag(5), bag(6), bag(9)].concat();
+        let instrument_generators = [
+            generator(ATTACK, 0),
+            range(KEY_RANGE, 0, 127),
+            generator(ATTENUATION, 60),
+            generator(SAMPLE_MODES, 1),
+            generator(SAMPLE_ID, 0),
+            generator(SAMPLE_ID, 1),
+            range(KEY_RANGE, 30, 40),
+            generator(ROOT_KEY, 36),
+            generator(SAMPLE_ID, 0),
+            generator(0, 0),
+        ]
+        .concat();
+        let instruments = [
+            instrument("Sine", 0),
+            instrument("Drum", 3),
+            instrument("EOI", 4),
+        ]
+        .concat();
+
+        let preset_zones = [bag(0), bag(2), bag(4), bag(5)].concat();
+        let preset_generators = [
+            range(KEY_RANGE, 0, 100),
+            generator(INSTRUMENT, 0),
+            range(KEY_RANGE, 36, 36),
+            generator(INSTRUMENT, 1),
+            generator(INSTRUMENT, 0),
+            generator(0, 0),
+        ]
+        .concat();
+        let preset_headers = [
+            preset_header("Sine", 0, 0, 0),
+            preset_header("Kit", 0, DRUM_BANK, 1),
+            preset_header("Other", 5, 1, 2),
+            preset_header("EOP", 0, 0, 3),
+        ]
+        .concat();
+
+        let info = list(
+            b"INFO",
+            &[chunk(b"ifil", &[2, 0, 1, 0]), chunk(b"INAM", b"Test\0")],
+        );
+        let sample_data = list(b"sdta", &[chunk(b"smpl", &samples)]);
+        let preset_data = list(
+            b"pdta",
+            &[
+                chunk(b"phdr", &preset_headers),
+                chunk(b"pbag", &preset_zones),
+                chunk(b"pmod", &[0; 10]),
+                chunk(b"pgen", &preset_generators),
+                chunk(b"inst", &instruments),
+                chunk(b"ibag", &instrument_zones),
+                chunk(b"imod", &[0; 10]),
+                chunk(b"igen", &instrument_generators),
+                chunk(b"shdr", &sample_headers),
+            ],
+        );
+        chunk(
+            b"RIFF",
+            &[b"sfbk".to_vec(), info, sample_data, preset_data].concat(),
+        )
+    }
+
+    #[test]
+    fn presets_are_read_with_their_zones() {
+        let soundfont = parse_soundfont(&test_soundfont()).unwrap();
+        assert_eq!(soundfont.samples.len(), 2);
+        assert_eq!(soundfont.samples[0].num_frames(), SINE_FRAMES as usize);
+        assert_eq!(soundfont.presets.len(), 3);
+
+        let sine = &soundfont.presets[0];
+        assert_eq!(sine.name, "Sine");
+        // The right half of the stereo pair is skipped
+        assert_eq!(sine.zones.len(), 1);
+        let zone = &sine.zones[0].sample_zone;
+        assert_eq!(zone.sample_index, Some(0));
+        // The preset's key range narrows the instrument's
+        assert_eq!((zone.low_key, zone.high_key), (0, 100));
+        assert_eq!(zone.root_key, 69);
+        assert!(zone.looping);
+        // Loops are relative to the start of the sample
+        assert_eq!((zone.loop_start, zone.loop_end), (100, 900));
+        assert!((zone.gain - 10.0_f32.powf(-0.3)).abs() < 1e-4);
+        // The global zone's attack applies to the zones after it
+        assert!((sine.zones[0].envelope.attack - 1.0).abs() < 1e-4);
+
+        let kit = &soundfont.presets[1];
+        assert_eq!(kit.zones.len(), 1);
+        let zone = &kit.zones[0].sample_zone;
+        assert_eq!((zone.low_key, zone.high_key), (36, 36));
+        assert_eq!(zone.root_key, 36);
+        assert!(!zone.looping);
+    }
+
+    #[test]
+    fn the_program_map_plays_the_first_bank_and_the_drum_kit() {
+        let soundfont = parse_soundfont(&test_soundfont()).unwrap();
+        let program_map = soundfont.program_map("test.sf2");
+        let sine = program_map.program(0);
+        assert!(matches!(sine.patch_type, PatchType::Sampler));
+        assert_eq!(sine.sampler.zones[0].path, "test.sf2");
+        // Program 5 is only in another bank, so it keeps the default patch
+        assert!(matches!(
+            program_map.program(5).patch_type,
+            PatchType::Subtractive
+        ));
+        assert_eq!(program_map.drums.len(), 1);
+        assert!(program_map.drum(36).is_some());
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        assert!(parse_soundfont(b"RIFF\0\0\0\0WAVE").is_err());
+        assert!(parse_soundfont(&test_soundfont()[..200]).is_err());
+    }
+}
@@ -1,140 +1,833 @@
-use std::collections::HashMap;
-use std::time::Instant;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use midly::live::LiveEvent;
+use midly::MidiMessage;
 use rodio::source::Source;
-use rodio::Sink;
-
-// The envelope struct
-pub struct Envelope {
-    attack: f32,
-    decay: f32,
-    sustain: f32,
-    release: f32,
+
+use crate::channel::{pan_gains, Channel, ChannelSettings, DRUM_CHANNEL, NUM_CHANNELS};
+use crate::lfo::Lfo;
+use crate::mod_matrix::{apply_to_patch, key_value, uses_source, ModSource, ModSources};
+use crate::mono::{choose_key, VoiceMode};
+use crate::patch::Patch;
+use crate::patch_files::{LoadedFiles, MissingFiles};
+use crate::polyphony::{choose_voice_to_steal, PolyphonySettings, VoiceStats, MAX_POLYPHONY};
+use crate::programs::ProgramMap;
+use crate::sampler::{Sample, Samples};
+use crate::soundfont::SoundFont;
+use crate::voice::{Voice, VoiceId};
+use crate::wavetable::{Wavetable, Wavetables};
+
+const HEADROOM: f32 = 0.25; // Scales the mix down so that several notes can play at once without clipping
+const BLOCK_SIZE: usize = 128; // The number of samples rendered each time the synth is locked by the audio thread
+
+// MIDI controller numbers
+const MOD_WHEEL: u8 = 1;
+const VOLUME: u8 = 7;
+const PAN: u8 = 10;
+const SUSTAIN_PEDAL: u8 = 64;
+
+// The synth mixes all of its voices into one stream of stereo samples.
+// It doesn't need an output device, so it can also be rendered offline.
+pub struct Synth {
+    voices: Vec<Voice>,
+    channels: Vec<Channel>, // One for each MIDI channel, notes use the patch of the channel they are played on
+    program_map: ProgramMap, // The patches that Program Change messages switch channels to
+    wavetables: Wavetables, // The wavetables used by the channels' patches and the program map
+    samples: Samples,       // The samples used by the channels' patches and the program map
+    next_voice_id: u64,
+    polyphony: PolyphonySettings,
+    stats: VoiceStats,
+    tempo: u32,       // In microseconds per beat, for LFOs that are synced to the tempo
+    sample_rate: u32, // Chosen to match the output device, or the file being rendered
 }
 
-impl Envelope {
-    pub fn new(attack: f32, decay: f32, sustain: f32, release: f32) -> Envelope {
-        Envelope {
-            attack,
-            decay,
-            sustain,
-            release,
+impl Synth {
+    pub fn new(sample_rate: u32) -> Synth {
+        Synth {
+            // Room for every voice is reserved up front, so the list itself doesn't grow while notes play.
+            // Each voice's sources are still boxed when its note starts.
+            voices: Vec::with_capacity(MAX_POLYPHONY),
+            channels: vec![Channel::default(); NUM_CHANNELS],
+            program_map: ProgramMap::default(),
+            wavetables: Wavetables::default(),
+            samples: Samples::default(),
+            next_voice_id: 0,
+            polyphony: PolyphonySettings::default(),
+            stats: VoiceStats::default(),
+            tempo: 500_000, // 120 bpm
+            sample_rate,
         }
     }
-}
 
-// The active note struct
-struct ActiveNote {
-    envelope: Envelope,
-    start_time: Instant,
-    is_releasing: bool,
-    time_released: Option<Instant>,
-    sink: Sink,
-}
+    // Responds to a MIDI message, this is used for both live input and MIDI files
+    pub fn handle_event(&mut self, event: LiveEvent) {
+        if let LiveEvent::Midi { channel, message } = event {
+            let channel = channel.as_int();
+            match message {
+                MidiMessage::NoteOn { key, vel } => {
+                    self.note_on(channel, key.as_int(), vel.as_int())
+                }
+                MidiMessage::NoteOff { key, vel: _ } => self.note_off(channel, key.as_int()),
+                MidiMessage::PitchBend { bend } => self.set_pitch_bend(channel, bend.as_f32()),
+                MidiMessage::Controller { controller, value } => {
+                    self.control_change(channel, controller.as_int(), value.as_int())
+                }
+                MidiMessage::ProgramChange { program } => {
+                    self.program_change(channel, program.as_int())
+                }
+                MidiMessage::ChannelAftertouch { vel } => {
+                    self.set_channel_pressure(channel, vel.as_int())
+                }
+                MidiMessage::Aftertouch { key, vel } => {
+                    self.set_key_pressure(channel, key.as_int(), vel.as_int())
+                }
+            }
+        }
+    }
+
+    // Plays a MIDI note on a channel, from 0 to 15.
+    // A note that is already playing keeps going, so that it can overlap with the new one.
+    pub fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        if self.is_mono(channel) {
+            let state = &mut self.channels[channel as usize];
+            state.held_keys.retain(|(held_key, _)| *held_key != key);
+            state.held_keys.push((key, velocity));
+            let priority = state.settings.patch.note_priority;
+            if let Some((key, velocity)) = choose_key(&state.held_keys, priority) {
+                self.play_mono(channel, key, velocity);
+            }
+        } else {
+            self.start_voice(channel, key, velocity, None);
+        }
+    }
+
+    // Starts a new voice, optionally gliding to its pitch from another key
+    fn start_voice(&mut self, channel: u8, key: u8, velocity: u8, glide_from: Option<u8>) {
+        let state = &self.channels[channel as usize];
+        let patch = if channel == DRUM_CHANNEL {
+            // Every key on the drum channel plays its own drum sound
+            match self.program_map.drum(key) {
+                Some(patch) => patch,
+                None => return,
+            }
+        } else {
+            &state.settings.patch
+        };
+        let id = VoiceId {
+            channel,
+            key,
+            id: self.next_voice_id,
+        };
+        self.next_voice_id += 1;
+        // The matrix slots that only change when a note starts are applied to a copy of the patch
+        let modulated;
+        let patch = if patch.mod_matrix.is_empty() {
+            patch
+        } else {
+            let sources = ModSources {
+                velocity: velocity as f32 / 127.0,
+                key: key_value(key),
+                mod_wheel: state.mod_wheel,
+                aftertouch: state.aftertouch,
+                pitch_bend: state.pitch_bend,
+                global_lfo: state.global_lfo.as_ref().map_or(0.0, |lfo| lfo.value()),
+                ..ModSources::default()
+            };
+            let mut patch = patch.clone();
+            apply_to_patch(&mut patch, &sources);
+            modulated = patch;
+            &modulated
+        };
+        let mut voice = Voice::new(
+            patch,
+            id,
+            velocity,
+            &self.wavetables,
+            &self.samples,
+            self.tempo,
+            self.sample_rate,
+        );
+        voice.set_pitch_bend(state.pitch_bend);
+        voice.set_mod_wheel(state.mod_wheel);
+        voice.set_channel_pressure(state.aftertouch);
+        if let Some(glide_from) = glide_from {
+            voice.glide_from(glide_from, patch.portamento);
+        }
 
-impl ActiveNote {
-    fn time_since_start(&self) -> f32 {
-        Instant::now().duration_since(self.start_time).as_secs_f32()
+        self.steal_voices(channel, key);
+        self.voices.push(voice);
+        self.stats.peak_voices = self.stats.peak_voices.max(self.voices.len());
     }
 
-    fn time_since_release(&self) -> Option<f32> {
-        self.time_released
-            .map(|time| Instant::now().duration_since(time).as_secs_f32())
+    fn is_mono(&self, channel: u8) -> bool {
+        channel != DRUM_CHANNEL
+            && self.channels[channel as usize].settings.patch.voice_mode != VoiceMode::Poly
     }
-}
 
-pub struct Synth {
-    active_notes: HashMap<u8, ActiveNote>,
-    stream_handle: rodio::OutputStreamHandle, // Required to create sinks
-}
+    // The voice a mono channel is playing, if it hasn't been released
+    fn mono_voice(&self, channel: u8) -> Option<usize> {
+        self.voices.iter().position(|voice| {
+            voice.id.channel == channel
+                && !voice.is_stolen()
+                && (voice.is_held() || voice.is_sustained())
+        })
+    }
 
-impl Synth {
-    pub fn new(stream_handle: rodio::OutputStreamHandle) -> Synth {
-        Synth {
-            active_notes: HashMap::new(),
-            stream_handle,
-        }
-    }
-
-    pub fn play_source(
-        &mut self,
-        audio_source: Box<dyn Source<Item = f32> + Send>, // This will likely be created with the Oscillator
-        source_id: u8, // This is to differentiate between different "sources", so that multiple can be played at once
-        envelope: Envelope, // The envelope will effect the volume of the audio source over time
-    ) {
-        // Sink is a handle to the audio output device
-        let sink = Sink::try_new(&self.stream_handle).expect("Failed to create sink");
-        sink.append(audio_source);
-
-        let active_note = ActiveNote {
-            envelope,
-            start_time: Instant::now(),
-            is_releasing: false,
-            time_released: None,
-            sink,
+    // Moves a mono channel on to a new key
+    fn play_mono(&mut self, channel: u8, key: u8, velocity: u8) {
+        let state = &self.channels[channel as usize];
+        let patch = &state.settings.patch;
+        let (voice_mode, portamento) = (patch.voice_mode, patch.portamento);
+        let last_key = state.last_key;
+
+        match self.mono_voice(channel) {
+            Some(i) if self.voices[i].id.key == key => {}
+            Some(i) if voice_mode == VoiceMode::Legato => self.voices[i].glide_to(key, portamento),
+            Some(i) => {
+                // Restart the envelope with a new voice, and fade out the old one
+                let old_key = self.voices[i].id.key;
+                self.voices[i].steal();
+                self.start_voice(channel, key, velocity, Some(old_key));
+            }
+            None => self.start_voice(channel, key, velocity, last_key),
+        }
+        self.channels[channel as usize].last_key = Some(key);
+    }
+
+    fn mono_note_off(&mut self, channel: u8, key: u8) {
+        let state = &mut self.channels[channel as usize];
+        state.held_keys.retain(|(held_key, _)| *held_key != key);
+        let next = choose_key(&state.held_keys, state.settings.patch.note_priority);
+        let sustain = state.sustain;
+
+        let i = match self.mono_voice(channel) {
+            Some(i) if self.voices[i].id.key == key => i,
+            _ => return, // The key wasn't the one sounding
         };
+        match next {
+            // Go back to one of the keys that is still held
+            Some((key, velocity)) => self.play_mono(channel, key, velocity),
+            None if sustain => self.voices[i].sustain(),
+            None => self.voices[i].release(),
+        }
+    }
+
+    // Steals voices until there is room for a new note under the polyphony limit
+    fn steal_voices(&mut self, channel: u8, key: u8) {
+        let mut playing = self
+            .voices
+            .iter()
+            .filter(|voice| !voice.is_stolen())
+            .count();
+        let mut priorities = [0; NUM_CHANNELS];
+        for (priority, state) in priorities.iter_mut().zip(self.channels.iter()) {
+            *priority = state.settings.priority;
+        }
+        while playing >= self.polyphony.max_voices {
+            let policy = self.polyphony.steal_policy;
+            match choose_voice_to_steal(&self.voices, policy, channel, key, &priorities) {
+                Some(i) => self.voices[i].steal(),
+                None => break,
+            }
+            self.stats.stolen_voices += 1;
+            playing -= 1;
+        }
+    }
 
-        self.active_notes.insert(source_id, active_note);
+    pub fn note_off(&mut self, channel: u8, key: u8) {
+        // Drums ignore note off and play their whole sound, unless it would keep going until the key is let go
+        if channel == DRUM_CHANNEL && !self.program_map.drum(key).map_or(false, Patch::sustains) {
+            return;
+        }
+        if self.is_mono(channel) {
+            self.mono_note_off(channel, key);
+            return;
+        }
+        // Release the oldest voice still held on the key, so that overlapping notes are released in the order they were played
+        let voice = self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.id.channel == channel && voice.id.key == key && voice.is_held())
+            .min_by_key(|voice| voice.id.id);
+        if let Some(voice) = voice {
+            if self.channels[channel as usize].sustain {
+                voice.sustain();
+            } else {
+                voice.release();
+            }
+        }
     }
 
-    pub fn release_source(&mut self, source_id: u8) {
-        if let Some(active_note) = self.active_notes.get_mut(&source_id) {
-            active_note.is_releasing = true;
-            active_note.time_released = Some(Instant::now());
+    fn control_change(&mut self, channel: u8, controller: u8, value: u8) {
+        let state = &mut self.channels[channel as usize];
+        match controller {
+            MOD_WHEEL => {
+                state.mod_wheel = value as f32 / 127.0;
+                let mod_wheel = state.mod_wheel;
+                for voice in self
+                    .voices
+                    .iter_mut()
+                    .filter(|voice| voice.id.channel == channel)
+                {
+                    voice.set_mod_wheel(mod_wheel);
+                }
+            }
+            VOLUME => state.settings.volume = value as f32 / 127.0,
+            // 64 is the centre
+            PAN => state.settings.pan = ((value as f32 - 64.0) / 63.0).max(-1.0),
+            SUSTAIN_PEDAL => {
+                // Values of 64 and above mean the pedal is down
+                state.sustain = value >= 64;
+                if !state.sustain {
+                    for voice in self.voices.iter_mut() {
+                        if voice.id.channel == channel && voice.is_sustained() {
+                            voice.release();
+                        }
+                    }
+                }
+            }
+            _ => {}
         }
     }
 
-    pub fn update(&mut self) {
-        let mut to_remove = Vec::new();
+    // Switches a channel to the patch for a program, the drum channel only has one kit so it ignores this
+    fn program_change(&mut self, channel: u8, program: u8) {
+        if channel != DRUM_CHANNEL {
+            self.channels[channel as usize].settings.patch = self.program_map.program(program);
+        }
+    }
 
-        for (source_id, active_note) in self.active_notes.iter_mut() {
-            let elapsed = active_note.time_since_start();
+    // Bends every note on a channel, the bend is from -1 to 1 and is scaled by the patch's pitch bend range
+    fn set_pitch_bend(&mut self, channel: u8, bend: f32) {
+        let state = &mut self.channels[channel as usize];
+        state.pitch_bend = bend;
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.id.channel == channel)
+        {
+            voice.set_pitch_bend(bend);
+        }
+    }
 
-            let envelope = &active_note.envelope;
+    // Aftertouch for every note on a channel, which can be used in the modulation matrix
+    fn set_channel_pressure(&mut self, channel: u8, pressure: u8) {
+        let pressure = pressure as f32 / 127.0;
+        self.channels[channel as usize].aftertouch = pressure;
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.id.channel == channel)
+        {
+            voice.set_channel_pressure(pressure);
+        }
+    }
 
-            let volume = if elapsed < envelope.attack {
-                // Attack
-                elapsed / envelope.attack
-            } else if elapsed < envelope.attack + envelope.decay {
-                // Decay
-                1.0 - (elapsed - envelope.attack) / envelope.decay * (1.0 - envelope.sustain)
-            } else if active_note.is_releasing {
-                // Release
-                let time_since_release = active_note
-                    .time_since_release()
-                    .unwrap_or(0.0)
-                    .min(envelope.release);
-                // Linear interpolation between sustain and 0
-                // envelope.sustain - time_since_release / envelope.release * envelope.sustain
-                // Use the ease out quint function instead of linear interpolation
-                envelope.sustain - ease_out_quint(time_since_release / envelope.release) * envelope.sustain
-            } else {
-                // Sustain
-                envelope.sustain
-            };
+    // Aftertouch for a single key, which only affects the notes playing on that key
+    fn set_key_pressure(&mut self, channel: u8, key: u8, pressure: u8) {
+        let pressure = pressure as f32 / 127.0;
+        for voice in self
+            .voices
+            .iter_mut()
+            .filter(|voice| voice.id.channel == channel && voice.id.key == key)
+        {
+            voice.set_key_pressure(pressure);
+        }
+    }
 
-            active_note.sink.set_volume(volume);
+    // Fills the buffer with the next samples of the mix, the left and right samples are interleaved
+    pub fn render(&mut self, buffer: &mut [f32]) {
+        let mut gains = [(0.0, 0.0); NUM_CHANNELS];
+        for (gain, channel) in gains.iter_mut().zip(self.channels.iter()) {
+            *gain = channel.settings.gains();
+        }
+        self.update_global_lfos();
+
+        let mut global_lfo_values = [0.0; NUM_CHANNELS];
+        for frame in buffer.chunks_mut(2) {
+            for (value, channel) in global_lfo_values.iter_mut().zip(self.channels.iter_mut()) {
+                if let Some(lfo) = channel.global_lfo.as_mut() {
+                    *value = lfo.next_value();
+                }
+            }
 
-            if active_note.is_releasing {
-                if active_note.time_since_release().unwrap_or(0.0) >= envelope.release + 0.1 {
-                    let time_since_release = active_note
-                    .time_since_release()
-                    .unwrap_or(0.0)
-                    .min(envelope.release);
-                    println!("{}", envelope.sustain - ease_out_quint(time_since_release / envelope.release) * envelope.sustain);
-                    to_remove.push(*source_id);
+            let mut left = 0.0;
+            let mut right = 0.0;
+            let mut i = 0;
+            while i < self.voices.len() {
+                let voice = &mut self.voices[i];
+                let channel = voice.id.channel as usize;
+                voice.set_global_lfo(global_lfo_values[channel]);
+                match voice.next() {
+                    Some((left_value, right_value)) => {
+                        let (left_gain, right_gain) = if voice.pan() == 0.0 {
+                            gains[channel]
+                        } else {
+                            let settings = &self.channels[channel].settings;
+                            pan_gains(settings.volume, settings.pan + voice.pan())
+                        };
+                        left += left_value * left_gain;
+                        right += right_value * right_gain;
+                        i += 1;
+                    }
+                    None => {
+                        // The voice has finished its release, so it can be removed
+                        self.voices.swap_remove(i);
+                    }
                 }
             }
+            // Soft clip anything that still goes over the headroom
+            frame[0] = (left * HEADROOM).tanh();
+            if let Some(sample) = frame.get_mut(1) {
+                *sample = (right * HEADROOM).tanh();
+            }
+        }
+    }
+
+    // Starts the global LFOs that have been turned on, and stops the ones that have been turned off.
+    // An LFO with no amount of its own is still used when the modulation matrix reads from it.
+    fn update_global_lfos(&mut self) {
+        for (i, channel) in self.channels.iter_mut().enumerate() {
+            let patch = &channel.settings.patch;
+            let settings = &patch.global_lfo;
+            if settings.amount == 0.0 && !uses_source(&patch.mod_matrix, ModSource::GlobalLfo) {
+                channel.global_lfo = None;
+            } else if channel.global_lfo.as_ref().map(|lfo| lfo.settings()) != Some(settings) {
+                // Each channel is seeded by its number, so their sample and hold LFOs don't move together
+                let lfo = Lfo::new(settings.clone(), self.tempo, i as u32, self.sample_rate);
+                channel.global_lfo = Some(lfo);
+            }
         }
+    }
 
-        for source_id in to_remove {
-            // This is done as a seperate loop to avoid a second mutable borrow of self.active_notes
-            // First borrow is when .iter_mut() is called, second is when .remove() is called
-            self.active_notes.remove(&source_id);
+    // Sets the tempo that synced LFOs follow, in microseconds per beat like in MIDI files.
+    // Notes that are already playing keep the tempo they started with.
+    pub fn set_tempo(&mut self, tempo: u32) {
+        self.tempo = tempo;
+        for channel in self.channels.iter_mut() {
+            if let Some(lfo) = channel.global_lfo.as_mut() {
+                lfo.set_tempo(tempo);
+            }
         }
     }
+
+    pub fn channel_settings(&self, channel: u8) -> &ChannelSettings {
+        &self.channels[channel as usize].settings
+    }
+
+    // Changes a channel's settings, notes that are already playing keep their old sound but move to the new volume and pan.
+    // The files the patch uses should be added first, parts of it that use files the synth doesn't have are silent.
+    pub fn set_channel_settings(&mut self, channel: u8, settings: ChannelSettings) {
+        self.channels[channel as usize].settings = settings;
+    }
+
+    pub fn patch(&self, channel: u8) -> &Patch {
+        &self.channels[channel as usize].settings.patch
+    }
+
+    // Changes the patch used for new notes on a channel, the files it uses should be added first
+    pub fn set_patch(&mut self, channel: u8, patch: Patch) {
+        self.channels[channel as usize].settings.patch = patch;
+    }
+
+    pub fn program_map(&self) -> &ProgramMap {
+        &self.program_map
+    }
+
+    // The files the program map's patches use should be added first
+    pub fn set_program_map(&mut self, program_map: ProgramMap) {
+        self.program_map = program_map;
+    }
+
+    // Adds a wavetable that has already been loaded, so that patches can use it without loading it again
+    pub fn add_wavetable(&mut self, path: String, wavetable: Wavetable) {
+        self.wavetables.insert(path, wavetable);
+    }
+
+    // Adds a sample that has already been loaded, so that patches can use it without loading it again
+    pub fn add_sample(&mut self, path: String, sample: Sample) {
+        self.samples.insert(path, sample);
+    }
+
+    // Switches the program map to play a SoundFont that has already been loaded from the path
+    pub fn set_soundfont(&mut self, path: &str, soundfont: SoundFont) {
+        let program_map = soundfont.program_map(path);
+        self.samples
+            .insert_soundfont(path.to_string(), soundfont.samples);
+        self.set_program_map(program_map);
+    }
+
+    // The files that the patches use which haven't been added to the synth yet.
+    // They can be loaded with MissingFiles::load while the synth is unlocked, and then added with add_files.
+    pub fn missing_files<'a>(&self, patches: impl IntoIterator<Item = &'a Patch>) -> MissingFiles {
+        let mut missing = MissingFiles::default();
+        for patch in patches {
+            for path in self.wavetables.missing(patch) {
+                missing.add_wavetable(path);
+            }
+            for path in self.samples.missing(patch) {
+                missing.add_sample(path);
+            }
+            for path in self.samples.missing_soundfonts(patch) {
+                missing.add_soundfont(path);
+            }
+        }
+        missing
+    }
+
+    pub fn add_files(&mut self, files: LoadedFiles) {
+        for (path, wavetable) in files.wavetables {
+            match wavetable {
+                Some(wavetable) => self.wavetables.insert(path, wavetable),
+                None => self.wavetables.insert_missing(path),
+            }
+        }
+        for (path, sample) in files.samples {
+            match sample {
+                Some(sample) => self.samples.insert(path, sample),
+                None => self.samples.insert_missing(path),
+            }
+        }
+        for (path, samples) in files.soundfonts {
+            match samples {
+                Some(samples) => self.samples.insert_soundfont(path, samples),
+                None => self.samples.insert_missing_soundfont(path),
+            }
+        }
+    }
+
+    // Loads the files that the patches use straight away, for synths that aren't shared with the audio thread
+    pub fn load_files<'a>(&mut self, patches: impl IntoIterator<Item = &'a Patch>) {
+        let files = self.missing_files(patches).load();
+        self.add_files(files);
+    }
+
+    pub fn polyphony(&self) -> &PolyphonySettings {
+        &self.polyphony
+    }
+
+    // The limit applies to new notes, if it is lowered the extra voices are stolen when the next note is played
+    pub fn set_polyphony(&mut self, mut polyphony: PolyphonySettings) {
+        polyphony.max_voices = polyphony.max_voices.clamp(1, MAX_POLYPHONY);
+        self.polyphony = polyphony;
+    }
+
+    pub fn stats(&self) -> VoiceStats {
+        VoiceStats {
+            active_voices: self.voices.len(),
+            ..self.stats.clone()
+        }
+    }
+
+    // The number of voices still playing, including ones that are releasing
+    pub fn voice_count(&self) -> usize {
+        self.voices.len()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+// Plays a shared synth through rodio.
+// The synth is locked once per block rather than once per sample, so that sending it notes doesn't hold up the audio.
+pub struct SynthSource {
+    synth: Arc<Mutex<Synth>>,
+    buffer: Vec<f32>,
+    position: usize,
+    sample_rate: u32,
 }
 
-fn ease_out_quint(x: f32) -> f32 {
-    1.0 - (1.0 - x).powf(5.0)
-}
\ No newline at end of file
+impl SynthSource {
+    pub fn new(synth: Arc<Mutex<Synth>>) -> SynthSource {
+        let sample_rate = synth.lock().unwrap().sample_rate();
+        SynthSource {
+            synth,
+            buffer: vec![0.0; BLOCK_SIZE],
+            position: BLOCK_SIZE,
+            sample_rate,
+        }
+    }
+}
+
+impl Iterator for SynthSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position == self.buffer.len() {
+            self.synth.lock().unwrap().render(&mut self.buffer);
+            self.position = 0;
+        }
+        let sample = self.buffer[self.position];
+        self.position += 1;
+        Some(sample)
+    }
+}
+
+impl Source for SynthSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        2 // Stereo, the left and right samples are interleaved
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None // The synth plays until the app is closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::envelope::Envelope;
+    use crate::oscillator::WaveType;
+    use crate::oscillator_bank::single_oscillator;
+    use crate::patch::PatchType;
+    use crate::sampler::{SampleZone, SamplerSettings};
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    fn render_seconds(synth: &mut Synth, seconds: f32) -> Vec<f32> {
+        let mut buffer = vec![0.0; (seconds * SAMPLE_RATE as f32) as usize * 2];
+        synth.render(&mut buffer);
+        buffer
+    }
+
+    // A sine wave that holds its volume for as long as the key is held
+    fn sine_patch() -> Patch {
+        Patch {
+            oscillators: single_oscillator(WaveType::Sine),
+            envelope: Envelope::new(0.0, 0.0, 1.0, 0.0),
+            ..Patch::default()
+        }
+    }
+
+    // The frequency of the left channel, from how often it crosses 0 going up
+    fn frequency(buffer: &[f32]) -> f32 {
+        let left: Vec<f32> = buffer.iter().step_by(2).copied().collect();
+        let crossings = left
+            .windows(2)
+            .filter(|values| values[0] < 0.0 && values[1] >= 0.0)
+            .count();
+        crossings as f32 * SAMPLE_RATE as f32 / left.len() as f32
+    }
+
+    // A drum kit with a looping sample on key 36 and a one-shot sample on key 38
+    fn sampled_drums(synth: &mut Synth) {
+        synth.add_sample(
+            "drum.wav".to_string(),
+            Sample::new(vec![0.5; SAMPLE_RATE as usize / 10], 1, SAMPLE_RATE),
+        );
+        let drum = |looping| Patch {
+            patch_type: PatchType::Sampler,
+            sampler: SamplerSettings {
+                zones: vec![SampleZone {
+                    path: "drum.wav".to_string(),
+                    looping,
+                    ..SampleZone::default()
+                }],
+            },
+            envelope: Envelope::new(0.0, 10.0, 0.0, 0.0),
+            ..Patch::default()
+        };
+        let mut program_map = ProgramMap::default();
+        program_map.drums.insert(36, drum(true));
+        program_map.drums.insert(38, drum(false));
+        synth.set_program_map(program_map);
+    }
+
+    #[test]
+    fn looping_drums_are_released_by_note_off() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        sampled_drums(&mut synth);
+        synth.note_on(DRUM_CHANNEL, 36, 100);
+        render_seconds(&mut synth, 0.5);
+        assert_eq!(synth.voice_count(), 1);
+        synth.note_off(DRUM_CHANNEL, 36);
+        render_seconds(&mut synth, 0.1);
+        assert_eq!(synth.voice_count(), 0);
+    }
+
+    #[test]
+    fn one_shot_drums_play_out_after_note_off() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        sampled_drums(&mut synth);
+        synth.note_on(DRUM_CHANNEL, 38, 100);
+        synth.note_off(DRUM_CHANNEL, 38);
+        render_seconds(&mut synth, 0.05);
+        assert_eq!(synth.voice_count(), 1);
+        // It ends by itself at the end of the sample, which is played slower than the root key
+        render_seconds(&mut synth, 1.0);
+        assert_eq!(synth.voice_count(), 0);
+    }
+
+    #[test]
+    fn the_sustain_pedal_holds_notes_until_it_is_let_go() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_patch(0, sine_patch());
+        synth.note_on(0, 60, 100);
+        synth.control_change(0, SUSTAIN_PEDAL, 127);
+        synth.note_off(0, 60);
+        render_seconds(&mut synth, 0.5);
+        assert_eq!(synth.voice_count(), 1);
+        assert!(synth.voices[0].is_sustained());
+
+        synth.control_change(0, SUSTAIN_PEDAL, 0);
+        render_seconds(&mut synth, 0.1);
+        assert_eq!(synth.voice_count(), 0);
+    }
+
+    #[test]
+    fn pitch_bends_are_scaled_by_the_bend_range() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_patch(
+            0,
+            Patch {
+                pitch_bend_range: 12.0,
+                ..sine_patch()
+            },
+        );
+        synth.note_on(0, 57, 100); // 220 Hz
+        let unbent = frequency(&render_seconds(&mut synth, 1.0));
+        synth.set_pitch_bend(0, 1.0);
+        let bent_up = frequency(&render_seconds(&mut synth, 1.0));
+        synth.set_pitch_bend(0, -0.5);
+        let bent_down = frequency(&render_seconds(&mut synth, 1.0));
+        assert!((unbent - 220.0).abs() <= 2.0, "{}", unbent);
+        assert!((bent_up - 440.0).abs() <= 2.0, "{}", bent_up);
+        assert!(
+            (bent_down - 110.0 * 2.0_f32.sqrt()).abs() <= 2.0,
+            "{}",
+            bent_down
+        );
+    }
+
+    // The loudest sample on the left and the right
+    fn peaks(buffer: &[f32]) -> (f32, f32) {
+        let peak = |channel: usize| {
+            buffer
+                .iter()
+                .skip(channel)
+                .step_by(2)
+                .fold(0.0_f32, |peak, sample| peak.max(sample.abs()))
+        };
+        (peak(0), peak(1))
+    }
+
+    #[test]
+    fn channels_are_panned_by_their_gains() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        for channel in 0..3 {
+            synth.set_patch(channel, sine_patch());
+            synth.note_on(channel, 60, 100);
+        }
+        // Channel 0 is panned with Control Change, channel 1 with its settings, and channel 2 stays in the centre
+        synth.control_change(0, PAN, 0);
+        let mut settings = synth.channel_settings(1).clone();
+        settings.pan = 1.0;
+        synth.set_channel_settings(1, settings);
+        let levels: Vec<(f32, f32)> = (0..3)
+            .map(|channel| synth.channel_settings(channel).gains())
+            .collect();
+        assert_eq!(levels[0].1, 0.0);
+        assert!(levels[1].0.abs() < 1e-6);
+        assert!((levels[2].0 - levels[2].1).abs() < 1e-6);
+        // Constant power, so panning doesn't change the loudness
+        for (left, right) in levels {
+            assert!((left * left + right * right - 1.0).abs() < 1e-5);
+        }
+
+        // Channel 0 alone is only heard on the left
+        synth.note_off(1, 60);
+        synth.note_off(2, 60);
+        render_seconds(&mut synth, 0.1);
+        let (left, right) = peaks(&render_seconds(&mut synth, 0.1));
+        assert!(left > 0.01);
+        assert_eq!(right, 0.0);
+    }
+
+    #[test]
+    fn events_only_change_their_own_channel() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_patch(1, sine_patch());
+        synth.set_patch(2, sine_patch());
+        synth.note_on(1, 60, 100);
+        synth.note_on(2, 60, 100);
+        synth.control_change(2, SUSTAIN_PEDAL, 127);
+        synth.set_pitch_bend(2, 1.0);
+        synth.control_change(2, MOD_WHEEL, 127);
+        synth.program_change(2, 40);
+        synth.note_off(2, 60);
+        synth.note_off(1, 60);
+
+        let channel = &synth.channels[1];
+        assert!(!channel.sustain);
+        assert_eq!(channel.pitch_bend, 0.0);
+        assert_eq!(channel.mod_wheel, 0.0);
+        assert!(matches!(
+            channel.settings.patch.oscillators[0].wave_type,
+            WaveType::Sine
+        ));
+        // Channel 1's note is released without waiting for the pedal on channel 2
+        render_seconds(&mut synth, 0.1);
+        assert_eq!(synth.voice_count(), 1);
+        assert_eq!(synth.voices[0].id.channel, 2);
+    }
+
+    #[test]
+    fn the_same_key_can_overlap_itself() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_patch(0, sine_patch());
+        synth.note_on(0, 60, 100);
+        synth.note_on(0, 60, 100);
+        assert_eq!(synth.voice_count(), 2);
+
+        // The first note to start is the first to be released
+        synth.note_off(0, 60);
+        let held: Vec<u64> = synth
+            .voices
+            .iter()
+            .filter(|voice| voice.is_held())
+            .map(|voice| voice.id.id)
+            .collect();
+        assert_eq!(held, vec![1]);
+        synth.note_off(0, 60);
+        render_seconds(&mut synth, 0.1);
+        assert_eq!(synth.voice_count(), 0);
+    }
+
+    #[test]
+    fn the_mix_stays_between_minus_one_and_one() {
+        let mut synth = Synth::new(SAMPLE_RATE);
+        let loud = Patch {
+            oscillators: single_oscillator(WaveType::Square),
+            gain: 4.0,
+            ..sine_patch()
+        };
+        for channel in 0..4 {
+            synth.set_patch(channel, loud.clone());
+            for key in 60..72 {
+                synth.note_on(channel, key, 127);
+            }
+        }
+        let buffer = render_seconds(&mut synth, 0.5);
+        assert!(buffer.iter().all(|sample| (-1.0..=1.0).contains(sample)));
+        let (left, right) = peaks(&buffer);
+        assert!(left > 0.5 && right > 0.5);
+
+        // A single note is scaled down by the headroom, leaving room for others
+        let mut synth = Synth::new(SAMPLE_RATE);
+        synth.set_patch(0, sine_patch());
+        synth.note_on(0, 69, 127);
+        let (left, _) = peaks(&render_seconds(&mut synth, 0.1));
+        let expected = (HEADROOM / 2.0_f32.sqrt()).tanh(); // The centre pan is down 3 dB on each side
+        assert!((left - expected).abs() < 0.02, "{}", left);
+    }
+}
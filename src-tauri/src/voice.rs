@@ -0,0 +1,360 @@
+// This file is for the Voice struct, which is a single note being played by the synth.
+// Voices are built from a patch, and keep hold of their oscillators so that they can be retuned while they play.
+
+use std::f32::consts::PI;
+use std::time::Duration;
+
+use rodio::source::{Amplify, Source};
+
+use crate::envelope::{EnvelopeHandle, Enveloped};
+use crate::filter::Filtered;
+use crate::fm::FmOperators;
+use crate::lfo::{Lfo, LfoSettings};
+use crate::mod_matrix::{key_value, ModSlot, ModSources, Modulation};
+use crate::oscillator_bank::OscillatorBank;
+use crate::patch::{Patch, PatchType};
+use crate::sampler::{SamplePlayer, Samples};
+use crate::wavetable::Wavetables;
+
+const STEAL_FADE_SECONDS: f32 = 0.005; // Long enough to not click, short enough to make room for the new note straight away
+
+// Identifies a voice by the channel and key that played it, along with a number that counts up with every note.
+// Playing the same key again makes a new voice, so the old one can finish its release underneath it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoiceId {
+    pub channel: u8,
+    pub key: u8,
+    pub id: u64,
+}
+
+// The part of a voice that makes the sound, before the filter and envelope, which depends on the patch type
+enum Generator {
+    Oscillators(OscillatorBank),
+    Fm(FmOperators),
+    Sampler(SamplePlayer),
+}
+
+impl Generator {
+    fn frequency(&self) -> f32 {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.frequency(),
+            Generator::Fm(operators) => operators.frequency(),
+            Generator::Sampler(player) => player.frequency(),
+        }
+    }
+
+    fn set_frequency(&mut self, freq: f32) {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.set_frequency(freq),
+            Generator::Fm(operators) => operators.set_frequency(freq),
+            Generator::Sampler(player) => player.set_frequency(freq),
+        }
+    }
+
+    fn glide_to(&mut self, freq: f32, seconds: f32) {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.glide_to(freq, seconds),
+            Generator::Fm(operators) => operators.glide_to(freq, seconds),
+            Generator::Sampler(player) => player.glide_to(freq, seconds),
+        }
+    }
+
+    fn set_pitch_bend(&mut self, semitones: f32) {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.set_pitch_bend(semitones),
+            Generator::Fm(operators) => operators.set_pitch_bend(semitones),
+            Generator::Sampler(player) => player.set_pitch_bend(semitones),
+        }
+    }
+}
+
+impl Iterator for Generator {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.next(),
+            Generator::Fm(operators) => operators.next(),
+            Generator::Sampler(player) => player.next(),
+        }
+    }
+}
+
+impl Source for Generator {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.channels(),
+            Generator::Fm(operators) => operators.channels(),
+            Generator::Sampler(player) => player.channels(),
+        }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self {
+            Generator::Oscillators(oscillators) => oscillators.sample_rate(),
+            Generator::Fm(operators) => operators.sample_rate(),
+            Generator::Sampler(player) => player.sample_rate(),
+        }
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None // Will continue indefinitely until stopped
+    }
+}
+
+pub struct Voice {
+    pub id: VoiceId,
+    source: Enveloped<Filtered<Amplify<Generator>>>,
+    envelope: EnvelopeHandle,
+    sustained: bool, // Set when the note is released while the sustain pedal is down
+    amplitude: f32, // The volume of the note before the envelope, from its velocity and the patch's gain
+    steal_fade: Option<f32>, // Set once the voice has been stolen, this is the volume it is fading out from
+    steal_fade_step: f32,    // How much the volume drops each sample while the voice is fading out
+    velocity: f32,           // From 0 to 1
+    pitch_bend: f32,         // From -1 to 1
+    pitch_bend_range: f32,   // In semitones
+    mod_wheel: f32,          // From 0 to 1
+    channel_pressure: f32,   // Aftertouch for the whole channel, from 0 to 1
+    key_pressure: f32,       // Aftertouch for just this key, from 0 to 1
+    vibrato_depth: f32,      // In semitones, when the mod wheel is all the way up
+    vibrato_phase: f32,      // How far through the current cycle the vibrato is, from 0 to 1
+    vibrato_increment: f32,  // How far the vibrato phase moves each sample
+    applied_pitch: f32,      // The pitch change last given to the oscillators, in semitones
+    applied_shape: (f32, f32), // The pulse width and wavetable position modulation last given to the oscillators
+    lfo: Lfo,
+    global_lfo: LfoSettings, // The channel's global LFO runs in the synth, and its value is passed in every sample
+    global_lfo_value: f32,
+    mod_matrix: Vec<ModSlot>, // Only the slots that are modulated every sample
+    pan: f32, // Moves the voice from its channel's pan, set by the LFOs and the modulation matrix
+}
+
+impl Voice {
+    // The tempo is in microseconds per beat, for LFOs that are synced to it
+    pub fn new(
+        patch: &Patch,
+        id: VoiceId,
+        velocity: u8,
+        wavetables: &Wavetables,
+        samples: &Samples,
+        tempo: u32,
+        sample_rate: u32,
+    ) -> Voice {
+        let hz = key_to_hz(patch.fixed_key.unwrap_or(id.key));
+        let amplitude = velocity as f32 / 127.0 * patch.gain;
+
+        let generator = match patch.patch_type {
+            PatchType::Subtractive => Generator::Oscillators(OscillatorBank::new(
+                hz,
+                &patch.oscillators,
+                &patch.unison,
+                wavetables,
+                id.id as u32,
+                sample_rate,
+            )),
+            PatchType::Fm => Generator::Fm(FmOperators::new(hz, &patch.fm, sample_rate)),
+            PatchType::Sampler => {
+                let zone = patch.sampler.zone(id.key, velocity);
+                let sample = zone.and_then(|zone| samples.get(zone));
+                Generator::Sampler(SamplePlayer::new(hz, zone, sample, sample_rate))
+            }
+        };
+        let audio_source = patch.filter.apply(generator.amplify(amplitude), hz);
+        let (source, envelope) = patch.envelope.apply(audio_source);
+
+        Voice {
+            id,
+            source,
+            envelope,
+            sustained: false,
+            amplitude,
+            steal_fade: None,
+            steal_fade_step: 1.0 / (STEAL_FADE_SECONDS * sample_rate as f32),
+            velocity: velocity as f32 / 127.0,
+            pitch_bend: 0.0,
+            pitch_bend_range: patch.pitch_bend_range,
+            mod_wheel: 0.0,
+            channel_pressure: 0.0,
+            key_pressure: 0.0,
+            vibrato_depth: patch.vibrato_depth,
+            vibrato_phase: 0.0,
+            vibrato_increment: patch.vibrato_rate / sample_rate as f32,
+            applied_pitch: 0.0,
+            applied_shape: (0.0, 0.0),
+            // Every note gets its own seed, so that sample and hold LFOs on a chord don't move together
+            lfo: Lfo::new(patch.voice_lfo.clone(), tempo, id.id as u32, sample_rate),
+            global_lfo: patch.global_lfo.clone(),
+            global_lfo_value: 0.0,
+            mod_matrix: patch
+                .mod_matrix
+                .iter()
+                .filter(|slot| slot.destination.is_continuous())
+                .cloned()
+                .collect(),
+            pan: 0.0,
+        }
+    }
+
+    pub fn release(&mut self) {
+        self.sustained = false;
+        self.envelope.release();
+        self.source.inner_mut().release();
+        if let Generator::Fm(operators) = self.generator() {
+            operators.release();
+        }
+    }
+
+    // Holds the note until the sustain pedal is lifted, instead of releasing it now
+    pub fn sustain(&mut self) {
+        self.sustained = true;
+    }
+
+    pub fn is_sustained(&self) -> bool {
+        self.sustained
+    }
+
+    // Starts the note at another key's pitch and slides to its own, used for portamento
+    pub fn glide_from(&mut self, key: u8, seconds: f32) {
+        let generator = self.generator();
+        let target = generator.frequency();
+        generator.set_frequency(key_to_hz(key));
+        generator.glide_to(target, seconds);
+    }
+
+    // Slides the voice to a new key without restarting its envelope, used for legato.
+    // The voice now belongs to the new key, so it is held again even if the sustain pedal was holding it.
+    pub fn glide_to(&mut self, key: u8, seconds: f32) {
+        self.id.key = key;
+        self.sustained = false;
+        self.generator().glide_to(key_to_hz(key), seconds);
+    }
+
+    // Fades the voice out quickly, so that another note can use its place
+    pub fn steal(&mut self) {
+        if self.steal_fade.is_none() {
+            self.steal_fade = Some(1.0);
+        }
+    }
+
+    pub fn is_stolen(&self) -> bool {
+        self.steal_fade.is_some()
+    }
+
+    // How loud the voice is right now, used to find the quietest voice
+    pub fn level(&self) -> f32 {
+        self.source.level() * self.amplitude * self.steal_fade.unwrap_or(1.0)
+    }
+
+    // Whether the key is still held down, rather than released or only held by the sustain pedal
+    pub fn is_held(&self) -> bool {
+        !self.sustained && !self.envelope.is_released()
+    }
+
+    // The bend is from -1 to 1, and is scaled by the patch's pitch bend range
+    pub fn set_pitch_bend(&mut self, bend: f32) {
+        self.pitch_bend = bend;
+    }
+
+    // The mod wheel adds vibrato, and can be used in the modulation matrix
+    pub fn set_mod_wheel(&mut self, value: f32) {
+        self.mod_wheel = value;
+    }
+
+    pub fn set_channel_pressure(&mut self, pressure: f32) {
+        self.channel_pressure = pressure;
+    }
+
+    pub fn set_key_pressure(&mut self, pressure: f32) {
+        self.key_pressure = pressure;
+    }
+
+    // Sets the value of the channel's global LFO for the next sample, from -1 to 1
+    pub fn set_global_lfo(&mut self, value: f32) {
+        self.global_lfo_value = value;
+    }
+
+    // How far the LFOs have moved the voice from its channel's pan
+    pub fn pan(&self) -> f32 {
+        self.pan
+    }
+
+    fn generator(&mut self) -> &mut Generator {
+        self.source.inner_mut().inner_mut().inner_mut()
+    }
+}
+
+fn key_to_hz(key: u8) -> f32 {
+    440.0 * 2.0_f32.powf((key as f32 - 69.0) / 12.0)
+}
+
+impl Iterator for Voice {
+    type Item = (f32, f32);
+
+    // Returns the next frame as (left, right), or None once the note has finished releasing
+    fn next(&mut self) -> Option<(f32, f32)> {
+        let voice_lfo = self.lfo.next_value();
+        let mut modulation = Modulation::default();
+        if !self.mod_matrix.is_empty() {
+            let sources = ModSources {
+                amp_envelope: self.source.level(),
+                filter_envelope: self.source.inner_mut().envelope_level(),
+                voice_lfo,
+                global_lfo: self.global_lfo_value,
+                velocity: self.velocity,
+                key: key_value(self.id.key),
+                mod_wheel: self.mod_wheel,
+                aftertouch: self.channel_pressure.max(self.key_pressure),
+                pitch_bend: self.pitch_bend,
+            };
+            modulation.add_matrix(&self.mod_matrix, &sources);
+        }
+        let voice_lfo_amount = modulation.voice_lfo_amount;
+        modulation.add_lfo(self.lfo.settings(), voice_lfo, voice_lfo_amount);
+        let global_lfo_amount = modulation.global_lfo_amount;
+        modulation.add_lfo(&self.global_lfo, self.global_lfo_value, global_lfo_amount);
+
+        let mut semitones = self.pitch_bend * self.pitch_bend_range + modulation.pitch;
+        let vibrato_depth = self.mod_wheel * self.vibrato_depth;
+        if vibrato_depth != 0.0 {
+            semitones += (2.0 * PI * self.vibrato_phase).sin() * vibrato_depth;
+            self.vibrato_phase = (self.vibrato_phase + self.vibrato_increment).fract();
+        }
+        // Working out the pitch is slow, so it is only done when it changes
+        if semitones != self.applied_pitch {
+            self.applied_pitch = semitones;
+            self.generator().set_pitch_bend(semitones);
+        }
+        let shape = (modulation.pulse_width, modulation.wavetable_position);
+        if shape != self.applied_shape {
+            self.applied_shape = shape;
+            if let Generator::Oscillators(oscillators) = self.generator() {
+                oscillators.set_shape_modulation(shape.0, shape.1);
+            }
+        }
+        let filter = self.source.inner_mut();
+        filter.set_cutoff_modulation(modulation.cutoff);
+        filter.set_resonance_modulation(modulation.resonance);
+        self.pan = modulation.pan;
+
+        let fade = match self.steal_fade {
+            Some(fade) if fade <= 0.0 => return None,
+            Some(fade) => {
+                self.steal_fade = Some(fade - self.steal_fade_step);
+                fade
+            }
+            None => 1.0,
+        };
+        let gain = fade * modulation.amplitude;
+        // Voices are mono unless unison spreads them across the stereo field, or they play a stereo sample
+        let left = self.source.next()?;
+        let right = if self.source.channels() == 2 {
+            self.source.next()?
+        } else {
+            left
+        };
+        Some((left * gain, right * gain))
+    }
+}
@@ -0,0 +1,353 @@
+// This file is for wavetables, single cycle waves loaded from WAV files which the oscillators can play.
+// A wavetable can hold several frames, each one a different cycle, and the oscillator morphs between them by
+// moving its position through the table. Each frame is stored at several levels of band limiting, so that high
+// notes can play a copy without the harmonics that would alias.
+
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::patch::Patch;
+
+// The length of one frame in the common multi-frame wavetable format
+pub const FRAME_SIZE: usize = 2048;
+pub const MAX_FRAMES: usize = 256;
+
+// Each level keeps half as many harmonics as the one before, from FRAME_SIZE / 2 down to just the fundamental
+const NUM_LEVELS: usize = 11;
+// Levels with few harmonics are stored in fewer samples, but never so few that the interpolation is rough
+const MIN_LEVEL_SIZE: usize = 64;
+
+#[derive(Clone, Debug)]
+pub struct Wavetable {
+    levels: Vec<Vec<Vec<f32>>>, // The frames of the table for each level of band limiting
+}
+
+impl Wavetable {
+    // Builds a wavetable from frames of FRAME_SIZE samples
+    pub fn from_frames(frames: &[Vec<f32>]) -> Wavetable {
+        // Every frame is scaled by the same amount, so that morphing between them doesn't change the volume
+        let peak = frames
+            .iter()
+            .flatten()
+            .fold(0.0_f32, |peak, sample| peak.max(sample.abs()));
+        let scale = if peak > 0.0 { 1.0 / peak } else { 1.0 };
+
+        let mut levels = vec![Vec::new(); NUM_LEVELS];
+        for frame in frames {
+            let mut re: Vec<f32> = frame.iter().map(|sample| sample * scale).collect();
+            re.resize(FRAME_SIZE, 0.0);
+            let mut im = vec![0.0; FRAME_SIZE];
+            fft(&mut re, &mut im, false);
+
+            for (level, tables) in levels.iter_mut().enumerate() {
+                let harmonics = (FRAME_SIZE / 2) >> level;
+                let size = (harmonics * 4).clamp(MIN_LEVEL_SIZE, FRAME_SIZE);
+                let mut level_re = vec![0.0; size];
+                let mut level_im = vec![0.0; size];
+                // The DC offset is left out, along with every harmonic above the level's limit
+                for harmonic in 1..=harmonics.min(size / 2 - 1) {
+                    level_re[harmonic] = re[harmonic];
+                    level_im[harmonic] = im[harmonic];
+                    level_re[size - harmonic] = re[FRAME_SIZE - harmonic];
+                    level_im[size - harmonic] = im[FRAME_SIZE - harmonic];
+                }
+                fft(&mut level_re, &mut level_im, true);
+                tables.push(
+                    level_re
+                        .iter()
+                        .map(|sample| sample / FRAME_SIZE as f32)
+                        .collect(),
+                );
+            }
+        }
+        Wavetable { levels }
+    }
+
+    pub fn num_frames(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    // The sample at a point in the cycle. The position is from 0 for the first frame to 1 for the last,
+    // and the phase increment picks the level with as many harmonics as the note can play without aliasing.
+    pub fn sample(&self, position: f32, phase: f32, phase_increment: f32) -> f32 {
+        let num_frames = self.num_frames();
+        if num_frames == 0 {
+            return 0.0;
+        }
+        let level = if phase_increment > 0.0 {
+            ((FRAME_SIZE as f32 * phase_increment).log2().ceil().max(0.0) as usize)
+                .min(NUM_LEVELS - 1)
+        } else {
+            0
+        };
+        let frames = &self.levels[level];
+
+        let position = position.clamp(0.0, 1.0) * (num_frames - 1) as f32;
+        let frame = position as usize;
+        let fraction = position - frame as f32;
+        let sample = read_frame(&frames[frame], phase);
+        if fraction > 0.0 && frame + 1 < num_frames {
+            sample + (read_frame(&frames[frame + 1], phase) - sample) * fraction
+        } else {
+            sample
+        }
+    }
+}
+
+// Reads a frame at a phase from 0 to 1, interpolating between the samples either side
+fn read_frame(frame: &[f32], phase: f32) -> f32 {
+    let position = phase * frame.len() as f32;
+    let index = position as usize % frame.len();
+    let next = (index + 1) % frame.len();
+    let fraction = position.fract();
+    frame[index] + (frame[next] - frame[index]) * fraction
+}
+
+// Loads a wavetable from a WAV file. Files made of whole frames of FRAME_SIZE samples are split into frames,
+// anything else is treated as a single cycle and stretched to fit one frame. Stereo files are mixed down to mono.
+pub fn load_wavetable(path: &Path) -> io::Result<Wavetable> {
+    let mut reader = hound::WavReader::open(path).map_err(hound_error)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = 1.0 / (1_u64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|sample| sample.map(|sample| sample as f32 * scale))
+                .collect()
+        }
+    }
+    .map_err(hound_error)?;
+
+    let channels = spec.channels.max(1) as usize;
+    let mono: Vec<f32> = samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    if mono.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "The wavetable has no samples",
+        ));
+    }
+
+    let frames: Vec<Vec<f32>> = if mono.len() % FRAME_SIZE == 0 {
+        mono.chunks(FRAME_SIZE)
+            .take(MAX_FRAMES)
+            .map(|frame| frame.to_vec())
+            .collect()
+    } else {
+        vec![(0..FRAME_SIZE)
+            .map(|i| read_frame(&mono, i as f32 / FRAME_SIZE as f32))
+            .collect()]
+    };
+    Ok(Wavetable::from_frames(&frames))
+}
+
+fn hound_error(error: hound::Error) -> io::Error {
+    match error {
+        hound::Error::IoError(error) => error,
+        error => io::Error::new(io::ErrorKind::InvalidData, error.to_string()),
+    }
+}
+
+// The wavetables used by the synth's patches, keyed by their path.
+// Tables are loaded before a patch is set rather than when a note starts, so that notes don't wait for the disk.
+#[derive(Default)]
+pub struct Wavetables {
+    tables: HashMap<String, Option<Arc<Wavetable>>>, // None for files that couldn't be loaded, so they aren't tried again
+}
+
+impl Wavetables {
+    pub fn get(&self, path: &str) -> Option<Arc<Wavetable>> {
+        self.tables.get(path).cloned().flatten()
+    }
+
+    pub fn insert(&mut self, path: String, wavetable: Wavetable) {
+        self.tables.insert(path, Some(Arc::new(wavetable)));
+    }
+
+    // Remembers a file that couldn't be loaded, so that patches using it play silence
+    pub fn insert_missing(&mut self, path: String) {
+        self.tables.entry(path).or_insert(None);
+    }
+
+    // The wavetables that the patch uses which haven't been loaded, or tried, yet
+    pub fn missing(&self, patch: &Patch) -> Vec<String> {
+        patch
+            .oscillators
+            .iter()
+            .filter_map(|oscillator| oscillator.wavetable.as_ref())
+            .filter(|path| !self.tables.contains_key(*path))
+            .cloned()
+            .collect()
+    }
+}
+
+// An in place radix 2 fast Fourier transform, the length must be a power of 2.
+// The inverse transform isn't scaled, so it returns the original samples multiplied by the length.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    // Put the samples in bit reversed order
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let b_re = re[b] * cos - im[b] * sin;
+                let b_im = re[b] * sin + im[b] * cos;
+                re[b] = re[a] - b_re;
+                im[b] = im[a] - b_im;
+                re[a] += b_re;
+                im[a] += b_im;
+            }
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One cycle of a sawtooth, which has every harmonic
+    fn sawtooth(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| 2.0 * i as f32 / len as f32 - 1.0)
+            .collect()
+    }
+
+    // The magnitude of each harmonic in a frame, up to half its length
+    fn harmonics(frame: &[f32]) -> Vec<f32> {
+        let mut re = frame.to_vec();
+        let mut im = vec![0.0; frame.len()];
+        fft(&mut re, &mut im, false);
+        (0..=frame.len() / 2)
+            .map(|i| (re[i] * re[i] + im[i] * im[i]).sqrt() / frame.len() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn the_inverse_fft_gives_back_the_samples() {
+        let samples: Vec<f32> = (0..256)
+            .map(|i| ((i * 37) % 19) as f32 / 19.0 - 0.5)
+            .collect();
+        let mut re = samples.clone();
+        let mut im = vec![0.0; samples.len()];
+        fft(&mut re, &mut im, false);
+        fft(&mut re, &mut im, true);
+        for (original, result) in samples.iter().zip(&re) {
+            assert!((original - result / samples.len() as f32).abs() < 1e-4);
+        }
+        assert!(im
+            .iter()
+            .all(|value| (value / samples.len() as f32).abs() < 1e-4));
+    }
+
+    #[test]
+    fn each_level_stops_at_its_harmonic_limit() {
+        let wavetable = Wavetable::from_frames(&[sawtooth(FRAME_SIZE)]);
+        for (level, frames) in wavetable.levels.iter().enumerate() {
+            let limit = (FRAME_SIZE / 2) >> level;
+            let magnitudes = harmonics(&frames[0]);
+            assert!(magnitudes[1] > 0.1, "level {} lost its fundamental", level);
+            for (harmonic, magnitude) in magnitudes.iter().enumerate().skip(limit + 1) {
+                assert!(
+                    *magnitude < 1e-4,
+                    "level {} has harmonic {} at {}",
+                    level,
+                    harmonic,
+                    magnitude
+                );
+            }
+        }
+        // The highest level is left with just the fundamental, so it can't alias at any pitch
+        let top = harmonics(&wavetable.levels[NUM_LEVELS - 1][0]);
+        assert!(top.iter().skip(2).all(|magnitude| *magnitude < 1e-4));
+    }
+
+    #[test]
+    fn notes_play_levels_without_harmonics_above_nyquist() {
+        let wavetable = Wavetable::from_frames(&[sawtooth(FRAME_SIZE)]);
+        for phase_increment in [0.02, 0.05, 0.1, 0.2, 0.3, 0.45] {
+            // The phases land on the samples of every level, so the spectrum is the level's own
+            let cycle: Vec<f32> = (0..64)
+                .map(|i| wavetable.sample(0.0, i as f32 / 64.0, phase_increment))
+                .collect();
+            let nyquist = 0.5 / phase_increment;
+            let magnitudes = harmonics(&cycle);
+            assert!(magnitudes[1] > 0.1);
+            for (harmonic, magnitude) in magnitudes.iter().enumerate() {
+                if harmonic as f32 > nyquist {
+                    assert!(
+                        *magnitude < 1e-4,
+                        "harmonic {} is at {} for a phase increment of {}",
+                        harmonic,
+                        magnitude,
+                        phase_increment
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wavs_that_arent_whole_frames_are_stretched_to_one_frame() {
+        let path =
+            std::env::temp_dir().join(format!("ui_synth_wavetable_{}.wav", std::process::id()));
+        let write = |samples: &[f32]| {
+            let spec = hound::WavSpec {
+                channels: 1,
+                sample_rate: 44100,
+                bits_per_sample: 32,
+                sample_format: hound::SampleFormat::Float,
+            };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for sample in samples {
+                writer.write_sample(*sample).unwrap();
+            }
+            writer.finalize().unwrap();
+        };
+
+        // 3000 samples of one sine cycle, at half volume
+        let sine: Vec<f32> = (0..3000)
+            .map(|i| 0.5 * (2.0 * PI * i as f32 / 3000.0).sin())
+            .collect();
+        write(&sine);
+        let wavetable = load_wavetable(&path).unwrap();
+        assert_eq!(wavetable.num_frames(), 1);
+        assert_eq!(wavetable.levels[0][0].len(), FRAME_SIZE);
+        assert!((wavetable.sample(0.0, 0.25, 0.0) - 1.0).abs() < 0.01);
+        assert!((wavetable.sample(0.0, 0.75, 0.0) + 1.0).abs() < 0.01);
+
+        // Whole frames are kept as they are
+        write(&[sawtooth(FRAME_SIZE), sine[..FRAME_SIZE].to_vec()].concat());
+        assert_eq!(load_wavetable(&path).unwrap().num_frames(), 2);
+
+        write(&[]);
+        assert!(load_wavetable(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -6,17 +6,18 @@ if (window.__TAURI__) {
 
 async function open_midi_connection() {
   if (window.__TAURI__) {
-    await invoke("open_midi_connection");
-  }
-}
-
-async function update_synth() {  
-  if (window.__TAURI__) {
-    await invoke("update_synth");
+    return await invoke("open_midi_connection").catch((error) => {
+      console.log("Error opening midi connection: " + error);
+      return [];
+    });
   }
+  return [];
 }
 
 import {midi_player} from './midi_player.js';
+import {patch_editor} from './patch_editor.js';
+import {midi_ports} from './midi_ports.js';
+import {polyphony} from './polyphony.js';
 
 let computer_keyboard_keys = [
   "a",
@@ -37,9 +38,8 @@ let computer_keyboard_keys = [
   "p",
 ];
 
-window.addEventListener("DOMContentLoaded", () => {
-  open_midi_connection();
-  update_synth();
+window.addEventListener("DOMContentLoaded", async () => {
+  const ports = await open_midi_connection();
 
   // Get body element
   const body = document.querySelector("body");
@@ -109,8 +109,18 @@ window.addEventListener("DOMContentLoaded", () => {
       // console.log(event);
       // console.log("MIDI message received!")
       // console.log(event.payload.message[1]);
-      const key = document.querySelector(`.k${event.payload.message[1]}`);
-      if (event.payload.message[0] == 144) {
+      const message = event.payload.message;
+      // The top 4 bits of the status byte are the message type, the bottom 4 are the channel
+      const status = message[0] & 0xF0;
+      if (status != 0x90 && status != 0x80) {
+        return;
+      }
+      const key = document.querySelector(`.k${message[1]}`);
+      if (key == null) {
+        return;
+      }
+      // A note on with a velocity of 0 is a note off
+      if (status == 0x90 && message[2] > 0) {
         key.classList.add("pressed");
       } else {
         key.classList.remove("pressed");
@@ -119,6 +129,9 @@ window.addEventListener("DOMContentLoaded", () => {
   }
 
   midi_player();
+  patch_editor();
+  midi_ports(ports);
+  polyphony();
 });
 
 document.addEventListener("keypress", function(event) {
@@ -10,11 +10,27 @@ if (window.__TAURI__) {
 	}
   }
 
+  async function load_soundfont() {
+	if (window.__TAURI__) {
+	  await invoke("load_soundfont").catch((error) => {
+		console.log("Error loading SoundFont: " + error);
+	  });
+	}
+  }
+
   async function play_arrangement() {  
 	if (window.__TAURI__) {
 	  await invoke("play_arrangement");
 	}
   }
+
+  async function render_arrangement(format, sample_rate) {
+	if (window.__TAURI__) {
+	  await invoke("render_arrangement", { format: format, sampleRate: sample_rate }).catch((error) => {
+		console.log("Error rendering arrangement: " + error);
+	  });
+	}
+  }
   
   let line_microseconds = 0;
   let last_line_time = Date.now();
@@ -45,6 +61,40 @@ if (window.__TAURI__) {
 	button.addEventListener("click", () => {
 	  fild_upload();
 	});
+	// Create a button to play MIDI files with the instruments of a SoundFont
+	const soundfont_button = document.createElement("button");
+	soundfont_button.innerHTML = "Load SoundFont";
+	widget.appendChild(soundfont_button);
+	soundfont_button.addEventListener("click", () => {
+	  load_soundfont();
+	});
+
+	// Create a select for the sample format of exported WAV files
+	const format_select = document.createElement("select");
+	for (const [value, label] of [["int16", "16-bit"], ["int24", "24-bit"], ["float32", "32-bit float"]]) {
+	  const option = document.createElement("option");
+	  option.value = value;
+	  option.innerHTML = label;
+	  format_select.appendChild(option);
+	}
+	widget.appendChild(format_select);
+	// Create a select for the sample rate of exported WAV files
+	const sample_rate_select = document.createElement("select");
+	for (const sample_rate of [44100, 48000, 96000]) {
+	  const option = document.createElement("option");
+	  option.value = sample_rate;
+	  option.innerHTML = `${sample_rate / 1000} kHz`;
+	  sample_rate_select.appendChild(option);
+	}
+	sample_rate_select.value = 48000;
+	widget.appendChild(sample_rate_select);
+	// Create a button to export the loaded midi file as a WAV file
+	const export_button = document.createElement("button");
+	export_button.innerHTML = "Export WAV";
+	widget.appendChild(export_button);
+	export_button.addEventListener("click", () => {
+	  render_arrangement(format_select.value, parseInt(sample_rate_select.value));
+	});
 
 	//Create a progress bar inside of the midi_player widget
 	const progress_bar = document.createElement("progress");
@@ -0,0 +1,74 @@
+if (window.__TAURI__) {
+  var { invoke } = window.__TAURI__.tauri;
+  var { listen } = window.__TAURI__.event;
+}
+
+async function connect_midi_port(name) {
+  if (window.__TAURI__) {
+    return await invoke("connect_midi_port", { name: name }).catch((error) => {
+      console.log("Error connecting to midi port: " + error);
+      return null;
+    });
+  }
+  return null;
+}
+
+async function disconnect_midi_port(name) {
+  if (window.__TAURI__) {
+    return await invoke("disconnect_midi_port", { name: name });
+  }
+  return null;
+}
+
+// Show a checkbox for every midi port, ticked if the synth is listening to it
+function update_port_list(port_list, ports) {
+  if (ports == null) {
+    return;
+  }
+  port_list.innerHTML = "";
+  if (ports.length == 0) {
+    port_list.innerHTML = "No midi ports found";
+  }
+  for (const port of ports) {
+    const label = document.createElement("label");
+    const checkbox = document.createElement("input");
+    checkbox.setAttribute("type", "checkbox");
+    checkbox.checked = port.connected;
+    checkbox.addEventListener("change", async () => {
+      if (checkbox.checked) {
+        update_port_list(port_list, await connect_midi_port(port.name));
+      } else {
+        update_port_list(port_list, await disconnect_midi_port(port.name));
+      }
+    });
+    label.appendChild(checkbox);
+    label.appendChild(document.createTextNode(port.name));
+    port_list.appendChild(label);
+  }
+}
+
+export function midi_ports(ports) {
+  // Get body element
+  const body = document.querySelector("body");
+  // Create a new div inside the body with the class widget-container
+  const widget_container = document.createElement("div");
+  widget_container.classList.add("widget-container");
+  body.appendChild(widget_container);
+  // Create a new tag to label the widget
+  const widget_label = document.createElement("h2");
+  widget_label.innerHTML = "Midi Inputs";
+  widget_container.appendChild(widget_label);
+  // Create a new div inside the body with the class widget
+  const widget = document.createElement("div");
+  widget.classList.add("midi_ports");
+  widget_container.appendChild(widget);
+
+  update_port_list(widget, ports);
+
+  if (window.__TAURI__) {
+    // The list is sent whenever a port is plugged in or unplugged
+    listen("midi_ports_changed", (event) => {
+      update_port_list(widget, event.payload);
+    });
+  }
+}
@@ -0,0 +1,500 @@
+if (window.__TAURI__) {
+  var { invoke } = window.__TAURI__.tauri;
+}
+
+// The MIDI channel being edited, from 0 to 15, and its settings
+let channel = 0;
+let settings = null;
+let patch = null;
+// Functions which update the controls to match the patch, used after a preset is loaded
+const refresh_controls = [];
+
+async function get_channel() {
+  if (window.__TAURI__) {
+    settings = await invoke("get_channel", { channel: channel }).catch((error) => {
+      console.log("Error getting channel: " + error);
+      return null;
+    });
+    patch = settings ? settings.patch : null;
+  }
+}
+
+async function set_patch() {
+  if (window.__TAURI__) {
+    settings.patch = patch;
+    await invoke("set_channel", { channel: channel, settings: settings }).catch((error) => {
+      console.log("Error setting channel: " + error);
+    });
+  }
+}
+
+async function list_presets() {
+  if (window.__TAURI__) {
+    return await invoke("list_presets").catch((error) => {
+      console.log("Error listing presets: " + error);
+      return [];
+    });
+  }
+  return [];
+}
+
+async function save_preset(name) {
+  if (window.__TAURI__) {
+    await invoke("save_preset", { channel: channel, name: name }).catch((error) => {
+      console.log("Error saving preset: " + error);
+    });
+  }
+}
+
+async function load_preset(name) {
+  if (window.__TAURI__) {
+    await invoke("load_preset", { channel: channel, name: name }).then((loaded_patch) => {
+      patch = loaded_patch;
+      refresh_controls.forEach((refresh) => refresh());
+    }).catch((error) => {
+      console.log("Error loading preset: " + error);
+    });
+  }
+}
+
+// Makes Program Change messages for a program number switch to the patch
+async function set_program(program) {
+  if (window.__TAURI__) {
+    await invoke("set_program", { program: program, patch: patch }).catch((error) => {
+      console.log("Error setting program: " + error);
+    });
+  }
+}
+
+async function delete_preset(name) {
+  if (window.__TAURI__) {
+    await invoke("delete_preset", { name: name }).catch((error) => {
+      console.log("Error deleting preset: " + error);
+    });
+  }
+}
+
+// Fill the preset select with the presets that have been saved
+async function update_preset_select(preset_select) {
+  const names = await list_presets();
+  preset_select.innerHTML = "";
+  for (const name of names) {
+    const option = document.createElement("option");
+    option.value = name;
+    option.innerHTML = name;
+    preset_select.appendChild(option);
+  }
+}
+
+// Create a select which edits a setting in the patch, the options are [value, name] pairs
+function create_select(parent, options, get_value, set_value) {
+  const select = document.createElement("select");
+  for (const [value, name] of options) {
+    const option = document.createElement("option");
+    option.value = value;
+    option.innerHTML = name;
+    select.appendChild(option);
+  }
+  select.value = get_value();
+  refresh_controls.push(() => select.value = get_value());
+  select.addEventListener("change", () => {
+    set_value(select.value);
+    set_patch();
+  });
+  parent.appendChild(select);
+}
+
+// Create a labelled slider which edits a number in the patch
+function create_slider(parent, label_text, min, max, step, get_value, set_value) {
+  const label = document.createElement("label");
+  label.innerHTML = label_text;
+  const slider = document.createElement("input");
+  slider.setAttribute("type", "range");
+  slider.setAttribute("min", min);
+  slider.setAttribute("max", max);
+  slider.setAttribute("step", step);
+  slider.value = get_value();
+  refresh_controls.push(() => slider.value = get_value());
+  slider.addEventListener("input", () => {
+    set_value(parseFloat(slider.value));
+    set_patch();
+  });
+  label.appendChild(slider);
+  parent.appendChild(label);
+}
+
+// Create a labelled checkbox which edits a setting in the patch
+function create_checkbox(parent, label_text, get_value, set_value) {
+  const label = document.createElement("label");
+  const checkbox = document.createElement("input");
+  checkbox.setAttribute("type", "checkbox");
+  checkbox.checked = get_value();
+  refresh_controls.push(() => checkbox.checked = get_value());
+  checkbox.addEventListener("change", () => {
+    set_value(checkbox.checked);
+    set_patch();
+  });
+  label.appendChild(checkbox);
+  label.appendChild(document.createTextNode(label_text));
+  parent.appendChild(label);
+}
+
+// Create the controls for one of the patch's LFOs, the name is the LFO's setting in the patch
+function create_lfo_controls(parent, label_text, name) {
+  const heading = document.createElement("h3");
+  heading.innerHTML = label_text;
+  parent.appendChild(heading);
+  create_select(parent, [["Sine", "Sine"], ["Square", "Square"], ["Sawtooth", "Sawtooth"], ["Triangle", "Triangle"], ["SampleAndHold", "Sample and Hold"]], () => patch[name].shape, (value) => patch[name].shape = value);
+  create_select(parent, [["Pitch", "Pitch"], ["Cutoff", "Cutoff"], ["Amplitude", "Amplitude"], ["Pan", "Pan"], ["PulseWidth", "Pulse Width"], ["WavetablePosition", "Wavetable Position"]], () => patch[name].destination, (value) => patch[name].destination = value);
+  create_slider(parent, "Rate", 0.01, 20, 0.01, () => patch[name].rate, (value) => patch[name].rate = value);
+  create_checkbox(parent, "Sync to Tempo", () => patch[name].sync, (value) => patch[name].sync = value);
+  create_slider(parent, "Beats", 0.25, 16, 0.25, () => patch[name].beats, (value) => patch[name].beats = value);
+  // The amount is in semitones for pitch and octaves for cutoff, so it goes higher than the other destinations need
+  create_slider(parent, "Amount", 0, 12, 0.01, () => patch[name].amount, (value) => patch[name].amount = value);
+}
+
+const mod_sources = [["AmpEnvelope", "Amp Envelope"], ["FilterEnvelope", "Filter Envelope"], ["VoiceLfo", "Voice LFO"], ["GlobalLfo", "Global LFO"], ["Velocity", "Velocity"], ["Key", "Key"], ["ModWheel", "Mod Wheel"], ["Aftertouch", "Aftertouch"], ["PitchBend", "Pitch Bend"]];
+const mod_destinations = [["Pitch", "Pitch"], ["Cutoff", "Cutoff"], ["Resonance", "Resonance"], ["Amplitude", "Amplitude"], ["Pan", "Pan"], ["PulseWidth", "Pulse Width"], ["WavetablePosition", "Wavetable Position"], ["VoiceLfoAmount", "Voice LFO Amount"], ["GlobalLfoAmount", "Global LFO Amount"], ["Gain", "Gain"], ["Attack", "Attack"], ["Decay", "Decay"], ["Sustain", "Sustain"], ["Release", "Release"], ["FilterEnvelopeAmount", "Filter Envelope"], ["FilterKeyTracking", "Key Tracking"], ["FilterAttack", "Filter Attack"], ["FilterDecay", "Filter Decay"], ["FilterSustain", "Filter Sustain"], ["FilterRelease", "Filter Release"], ["VoiceLfoRate", "Voice LFO Rate"], ["VibratoRate", "Vibrato Rate"], ["VibratoDepth", "Vibrato Depth"], ["PitchBendRange", "Bend Range"], ["Portamento", "Portamento"], ["OscillatorDetune", "Oscillator Detune"], ["UnisonDetune", "Unison Detune"], ["UnisonSpread", "Unison Spread"], ["FmRatio1", "FM Ratio 1"], ["FmRatio2", "FM Ratio 2"], ["FmRatio3", "FM Ratio 3"], ["FmRatio4", "FM Ratio 4"], ["FmLevel1", "FM Level 1"], ["FmLevel2", "FM Level 2"], ["FmLevel3", "FM Level 3"], ["FmLevel4", "FM Level 4"]];
+
+// Asks the user for a WAV file for an oscillator to play, returns the path or null if nothing was loaded
+async function load_wavetable(oscillator) {
+  if (window.__TAURI__) {
+    return await invoke("load_wavetable", { channel: channel, oscillator: oscillator }).catch((error) => {
+      console.log("Error loading wavetable: " + error);
+      return null;
+    });
+  }
+  return null;
+}
+
+// Asks the user for an audio file and adds it to the patch as a new sample zone, returns the zone or null if nothing was loaded
+async function add_sample_zone() {
+  if (window.__TAURI__) {
+    return await invoke("add_sample_zone", { channel: channel }).catch((error) => {
+      console.log("Error loading sample: " + error);
+      return null;
+    });
+  }
+  return null;
+}
+
+// Create the list of sample zones, each one plays a file for a range of keys and velocities
+function create_sample_zones(parent) {
+  const heading = document.createElement("h3");
+  heading.innerHTML = "Sampler";
+  parent.appendChild(heading);
+  const zones = document.createElement("div");
+  zones.classList.add("sample_zones");
+  parent.appendChild(zones);
+
+  // The zones are made again whenever one is added or removed, or a different patch is loaded
+  const update_zones = () => {
+    zones.innerHTML = "";
+    patch.sampler.zones.forEach((zone, index) => {
+      const row = document.createElement("div");
+      const name = document.createElement("span");
+      name.textContent = zone.path.split(/[\\/]/).pop();
+      name.title = zone.path;
+      row.appendChild(name);
+      // Keys and velocities are MIDI numbers, loop points are in frames from the start of the file
+      for (const [text, setting, max] of [["Root", "root_key", 127], ["Low Key", "low_key", 127], ["High Key", "high_key", 127], ["Low Vel", "low_velocity", 127], ["High Vel", "high_velocity", 127], ["Loop Start", "loop_start", null], ["Loop End", "loop_end", null]]) {
+        const label = document.createElement("label");
+        label.innerHTML = text;
+        const input = document.createElement("input");
+        input.setAttribute("type", "number");
+        input.setAttribute("min", 0);
+        if (max != null) {
+          input.setAttribute("max", max);
+        }
+        input.value = zone[setting];
+        input.addEventListener("change", () => {
+          const value = Math.max(parseInt(input.value) || 0, 0);
+          zone[setting] = max != null ? Math.min(value, max) : value;
+          set_patch();
+        });
+        label.appendChild(input);
+        row.appendChild(label);
+      }
+      const loop_label = document.createElement("label");
+      const loop_checkbox = document.createElement("input");
+      loop_checkbox.setAttribute("type", "checkbox");
+      loop_checkbox.checked = zone.looping;
+      loop_checkbox.addEventListener("change", () => {
+        zone.looping = loop_checkbox.checked;
+        set_patch();
+      });
+      loop_label.appendChild(loop_checkbox);
+      loop_label.appendChild(document.createTextNode("Loop"));
+      row.appendChild(loop_label);
+      const remove_button = document.createElement("button");
+      remove_button.innerHTML = "Remove";
+      remove_button.addEventListener("click", () => {
+        patch.sampler.zones.splice(index, 1);
+        set_patch();
+        update_zones();
+      });
+      row.appendChild(remove_button);
+      zones.appendChild(row);
+    });
+  };
+  update_zones();
+  refresh_controls.push(update_zones);
+
+  const add_button = document.createElement("button");
+  add_button.innerHTML = "Add Sample";
+  add_button.addEventListener("click", async () => {
+    if (await add_sample_zone()) {
+      await get_channel();
+      refresh_controls.forEach((refresh) => refresh());
+    }
+  });
+  parent.appendChild(add_button);
+}
+
+async function get_mod_matrix() {
+  if (window.__TAURI__) {
+    return await invoke("get_mod_matrix", { channel: channel }).catch((error) => {
+      console.log("Error getting mod matrix: " + error);
+      return [];
+    });
+  }
+  return [];
+}
+
+async function set_mod_matrix() {
+  if (window.__TAURI__) {
+    await invoke("set_mod_matrix", { channel: channel, modMatrix: patch.mod_matrix }).catch((error) => {
+      console.log("Error setting mod matrix: " + error);
+    });
+  }
+}
+
+// Create the list of modulation matrix slots, each one routes a source to a destination by an amount
+function create_mod_matrix(parent) {
+  const heading = document.createElement("h3");
+  heading.innerHTML = "Mod Matrix";
+  parent.appendChild(heading);
+  const slots = document.createElement("div");
+  slots.classList.add("mod_matrix");
+  parent.appendChild(slots);
+
+  // The slots are made again whenever the matrix changes size, or a different patch is loaded
+  const update_slots = () => {
+    slots.innerHTML = "";
+    patch.mod_matrix.forEach((slot, index) => {
+      const row = document.createElement("div");
+      for (const [options, name] of [[mod_sources, "source"], [mod_destinations, "destination"]]) {
+        const select = document.createElement("select");
+        for (const [value, text] of options) {
+          const option = document.createElement("option");
+          option.value = value;
+          option.innerHTML = text;
+          select.appendChild(option);
+        }
+        select.value = slot[name];
+        select.addEventListener("change", () => {
+          slot[name] = select.value;
+          set_mod_matrix();
+        });
+        row.appendChild(select);
+      }
+      const amount = document.createElement("input");
+      amount.setAttribute("type", "number");
+      amount.setAttribute("step", 0.01);
+      amount.value = slot.amount;
+      amount.addEventListener("change", () => {
+        slot.amount = parseFloat(amount.value) || 0;
+        set_mod_matrix();
+      });
+      row.appendChild(amount);
+      const remove_button = document.createElement("button");
+      remove_button.innerHTML = "Remove";
+      remove_button.addEventListener("click", () => {
+        patch.mod_matrix.splice(index, 1);
+        set_mod_matrix();
+        update_slots();
+      });
+      row.appendChild(remove_button);
+      slots.appendChild(row);
+    });
+  };
+  update_slots();
+  refresh_controls.push(update_slots);
+
+  const add_button = document.createElement("button");
+  add_button.innerHTML = "Add Slot";
+  add_button.addEventListener("click", () => {
+    patch.mod_matrix.push({ source: "ModWheel", destination: "Cutoff", amount: 1 });
+    set_mod_matrix();
+    update_slots();
+  });
+  parent.appendChild(add_button);
+}
+
+export async function patch_editor() {
+  await get_channel();
+  if (patch == null) {
+    return;
+  }
+
+  // Get body element
+  const body = document.querySelector("body");
+  // Create a new div inside the body with the class widget-container
+  const widget_container = document.createElement("div");
+  widget_container.classList.add("widget-container");
+  body.appendChild(widget_container);
+  // Create a new tag to label the widget
+  const widget_label = document.createElement("h2");
+  widget_label.innerHTML = "Patch";
+  widget_container.appendChild(widget_label);
+  // Create a new div inside the body with the class widget
+  const widget = document.createElement("div");
+  widget.classList.add("patch_editor");
+  widget_container.appendChild(widget);
+
+  // Create a select for the channel being edited, each channel has its own patch
+  const channel_select = document.createElement("select");
+  for (let i = 0; i < 16; i++) {
+    const option = document.createElement("option");
+    option.value = i;
+    option.innerHTML = `Channel ${i + 1}`;
+    channel_select.appendChild(option);
+  }
+  channel_select.addEventListener("change", async () => {
+    channel = parseInt(channel_select.value);
+    await get_channel();
+    refresh_controls.forEach((refresh) => refresh());
+  });
+  widget.appendChild(channel_select);
+
+  // Create a select to load presets from
+  const preset_select = document.createElement("select");
+  await update_preset_select(preset_select);
+  preset_select.addEventListener("change", () => {
+    load_preset(preset_select.value);
+  });
+  widget.appendChild(preset_select);
+  // Create a button to save the current patch as a preset
+  const save_button = document.createElement("button");
+  save_button.innerHTML = "Save Preset";
+  save_button.addEventListener("click", async () => {
+    const name = prompt("Preset name", preset_select.value);
+    if (name) {
+      await save_preset(name);
+      await update_preset_select(preset_select);
+      preset_select.value = name;
+    }
+  });
+  widget.appendChild(save_button);
+  // Create a button to delete the selected preset
+  const delete_button = document.createElement("button");
+  delete_button.innerHTML = "Delete Preset";
+  delete_button.addEventListener("click", async () => {
+    await delete_preset(preset_select.value);
+    await update_preset_select(preset_select);
+  });
+  widget.appendChild(delete_button);
+  // Create a button to use the current patch for a program number, programs are numbered from 1 like in General MIDI
+  const program_button = document.createElement("button");
+  program_button.innerHTML = "Assign to Program";
+  program_button.addEventListener("click", async () => {
+    const program = parseInt(prompt("Program number (1-128)", "1"));
+    if (program >= 1 && program <= 128) {
+      await set_program(program - 1);
+    }
+  });
+  widget.appendChild(program_button);
+
+  // Create a select for how the patch makes its sound, which decides whether the oscillators, the FM operators or the samples play
+  create_select(widget, [["Subtractive", "Subtractive"], ["Fm", "FM"], ["Sampler", "Sampler"]], () => patch.patch_type, (value) => patch.patch_type = value);
+
+  // Create controls for each oscillator, the ones with no level are turned off
+  for (let i = 0; i < patch.oscillators.length; i++) {
+    const heading = document.createElement("h3");
+    heading.innerHTML = `Oscillator ${i + 1}`;
+    widget.appendChild(heading);
+    create_select(widget, [["Sine", "Sine"], ["Square", "Square"], ["Sawtooth", "Sawtooth"], ["Triangle", "Triangle"], ["Pulse", "Pulse"], ["Supersaw", "Supersaw"], ["WhiteNoise", "White Noise"], ["PinkNoise", "Pink Noise"], ["BrownNoise", "Brown Noise"], ["Wavetable", "Wavetable"]], () => patch.oscillators[i].wave_type, (value) => patch.oscillators[i].wave_type = value);
+    create_slider(widget, "Octave", -3, 3, 1, () => patch.oscillators[i].octave, (value) => patch.oscillators[i].octave = value);
+    create_slider(widget, "Semitone", -12, 12, 1, () => patch.oscillators[i].semitone, (value) => patch.oscillators[i].semitone = value);
+    create_slider(widget, "Cents", -100, 100, 1, () => patch.oscillators[i].cents, (value) => patch.oscillators[i].cents = value);
+    create_slider(widget, "Level", 0, 1, 0.01, () => patch.oscillators[i].level, (value) => patch.oscillators[i].level = value);
+    create_slider(widget, "Pulse Width", 0.01, 0.99, 0.01, () => patch.oscillators[i].pulse_width, (value) => patch.oscillators[i].pulse_width = value);
+    create_slider(widget, "Wavetable Position", 0, 1, 0.01, () => patch.oscillators[i].wavetable_position, (value) => patch.oscillators[i].wavetable_position = value);
+    const wavetable_button = document.createElement("button");
+    wavetable_button.innerHTML = "Load Wavetable";
+    wavetable_button.addEventListener("click", async () => {
+      if (await load_wavetable(i)) {
+        await get_channel();
+        refresh_controls.forEach((refresh) => refresh());
+      }
+    });
+    widget.appendChild(wavetable_button);
+  }
+
+  // Create sliders for unison, which plays detuned copies of every oscillator
+  create_slider(widget, "Unison Voices", 1, 16, 1, () => patch.unison.voices, (value) => patch.unison.voices = value);
+  create_slider(widget, "Unison Detune", 0, 100, 1, () => patch.unison.detune, (value) => patch.unison.detune = value);
+  create_slider(widget, "Unison Spread", 0, 1, 0.01, () => patch.unison.spread, (value) => patch.unison.spread = value);
+
+  // Create controls for the FM operators, operators are numbered from 1 like on hardware FM synths
+  const fm_heading = document.createElement("h3");
+  fm_heading.innerHTML = "FM";
+  widget.appendChild(fm_heading);
+  create_select(widget, [["Stack", "4 > 3 > 2 > 1"], ["TwoIntoStack", "3 + 4 > 2 > 1"], ["StackAndOne", "3 > 2 > 1, 4 > 1"], ["ThreeIntoOne", "2 + 3 + 4 > 1"], ["TwoStacks", "2 > 1, 4 > 3"], ["OneIntoThree", "4 > 1 + 2 + 3"], ["StackAndSines", "2 > 1, 3, 4"], ["Additive", "1, 2, 3, 4"]], () => patch.fm.algorithm, (value) => patch.fm.algorithm = value);
+  create_slider(widget, "Feedback", 0, 1, 0.01, () => patch.fm.feedback, (value) => patch.fm.feedback = value);
+  for (let i = 0; i < patch.fm.operators.length; i++) {
+    const heading = document.createElement("h3");
+    heading.innerHTML = `Operator ${i + 1}`;
+    widget.appendChild(heading);
+    create_slider(widget, "Ratio", 0.5, 16, 0.5, () => patch.fm.operators[i].ratio, (value) => patch.fm.operators[i].ratio = value);
+    create_slider(widget, "Level", 0, 1, 0.01, () => patch.fm.operators[i].level, (value) => patch.fm.operators[i].level = value);
+    create_slider(widget, "Attack", 0, 2, 0.01, () => patch.fm.operators[i].envelope.attack, (value) => patch.fm.operators[i].envelope.attack = value);
+    create_slider(widget, "Decay", 0, 2, 0.01, () => patch.fm.operators[i].envelope.decay, (value) => patch.fm.operators[i].envelope.decay = value);
+    create_slider(widget, "Sustain", 0, 1, 0.01, () => patch.fm.operators[i].envelope.sustain, (value) => patch.fm.operators[i].envelope.sustain = value);
+    create_slider(widget, "Release", 0, 4, 0.01, () => patch.fm.operators[i].envelope.release, (value) => patch.fm.operators[i].envelope.release = value);
+  }
+
+  create_sample_zones(widget);
+
+  // Create sliders for where the channel sits in the mix
+  create_slider(widget, "Volume", 0, 1, 0.01, () => settings.volume, (value) => settings.volume = value);
+  create_slider(widget, "Pan", -1, 1, 0.01, () => settings.pan, (value) => settings.pan = value);
+  create_slider(widget, "Priority", 0, 127, 1, () => settings.priority, (value) => settings.priority = value);
+
+  // Create sliders for the envelope, times are in seconds
+  create_slider(widget, "Attack", 0, 2, 0.01, () => patch.envelope.attack, (value) => patch.envelope.attack = value);
+  create_slider(widget, "Decay", 0, 2, 0.01, () => patch.envelope.decay, (value) => patch.envelope.decay = value);
+  create_slider(widget, "Sustain", 0, 1, 0.01, () => patch.envelope.sustain, (value) => patch.envelope.sustain = value);
+  create_slider(widget, "Release", 0, 4, 0.01, () => patch.envelope.release, (value) => patch.envelope.release = value);
+
+  create_slider(widget, "Gain", 0, 2, 0.01, () => patch.gain, (value) => patch.gain = value);
+
+  // Create controls for the filter
+  create_select(widget, [["LowPass", "Low Pass"], ["HighPass", "High Pass"], ["BandPass", "Band Pass"], ["Notch", "Notch"]], () => patch.filter.filter_type, (value) => patch.filter.filter_type = value);
+  create_select(widget, [["Db12", "12 dB/oct"], ["Db24", "24 dB/oct"]], () => patch.filter.slope, (value) => patch.filter.slope = value);
+  create_slider(widget, "Cutoff", 20, 20000, 1, () => patch.filter.cutoff, (value) => patch.filter.cutoff = value);
+  create_slider(widget, "Resonance", 0, 1, 0.01, () => patch.filter.resonance, (value) => patch.filter.resonance = value);
+  create_slider(widget, "Key Tracking", 0, 1, 0.01, () => patch.filter.key_tracking, (value) => patch.filter.key_tracking = value);
+  create_slider(widget, "Filter Attack", 0, 2, 0.01, () => patch.filter.envelope.attack, (value) => patch.filter.envelope.attack = value);
+  create_slider(widget, "Filter Decay", 0, 2, 0.01, () => patch.filter.envelope.decay, (value) => patch.filter.envelope.decay = value);
+  create_slider(widget, "Filter Sustain", 0, 1, 0.01, () => patch.filter.envelope.sustain, (value) => patch.filter.envelope.sustain = value);
+  create_slider(widget, "Filter Release", 0, 4, 0.01, () => patch.filter.envelope.release, (value) => patch.filter.envelope.release = value);
+  // The envelope amount is in octaves, negative amounts move the cutoff down
+  create_slider(widget, "Filter Envelope", -8, 8, 0.1, () => patch.filter.envelope_amount, (value) => patch.filter.envelope_amount = value);
+
+  // Create sliders for the pitch bend wheel and the vibrato added by the mod wheel
+  create_slider(widget, "Bend Range", 0, 24, 1, () => patch.pitch_bend_range, (value) => patch.pitch_bend_range = value);
+  create_slider(widget, "Vibrato Rate", 0.1, 12, 0.1, () => patch.vibrato_rate, (value) => patch.vibrato_rate = value);
+  create_slider(widget, "Vibrato Depth", 0, 2, 0.01, () => patch.vibrato_depth, (value) => patch.vibrato_depth = value);
+
+  create_lfo_controls(widget, "Voice LFO", "voice_lfo");
+  create_lfo_controls(widget, "Global LFO", "global_lfo");
+
+  // Create controls for playing one note at a time
+  create_select(widget, [["Poly", "Poly"], ["Mono", "Mono"], ["Legato", "Legato"]], () => patch.voice_mode, (value) => patch.voice_mode = value);
+  create_select(widget, [["Last", "Last Note"], ["Low", "Low Note"], ["High", "High Note"]], () => patch.note_priority, (value) => patch.note_priority = value);
+  create_slider(widget, "Portamento", 0, 2, 0.01, () => patch.portamento, (value) => patch.portamento = value);
+
+  patch.mod_matrix = await get_mod_matrix();
+  create_mod_matrix(widget);
+}
@@ -0,0 +1,97 @@
+if (window.__TAURI__) {
+  var { invoke } = window.__TAURI__.tauri;
+}
+
+// How often the voice counters are updated, in milliseconds
+const STATS_INTERVAL = 500;
+
+async function get_polyphony() {
+  if (window.__TAURI__) {
+    return await invoke("get_polyphony");
+  }
+  return null;
+}
+
+async function set_polyphony(settings) {
+  if (window.__TAURI__) {
+    await invoke("set_polyphony", { polyphony: settings });
+  }
+}
+
+async function get_voice_stats() {
+  if (window.__TAURI__) {
+    return await invoke("get_voice_stats");
+  }
+  return null;
+}
+
+export async function polyphony() {
+  const settings = await get_polyphony();
+  if (settings == null) {
+    return;
+  }
+
+  // Get body element
+  const body = document.querySelector("body");
+  // Create a new div inside the body with the class widget-container
+  const widget_container = document.createElement("div");
+  widget_container.classList.add("widget-container");
+  body.appendChild(widget_container);
+  // Create a new tag to label the widget
+  const widget_label = document.createElement("h2");
+  widget_label.innerHTML = "Polyphony";
+  widget_container.appendChild(widget_label);
+  // Create a new div inside the body with the class widget
+  const widget = document.createElement("div");
+  widget.classList.add("polyphony");
+  widget_container.appendChild(widget);
+
+  // Create a number input for the most voices that can play at once
+  const max_voices_label = document.createElement("label");
+  max_voices_label.innerHTML = "Max Voices";
+  const max_voices_input = document.createElement("input");
+  max_voices_input.setAttribute("type", "number");
+  max_voices_input.setAttribute("min", 1);
+  max_voices_input.setAttribute("max", 256);
+  max_voices_input.value = settings.max_voices;
+  max_voices_input.addEventListener("change", () => {
+    settings.max_voices = parseInt(max_voices_input.value);
+    set_polyphony(settings);
+  });
+  max_voices_label.appendChild(max_voices_input);
+  widget.appendChild(max_voices_label);
+
+  // Create a select for which voice is stolen when there are too many
+  const policy_label = document.createElement("label");
+  policy_label.innerHTML = "Voice Stealing";
+  const policy_select = document.createElement("select");
+  const policies = [
+    ["Oldest", "Oldest"],
+    ["Quietest", "Quietest"],
+    ["SameNote", "Same Note"],
+    ["LowestPriorityChannel", "Lowest Priority Channel"],
+  ];
+  for (const [value, name] of policies) {
+    const option = document.createElement("option");
+    option.value = value;
+    option.innerHTML = name;
+    policy_select.appendChild(option);
+  }
+  policy_select.value = settings.steal_policy;
+  policy_select.addEventListener("change", () => {
+    settings.steal_policy = policy_select.value;
+    set_polyphony(settings);
+  });
+  policy_label.appendChild(policy_select);
+  widget.appendChild(policy_label);
+
+  // Show the voice counters, and keep them up to date
+  const stats = document.createElement("p");
+  widget.appendChild(stats);
+  setInterval(async () => {
+    const voice_stats = await get_voice_stats();
+    if (voice_stats != null) {
+      stats.innerHTML = `Voices: ${voice_stats.active_voices} (peak ${voice_stats.peak_voices}), stolen: ${voice_stats.stolen_voices}`;
+    }
+  }, STATS_INTERVAL);
+}